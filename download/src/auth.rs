@@ -0,0 +1,798 @@
+//! Credentials for servers that require HTTP authentication.
+//!
+//! Credentials are kept per host and sent with HTTP Basic or Bearer
+//! authentication.  They come from, in increasing order of precedence:
+//!
+//! 1. A netrc file, `$NETRC` or `~/.netrc`, in the format curl and ftp use:
+//!    ```text
+//!    machine artifacts.example.com login builder password s3cret
+//!    ```
+//! 2. The environment variables `DOWNLOAD_USER`, which holds entries such as
+//!    `artifacts.example.com=builder:s3cret`, and `DOWNLOAD_BEARER`, which
+//!    holds entries such as `api.example.com=TOKEN`.  Separate several entries
+//!    with spaces.
+//! 3. Command line flags in the same format.
+//!
+//! Credentials can also come from a credential helper, an external program
+//! that gets them on demand, e.g. from a password manager or by minting a
+//! short-lived token, so they never have to be stored in a file.  Helpers
+//! speak the protocol of git credential helpers: the helper is run by the
+//! shell with the argument `get` and told what we want on stdin,
+//!
+//! ```text
+//! protocol=https
+//! host=artifacts.example.com
+//! ```
+//!
+//! followed by a blank line, and it answers on stdout with `username=` and
+//! `password=` lines, or with `authtype=Bearer` and `credential=` lines.  An
+//! optional `password_expiry_utc=` line gives the Unix time the answer
+//! expires.  Answers are kept for the life of the process.  When the server
+//! refuses a credential with 401 Unauthorized, the helper is asked again,
+//! with the server's `WWW-Authenticate` challenges as `wwwauth[]=` lines, and
+//! the request is retried once with the new credential.  A helper is given
+//! as `host=command` to use it for one host, or as a bare command for every
+//! host, with the `DOWNLOAD_CREDENTIAL_HELPER` environment variable or a
+//! command line flag.  Credentials stored for a host come before a helper.
+//!
+//! Hosts behind an OAuth2 authorization server get access tokens from the
+//! token endpoint instead, see the `oauth2` module.  They are fetched when
+//! needed and refreshed like the answers of helpers.  Requests to S3 and
+//! S3-compatible object stores are signed with AWS Signature Version 4
+//! instead, see the `aws` module.
+//!
+//! A host may include a port, e.g. `localhost:8080`, to apply only there.
+//! The netrc `default` entry applies to hosts without credentials of their
+//! own.
+//! Credentials are never included in `Debug` output or error messages, and
+//! the `Authorization` header is marked as sensitive.  reqwest drops the
+//! header when a redirect leaves the host or port; `redirect_policy()` also
+//! refuses redirects that change only the scheme, so credentials never
+//! follow a redirect to another origin.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::lock::Mutex;
+
+use reqwest::header::{self, HeaderValue};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+
+use crate::aws::SigV4;
+use crate::oauth2::OAuth2;
+use crate::rt;
+
+/// Most redirects followed for one request, the same as reqwest's default.
+pub(crate) const MAX_REDIRECTS: usize = 10;
+
+/// Credentials for one host.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credential {
+    /// User name and password, sent with HTTP Basic authentication.
+    Basic { username: String, password: String },
+    /// Token, e.g. an API key, sent with HTTP Bearer authentication.
+    Bearer(String),
+}
+impl Credential {
+    /// Value of the `Authorization` header for this credential, marked as
+    /// sensitive so it isn't logged.
+    pub fn header_value(&self) -> Result<HeaderValue, util::Error> {
+        let value = match self {
+            Credential::Basic { username, password } => {
+                format!(
+                    "Basic {}",
+                    base64::encode(format!("{}:{}", username, password))
+                )
+            }
+            Credential::Bearer(token) => format!("Bearer {}", token),
+        };
+        let mut value = HeaderValue::from_str(&value).map_err(|_| util::Error {
+            what: "Credentials contain characters that can't be sent in a header.".to_string(),
+            source: None,
+            kind: util::ErrorKind::Auth,
+        })?;
+        value.set_sensitive(true);
+        Ok(value)
+    }
+}
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credential::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .field("password", &"<hidden>")
+                .finish(),
+            Credential::Bearer(_) => f.debug_tuple("Bearer").field(&"<hidden>").finish(),
+        }
+    }
+}
+
+/// Credentials for every host that needs them.  Shared by every download in
+/// a batch.  Example:
+///
+/// ```ignore
+/// let mut credentials = Credentials::load().await?;
+/// credentials.add_basic("artifacts.example.com=builder:s3cret")?;
+/// let options = Options {
+///     credentials: Some(Arc::new(credentials)),
+///     ..Options::new(client)
+/// };
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Credentials {
+    /// Credentials by lowercase host, optionally with a port.
+    hosts: HashMap<String, Credential>,
+    /// Credential helpers and OAuth2 clients, in the order they were added.
+    helpers: Vec<Helper>,
+    /// Answers from helpers, by `Helper::key()`, each behind a lock of its
+    /// own.  Clones share them.
+    answers: Arc<Mutex<HashMap<String, Slot>>>,
+    /// AWS signers by lowercase host, optionally with a port.
+    signers: HashMap<String, Arc<SigV4>>,
+    /// Credentials for any other host, from the netrc `default` entry.
+    default: Option<Credential>,
+}
+impl Credentials {
+    /// No credentials at all.
+    pub fn new() -> Self {
+        Credentials::default()
+    }
+
+    /// Credentials from the netrc file, if there is one, and the environment.
+    pub async fn load() -> Result<Self, util::Error> {
+        let mut credentials = Credentials::new();
+        if let Some(path) = netrc_path() {
+            credentials.add_netrc(&path).await?;
+        }
+        credentials.add_env()?;
+        Ok(credentials)
+    }
+
+    /// Use `credential` for `host`, which may include a port, replacing any
+    /// credential it had.
+    pub fn insert(&mut self, host: &str, credential: Credential) {
+        self.hosts.insert(host.to_lowercase(), credential);
+    }
+
+    /// Add Basic credentials given as `host=username:password`.
+    pub fn add_basic(&mut self, spec: &str) -> Result<(), util::Error> {
+        let (host, user) = split_spec(spec, "host=username:password")?;
+        let (username, password) = match user.find(':') {
+            Some(at) => (&user[..at], &user[at + 1..]),
+            None => (user, ""),
+        };
+        let credential = Credential::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        credential.header_value()?;
+        self.insert(host, credential);
+        Ok(())
+    }
+
+    /// Add a Bearer token given as `host=token`.
+    pub fn add_bearer(&mut self, spec: &str) -> Result<(), util::Error> {
+        let (host, token) = split_spec(spec, "host=token")?;
+        let credential = Credential::Bearer(token.to_string());
+        credential.header_value()?;
+        self.insert(host, credential);
+        Ok(())
+    }
+
+    /// Add a credential helper given as `host=command`, or as `command` for
+    /// every host.  The host may include a port.
+    pub fn add_helper(&mut self, spec: &str) -> Result<(), util::Error> {
+        // A host has no spaces or slashes, unlike the arguments of a command
+        // that happen to contain `=`.
+        let (host, command) = match spec.find('=') {
+            Some(at) if at > 0 && !spec[..at].contains(|c: char| c.is_whitespace() || c == '/') => {
+                (Some(spec[..at].to_lowercase()), &spec[at + 1..])
+            }
+            _ => (None, spec),
+        };
+        if command.trim().is_empty() {
+            return Err(util::Error {
+                what: "Invalid credential helper, expected host=command or command.".to_string(),
+                source: None,
+                kind: util::ErrorKind::Other,
+            });
+        }
+        self.helpers.push(Helper {
+            host,
+            source: Source::Command(command.to_string()),
+        });
+        Ok(())
+    }
+
+    /// Get access tokens for `host`, which may include a port, from
+    /// `oauth2`.  Hosts sharing an authorization server, client, and scopes
+    /// share tokens.
+    pub fn add_oauth2(&mut self, host: &str, oauth2: OAuth2) -> Result<(), util::Error> {
+        if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == '/') {
+            return Err(util::Error {
+                what: format!("Invalid host {} for OAuth2.", host),
+                source: None,
+                kind: util::ErrorKind::Other,
+            });
+        }
+        self.helpers.push(Helper {
+            host: Some(host.to_lowercase()),
+            source: Source::OAuth2(Arc::new(oauth2)),
+        });
+        Ok(())
+    }
+
+    /// Sign requests to `host`, which may include a port, with `signer`
+    /// instead of sending other credentials.
+    pub fn add_sigv4(&mut self, host: &str, signer: SigV4) -> Result<(), util::Error> {
+        if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == '/') {
+            return Err(util::Error {
+                what: format!("Invalid host {} for AWS signing.", host),
+                source: None,
+                kind: util::ErrorKind::Other,
+            });
+        }
+        self.signers.insert(host.to_lowercase(), Arc::new(signer));
+        Ok(())
+    }
+
+    /// Add the credentials in the environment variables `DOWNLOAD_USER` and
+    /// `DOWNLOAD_BEARER`, and the helper in `DOWNLOAD_CREDENTIAL_HELPER`.
+    pub fn add_env(&mut self) -> Result<(), util::Error> {
+        if let Ok(specs) = std::env::var("DOWNLOAD_USER") {
+            for spec in specs.split_whitespace() {
+                self.add_basic(spec)?;
+            }
+        }
+        if let Ok(specs) = std::env::var("DOWNLOAD_BEARER") {
+            for spec in specs.split_whitespace() {
+                self.add_bearer(spec)?;
+            }
+        }
+        if let Ok(spec) = std::env::var("DOWNLOAD_CREDENTIAL_HELPER") {
+            self.add_helper(&spec)?;
+        }
+        Ok(())
+    }
+
+    /// Add the credentials in the netrc file at `path`.  A missing file is
+    /// not an error.
+    pub async fn add_netrc(&mut self, path: &Path) -> Result<(), util::Error> {
+        let text = match rt::fs::read_to_string(path).await {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(util::Error {
+                    what: format!("Couldn't read {}.", path.display()),
+                    source: Some(e.into()),
+                    kind: util::ErrorKind::Other,
+                })
+            }
+        };
+        self.add_netrc_text(&text).map_err(|what| util::Error {
+            what: format!("Invalid netrc file {}: {}.", path.display(), what),
+            source: None,
+            kind: util::ErrorKind::Other,
+        })
+    }
+
+    /// Add the entries of a netrc file.
+    fn add_netrc_text(&mut self, text: &str) -> Result<(), String> {
+        // Lines of a macro definition are not tokens, so we skip them up to
+        // the blank line that ends the definition.  Like curl, we skip
+        // comment lines too.
+        let mut tokens = Vec::new();
+        let mut in_macro = false;
+        for line in text.lines() {
+            if in_macro {
+                in_macro = !line.trim().is_empty();
+                continue;
+            }
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            for word in line.split_whitespace() {
+                if word == "macdef" {
+                    in_macro = true;
+                    break;
+                }
+                tokens.push(word);
+            }
+        }
+
+        // Each entry starts with `machine name` or `default`.
+        let mut host: Option<Option<&str>> = None;
+        let (mut login, mut password): (Option<&str>, Option<&str>) = (None, None);
+        let mut tokens = tokens.into_iter();
+        loop {
+            let token = tokens.next();
+            if let (None | Some("machine") | Some("default"), Some(host)) = (token, host) {
+                if login.is_some() || password.is_some() {
+                    let credential = Credential::Basic {
+                        username: login.take().unwrap_or_default().to_string(),
+                        password: password.take().unwrap_or_default().to_string(),
+                    };
+                    match host {
+                        Some(host) => {
+                            self.hosts.entry(host.to_lowercase()).or_insert(credential);
+                        }
+                        None => self.default = Some(credential),
+                    }
+                }
+            }
+            match token {
+                None => return Ok(()),
+                Some("machine") => {
+                    host = Some(Some(tokens.next().ok_or("machine without a name")?));
+                }
+                Some("default") => host = Some(None),
+                Some(key @ "login") | Some(key @ "password") | Some(key @ "account") => {
+                    let value = tokens
+                        .next()
+                        .ok_or_else(|| format!("{} without a value", key))?;
+                    if host.is_none() {
+                        return Err(format!("{} before the first machine", key));
+                    }
+                    match key {
+                        "login" => login = Some(value),
+                        "password" => password = Some(value),
+                        _ => {}
+                    }
+                }
+                // The word may be part of a password, so we don't repeat it.
+                Some(_) => return Err("unexpected word where a keyword should be".to_string()),
+            }
+        }
+    }
+
+    /// The stored credential for `url`, if we have one, without asking a
+    /// helper.  Credentials for the host and port come before credentials
+    /// for the host alone.
+    pub fn get(&self, url: &Url) -> Option<&Credential> {
+        self.stored(url).or(self.default.as_ref())
+    }
+
+    /// The AWS signer for `url`, if its host has one.
+    pub fn signer(&self, url: &Url) -> Option<&SigV4> {
+        let host = url.host_str()?.to_lowercase();
+        url.port()
+            .and_then(|port| self.signers.get(&format!("{}:{}", host, port)))
+            .or_else(|| self.signers.get(&host))
+            .map(|signer| &**signer)
+    }
+
+    /// The credential for `url`, if we have one, asking a helper if no
+    /// credential is stored for its host.
+    pub async fn lookup(&self, url: &Url) -> Result<Option<Credential>, util::Error> {
+        if let Some(credential) = self.stored(url) {
+            return Ok(Some(credential.clone()));
+        }
+        if let Some(helper) = self.helper(url) {
+            // Holding the lock while the helper runs means concurrent
+            // downloads ask it only once for the same key.
+            let slot = self.slot(helper.key(url)).await;
+            let mut slot = slot.lock().await;
+            let answer = match &*slot {
+                Some(answer) if answer.is_fresh() => answer.clone(),
+                _ => {
+                    let answer = helper.get(url, &[]).await?;
+                    *slot = Some(answer.clone());
+                    answer
+                }
+            };
+            if answer.credential.is_some() {
+                return Ok(answer.credential);
+            }
+        }
+        Ok(self.default.clone())
+    }
+
+    /// Ask the helper for `url` again after the server refused `rejected`
+    /// with `challenges`, its `WWW-Authenticate` headers.  Returns the new
+    /// credential, if the helper has one that is different.
+    pub async fn refresh(
+        &self,
+        url: &Url,
+        rejected: Option<&Credential>,
+        challenges: &[String],
+    ) -> Result<Option<Credential>, util::Error> {
+        let helper = match self.helper(url) {
+            Some(helper) => helper,
+            None => return Ok(None),
+        };
+        let slot = self.slot(helper.key(url)).await;
+        let mut slot = slot.lock().await;
+
+        // Another download may have asked the helper again already.
+        if let Some(answer) = &*slot {
+            if answer.is_fresh()
+                && answer.credential.is_some()
+                && answer.credential.as_ref() != rejected
+            {
+                return Ok(answer.credential.clone());
+            }
+        }
+        let answer = helper.get(url, challenges).await?;
+        *slot = Some(answer.clone());
+        Ok(answer
+            .credential
+            .filter(|credential| Some(credential) != rejected))
+    }
+
+    /// Add the `Authorization` header for `url` to `request`, if we have
+    /// credentials for it, or sign it, if we have an AWS signer for it.
+    /// Requests are signed as they are, so headers must be added before.
+    pub async fn authorize(
+        &self,
+        url: &Url,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::RequestBuilder, util::Error> {
+        if let Some(signer) = self.signer(url) {
+            return signer.sign_request(request);
+        }
+        match self.lookup(url).await? {
+            Some(credential) => {
+                Ok(request.header(header::AUTHORIZATION, credential.header_value()?))
+            }
+            None => Ok(request),
+        }
+    }
+}
+
+impl Credentials {
+    /// The credential stored for the host of `url`.
+    fn stored(&self, url: &Url) -> Option<&Credential> {
+        let host = url.host_str()?.to_lowercase();
+        url.port()
+            .and_then(|port| self.hosts.get(&format!("{}:{}", host, port)))
+            .or_else(|| self.hosts.get(&host))
+    }
+
+    /// The answers for `key`, which only the lookups of the same key wait
+    /// for, so a slow helper doesn't hold up the others.
+    async fn slot(&self, key: String) -> Slot {
+        self.answers.lock().await.entry(key).or_default().clone()
+    }
+
+    /// The helper for `url`: the first one for its host and port, then the
+    /// first one for its host, then the first one for every host.
+    fn helper(&self, url: &Url) -> Option<&Helper> {
+        let host = url.host_str()?.to_lowercase();
+        let host_port = url.port().map(|port| format!("{}:{}", host, port));
+        let for_host = |wanted: Option<&str>| {
+            self.helpers
+                .iter()
+                .find(|helper| helper.host.as_deref() == wanted)
+        };
+        host_port
+            .as_deref()
+            .and_then(|host_port| for_host(Some(host_port)))
+            .or_else(|| for_host(Some(&host)))
+            .or_else(|| for_host(None))
+    }
+}
+
+/// Source of credentials on demand.
+#[derive(Clone, Debug)]
+struct Helper {
+    /// Host, optionally with a port, the helper is for, or `None` for all.
+    host: Option<String>,
+    source: Source,
+}
+
+/// Where a helper gets credentials.
+#[derive(Clone, Debug)]
+enum Source {
+    /// Shell command run like a git credential helper, to which the action
+    /// is appended.
+    Command(String),
+    /// OAuth2 authorization server.
+    OAuth2(Arc<OAuth2>),
+}
+
+impl Helper {
+    /// Ask the helper for the credential for `url`, passing on the server's
+    /// `challenges`, if any.
+    async fn get(&self, url: &Url, challenges: &[String]) -> Result<Answer, util::Error> {
+        match &self.source {
+            Source::Command(command) => self.run(command, url, challenges).await,
+            Source::OAuth2(oauth2) => {
+                let token = oauth2.token().await?;
+                Ok(Answer {
+                    credential: Some(Credential::Bearer(token.access_token)),
+                    expires: token.expires,
+                })
+            }
+        }
+    }
+
+    /// Key of the helper's answers for `url`.  Commands answer for an
+    /// origin, but an access token is good for every host of its client.
+    fn key(&self, url: &Url) -> String {
+        match &self.source {
+            Source::Command(_) => format!("{}://{}", url.scheme(), host_port(url)),
+            Source::OAuth2(oauth2) => oauth2.key(),
+        }
+    }
+
+    /// Ask the helper program `command` for the credential for `url`.
+    async fn run(
+        &self,
+        command: &str,
+        url: &Url,
+        challenges: &[String],
+    ) -> Result<Answer, util::Error> {
+        let mut input = format!("protocol={}\nhost={}\n", url.scheme(), host_port(url));
+        for challenge in challenges {
+            input.push_str(&format!("wwwauth[]={}\n", challenge));
+        }
+        input.push('\n');
+
+        let action = format!("{} get", command);
+        let error = |source: Option<util::BoxError>| helper_error(command, source);
+        let output = rt::spawn_blocking(move || run(&action, input.as_bytes()))
+            .await?
+            .map_err(|e| error(Some(e.into())))?;
+        if !output.status.success() {
+            return Err(error(Some(output.status.to_string().into())));
+        }
+        let output = String::from_utf8(output.stdout).map_err(|e| error(Some(e.into())))?;
+        parse(command, &output)
+    }
+}
+
+/// Make sense of the `key=value` lines the helper program `command` wrote.
+fn parse(command: &str, output: &str) -> Result<Answer, util::Error> {
+    let mut fields = HashMap::new();
+    for line in output.lines() {
+        if let Some(at) = line.find('=') {
+            fields.insert(&line[..at], &line[at + 1..]);
+        }
+    }
+    let expires = fields
+        .get("password_expiry_utc")
+        .and_then(|secs| secs.parse().ok())
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+    let credential = match (fields.get("authtype"), fields.get("credential")) {
+        _ if fields.get("quit") == Some(&"true") || fields.get("quit") == Some(&"1") => None,
+        (Some(authtype), Some(credential)) if authtype.eq_ignore_ascii_case("bearer") => {
+            Some(Credential::Bearer(credential.to_string()))
+        }
+        (Some(authtype), Some(credential)) if authtype.eq_ignore_ascii_case("basic") => {
+            let decoded = base64::decode(credential)
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .ok_or_else(|| helper_error(command, None))?;
+            let at = decoded.find(':').unwrap_or(decoded.len());
+            Some(Credential::Basic {
+                username: decoded[..at].to_string(),
+                password: decoded.get(at + 1..).unwrap_or_default().to_string(),
+            })
+        }
+        (Some(_), Some(_)) => {
+            return Err(helper_error(command, Some("unsupported authtype".into())))
+        }
+        _ => match (fields.get("username"), fields.get("password")) {
+            (Some(username), Some(password)) => Some(Credential::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => None,
+        },
+    };
+    if let Some(credential) = &credential {
+        credential.header_value()?;
+    }
+    Ok(Answer {
+        credential,
+        expires,
+    })
+}
+
+/// Error for a helper program that failed or gave an answer we can't use.
+/// Its output may hold a secret, so we never repeat it.
+fn helper_error(command: &str, source: Option<util::BoxError>) -> util::Error {
+    util::Error {
+        what: format!("Credential helper {} failed.", command),
+        source,
+        kind: util::ErrorKind::Auth,
+    }
+}
+
+/// The last answer for a `Helper::key()`, locked while the helper is asked.
+type Slot = Arc<Mutex<Option<Answer>>>;
+
+/// What a helper said about an origin.
+#[derive(Clone, Debug)]
+struct Answer {
+    /// The credential, or `None` if the helper has none.
+    credential: Option<Credential>,
+    /// When the credential expires, if it does.
+    expires: Option<SystemTime>,
+}
+impl Answer {
+    /// May the answer still be used?
+    fn is_fresh(&self) -> bool {
+        self.expires
+            .is_none_or(|expires| SystemTime::now() < expires)
+    }
+}
+
+/// Run the shell `command` with `input` on stdin and collect its stdout.
+/// The helper may talk to the user on stderr, so that stays ours.
+fn run(command: &str, input: &[u8]) -> std::io::Result<std::process::Output> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A helper that doesn't care what we want may not read it.
+        match stdin.write_all(input) {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e),
+            _ => {}
+        }
+    }
+    child.wait_with_output()
+}
+
+/// The host of `url` with its port, if it isn't the default one, like git
+/// gives it to helpers.
+fn host_port(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host,
+    }
+}
+
+/// Redirect policy that stops credentials from following a redirect to
+/// another origin.  reqwest already drops them when the host or port
+/// changes, so this refuses redirects that change only the scheme.  Example:
+///
+/// ```ignore
+/// let client = Client::builder().redirect(auth::redirect_policy()).build()?;
+/// ```
+pub fn redirect_policy() -> Policy {
+    Policy::custom(|attempt| {
+        if attempt.previous().len() > MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        match attempt.previous().last().map(|previous| check_redirect(previous, attempt.url())) {
+            Some(Err(error)) => attempt.error(error),
+            _ => attempt.follow(),
+        }
+    })
+}
+
+/// Refuse a redirect from `previous` to `next` that changes only the scheme.
+pub(crate) fn check_redirect(previous: &Url, next: &Url) -> Result<(), String> {
+    if previous.scheme() != next.scheme()
+        && previous.host_str() == next.host_str()
+        && previous.port_or_known_default() == next.port_or_known_default()
+    {
+        return Err(format!(
+            "refusing to follow a redirect from {}: to {}: on the same host and port, \
+             which could send credentials to another origin",
+            previous.scheme(),
+            next.scheme()
+        ));
+    }
+    Ok(())
+}
+
+/// Path of the netrc file: `$NETRC`, or `.netrc` in the home directory.
+fn netrc_path() -> Option<PathBuf> {
+    match std::env::var_os("NETRC") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => std::env::var_os("HOME").map(|home| Path::new(&home).join(".netrc")),
+    }
+}
+
+/// Split `spec` into the host before the first `=` and the rest.  `format`
+/// describes what we expected, for the error.  The error never repeats
+/// `spec`, which holds a secret.
+fn split_spec<'a>(spec: &'a str, format: &str) -> Result<(&'a str, &'a str), util::Error> {
+    match spec.find('=') {
+        Some(at) if at > 0 => Ok((&spec[..at], &spec[at + 1..])),
+        _ => Err(util::Error {
+            what: format!("Invalid credentials, expected {}.", format),
+            source: None,
+            kind: util::ErrorKind::Other,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The credential `credentials` has stored for `url`.
+    fn get(credentials: &Credentials, url: &str) -> Option<Credential> {
+        credentials.get(&Url::parse(url).unwrap()).cloned()
+    }
+
+    fn basic(username: &str, password: &str) -> Option<Credential> {
+        Some(Credential::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    #[test]
+    fn uses_the_netrc_default_for_other_hosts() {
+        let mut credentials = Credentials::new();
+        credentials
+            .add_netrc_text(
+                "machine Artifacts.example.com login builder password s3cret\n\
+                 default login anonymous password guest\n",
+            )
+            .unwrap();
+        assert_eq!(
+            get(&credentials, "https://artifacts.example.com/a"),
+            basic("builder", "s3cret")
+        );
+        assert_eq!(
+            get(&credentials, "https://other.example.com/a"),
+            basic("anonymous", "guest")
+        );
+    }
+
+    #[test]
+    fn skips_netrc_macros_and_comments() {
+        let mut credentials = Credentials::new();
+        credentials
+            .add_netrc_text(
+                "# machine commented.example.com login no password no\n\
+                 macdef init\n\
+                 machine macro.example.com login no password no\n\
+                 cd /pub\n\
+                 \n\
+                 machine host.example.com\n\
+                   login a\n\
+                   account ignored\n\
+                   password b\n",
+            )
+            .unwrap();
+        assert_eq!(get(&credentials, "https://host.example.com/"), basic("a", "b"));
+        assert_eq!(get(&credentials, "https://macro.example.com/"), None);
+        assert_eq!(get(&credentials, "https://commented.example.com/"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_netrc_files() {
+        let cases = [
+            ("login a password b", "login before the first machine"),
+            ("machine", "machine without a name"),
+            ("machine host.example.com login", "login without a value"),
+            // The word may be a password, so it isn't repeated.
+            ("machine host.example.com s3cret", "unexpected word where a keyword should be"),
+        ];
+        for (text, error) in &cases {
+            assert_eq!(Credentials::new().add_netrc_text(text).unwrap_err(), *error);
+        }
+    }
+
+    #[test]
+    fn refuses_redirects_that_change_only_the_scheme() {
+        let check = |previous: &str, next: &str| {
+            check_redirect(&Url::parse(previous).unwrap(), &Url::parse(next).unwrap())
+        };
+        let (secure, insecure) = ("https://host.example.com:8443", "http://host.example.com:8443");
+        assert!(check(&format!("{}/a", secure), &format!("{}/b", insecure)).is_err());
+        assert!(check(&format!("{}/a", insecure), &format!("{}/a", secure)).is_err());
+        // Another host or port is another origin, which reqwest already drops
+        // the credentials for.
+        assert!(check("https://host.example.com/a", "http://host.example.com/a").is_ok());
+        assert!(check("https://host.example.com/a", "http://other.example.com/a").is_ok());
+        assert!(check("https://host.example.com/a", "https://host.example.com/b").is_ok());
+    }
+}
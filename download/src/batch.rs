@@ -0,0 +1,80 @@
+//! Downloading a batch of requests a few at a time.
+
+use std::future::Future;
+
+use futures::stream::{self, Stream, StreamExt};
+
+use crate::sink::Sink;
+use crate::{Options, Outcome, Progress, Request};
+
+/// Download all of `requests` with `options`, up to `concurrency` at a time.
+/// Requests with higher priority are started first.  `progress` is called
+/// with the index of a request as it starts and returns where that download
+/// reports its progress.  The stream yields the index and result of each
+/// download as it finishes.  Example:
+///
+/// ```ignore
+/// let mut batch = download::download_batch(&requests, &options, 4, |_| |_| {});
+/// while let Some((i, result)) = batch.next().await {
+///     eprintln!("{}: {:?}", requests[i].output.display(), result.is_ok());
+/// }
+/// ```
+pub fn download_batch<'a, F, P>(
+    requests: &'a [Request],
+    options: &'a Options,
+    concurrency: usize,
+    progress: F,
+) -> impl Stream<Item = (usize, Result<Outcome, util::Error>)> + 'a
+where
+    F: Fn(usize) -> P + 'a,
+    P: Progress + Sync + 'a,
+{
+    batch(requests, concurrency, move |i| {
+        let progress = progress(i);
+        async move { crate::download(&requests[i], options, &progress).await }
+    })
+}
+
+/// Like `download_batch()`, but each download goes into the sink `sinks`
+/// returns for its index, as described for `download_to()`.
+pub fn download_batch_to<'a, F, P, G, S>(
+    requests: &'a [Request],
+    options: &'a Options,
+    concurrency: usize,
+    progress: F,
+    sinks: G,
+) -> impl Stream<Item = (usize, Result<Outcome, util::Error>)> + 'a
+where
+    F: Fn(usize) -> P + 'a,
+    P: Progress + Sync + 'a,
+    G: Fn(usize) -> S + 'a,
+    S: Sink + 'static,
+{
+    batch(requests, concurrency, move |i| {
+        let progress = progress(i);
+        let sink = sinks(i);
+        async move { crate::download_to(&requests[i], options, &progress, sink).await }
+    })
+}
+
+/// Run `task` for the index of every request, highest priority first and up
+/// to `concurrency` at a time.
+fn batch<'a, T, R>(
+    requests: &'a [Request],
+    concurrency: usize,
+    task: T,
+) -> impl Stream<Item = (usize, Result<Outcome, util::Error>)> + 'a
+where
+    T: Fn(usize) -> R + 'a,
+    R: Future<Output = Result<Outcome, util::Error>> + 'a,
+{
+    let mut order: Vec<usize> = (0..requests.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(requests[i].priority));
+
+    stream::iter(order)
+        .map(move |i| {
+            let task = task(i);
+            async move { (i, task.await) }
+        })
+        .buffer_unordered(concurrency.max(1))
+}
@@ -0,0 +1,110 @@
+//! Blocking API for synchronous programs.
+//!
+//! These functions run the async downloads on a runtime of their own, so a
+//! program without one can download files too.  They must not be called from
+//! async code, which should use `download()` instead.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::stream::StreamExt;
+
+use crate::stall::request_error;
+use crate::{rt, Options, Outcome, Progress, Request};
+
+/// Download `request` with the default options, blocking until it is done.
+/// Example:
+///
+/// ```ignore
+/// let request = Request::new(Url::parse("https://example.com/file.wav")?);
+/// let outcome = download::download_blocking(&request)?;
+/// ```
+pub fn download_blocking(request: &Request) -> Result<Outcome, util::Error> {
+    let client = reqwest::Client::builder().build().map_err(request_error)?;
+    download_blocking_with(request, &Options::new(client), &|_| {})
+}
+
+/// Download `request` with `options`, reporting progress to `progress` and
+/// blocking until it is done.  Like `download()`, for synchronous callers.
+pub fn download_blocking_with<P>(
+    request: &Request,
+    options: &Options,
+    progress: &P,
+) -> Result<Outcome, util::Error>
+where
+    P: Progress + Sync,
+{
+    rt::block_on(crate::download(request, options, progress)).map_err(runtime_error)?
+}
+
+/// Download all of `requests` with `options` as described for
+/// `download_batch()`, blocking until every one of them is done.  `progress`
+/// is called with the index of a request, the bytes of it done so far, and
+/// its size if known.  The results are in the order of `requests`.  Example:
+///
+/// ```ignore
+/// let results = download::download_batch_blocking(&requests, &options, 4, |i, done, total| {
+///     eprintln!("{}: {} of {:?} bytes", requests[i].output.display(), done, total);
+/// })?;
+/// ```
+pub fn download_batch_blocking<F>(
+    requests: &[Request],
+    options: &Options,
+    concurrency: usize,
+    progress: F,
+) -> Result<Vec<Result<Outcome, util::Error>>, util::Error>
+where
+    F: Fn(usize, u64, Option<u64>) + Sync,
+{
+    let counter = |index| Counter {
+        index,
+        done: AtomicU64::new(0),
+        total: AtomicU64::new(0),
+        callback: &progress,
+    };
+    let batch = crate::download_batch(requests, options, concurrency, counter).collect::<Vec<_>>();
+    let mut finished = rt::block_on(batch).map_err(runtime_error)?;
+    finished.sort_by_key(|(i, _)| *i);
+    Ok(finished.into_iter().map(|(_, result)| result).collect())
+}
+
+/// Turns the `Progress` reports of one download in a batch into calls of the
+/// batch's progress callback.
+struct Counter<'a, F> {
+    index: usize,
+    done: AtomicU64,
+    /// Size of the download, or 0 if unknown.
+    total: AtomicU64,
+    callback: &'a F,
+}
+impl<F: Fn(usize, u64, Option<u64>)> Counter<'_, F> {
+    /// Tell the callback where we are.
+    fn report(&self) {
+        let total = Some(self.total.load(Ordering::Relaxed)).filter(|&total| total > 0);
+        (self.callback)(self.index, self.done.load(Ordering::Relaxed), total);
+    }
+}
+impl<F: Fn(usize, u64, Option<u64>)> Progress for Counter<'_, F> {
+    fn advance(&self, n: u64) {
+        self.done.fetch_add(n, Ordering::Relaxed);
+        self.report();
+    }
+
+    fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+        self.report();
+    }
+
+    fn restart(&self, total: u64) {
+        self.done.store(0, Ordering::Relaxed);
+        self.set_total(total);
+    }
+}
+
+/// Wrap an error starting the runtime.
+fn runtime_error(error: std::io::Error) -> util::Error {
+    util::Error {
+        what: "Couldn't start the async runtime.".to_string(),
+        source: Some(error.into()),
+        kind: util::ErrorKind::Other,
+    }
+}
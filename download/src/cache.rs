@@ -0,0 +1,474 @@
+//! Opt-in on-disk HTTP cache shared by downloads, so jobs that fetch the same
+//! URLs don't download them again.
+//!
+//! Bodies are stored in a directory under names derived from their URL and
+//! the request headers named by the response's `Vary` header.  An `index.json`
+//! next to them records each body's URL, size, expiry, and when it was last
+//! used.  How long a body stays fresh comes from the `Cache-Control` `max-age`
+//! or the `Expires` header of the response; responses with
+//! `Cache-Control: no-store` or `Vary: *` are not stored.  Stale bodies are
+//! only served offline.  When the cache grows beyond its budget, the least
+//! recently used bodies are removed.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::lock::Mutex;
+use reqwest::header::{self, HeaderMap};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+use crate::rt;
+use crate::writer::write_atomic;
+
+/// Name of the index file in the cache directory.
+const INDEX: &str = "index.json";
+
+/// Lookups save when bodies were last used at most this often.  Storing a
+/// body always saves the index.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Numbers the temporary files of bodies being stored, so concurrent stores
+/// of the same body don't write the same file.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// One body in the cache.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Entry {
+    url: Url,
+    /// Request headers named by the response's `Vary` header and the values
+    /// they had when the body was stored.
+    vary: Vec<(String, Option<String>)>,
+    size: u64,
+    /// Seconds since the Unix epoch until which the body is fresh, or `None`
+    /// if it must not be served without asking the server.
+    expires: Option<u64>,
+    /// Milliseconds since the Unix epoch when the body was last stored or
+    /// served.
+    used: u64,
+}
+
+/// Contents of the index file, keyed by the name of each body's file.
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    entries: BTreeMap<String, Entry>,
+}
+
+/// In-memory state of the cache.
+struct State {
+    index: Index,
+    /// When the index file was last written.
+    saved: Instant,
+}
+
+/// A body found in the cache.
+#[derive(Clone, Debug)]
+pub struct Hit {
+    /// Where the body is stored.  Copy it rather than moving it.
+    pub path: PathBuf,
+    /// Size of the body in bytes.
+    pub size: u64,
+    /// May the body be served without asking the server?
+    pub fresh: bool,
+}
+
+/// On-disk cache of response bodies limited to a number of bytes.  Shared by
+/// every download in a batch.  Example:
+///
+/// ```ignore
+/// let options = Options {
+///     cache: Some(Arc::new(Cache::open("cache", 1024 * 1024 * 1024).await?)),
+///     ..Options::new(client)
+/// };
+/// ```
+pub struct Cache {
+    dir: PathBuf,
+    budget: u64,
+    state: Mutex<State>,
+}
+impl Cache {
+    /// Open the cache in `dir`, creating it if need be, and keep it under
+    /// `budget` bytes.
+    pub async fn open(dir: impl Into<PathBuf>, budget: u64) -> Result<Self, util::Error> {
+        let dir = dir.into();
+        rt::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| cache_error(&dir, e))?;
+        let path = dir.join(INDEX);
+        let mut index: Index = match rt::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| util::Error {
+                what: format!(
+                    "Invalid cache index {} at line {}, column {}.",
+                    path.display(),
+                    e.line(),
+                    e.column()
+                ),
+                source: Some(e.into()),
+                kind: util::ErrorKind::Other,
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Index::default(),
+            Err(e) => return Err(cache_error(&dir, e)),
+        };
+
+        // Forget bodies that were deleted behind our back.
+        let mut missing = Vec::new();
+        for name in index.entries.keys() {
+            if rt::fs::metadata(dir.join(name)).await.is_err() {
+                missing.push(name.clone());
+            }
+        }
+        for name in missing {
+            index.entries.remove(&name);
+        }
+        Ok(Cache {
+            dir,
+            budget,
+            state: Mutex::new(State {
+                index,
+                saved: Instant::now(),
+            }),
+        })
+    }
+
+    /// Directory holding the cache.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Find the body stored for `url` requested with `headers`, if any, and
+    /// mark it as used.  The index is saved at most once a minute.
+    pub async fn lookup(&self, url: &Url, headers: &HeaderMap) -> Result<Option<Hit>, util::Error> {
+        let mut state = self.state.lock().await;
+        let found = state
+            .index
+            .entries
+            .iter_mut()
+            .filter(|(_, entry)| entry.url == *url && vary_matches(&entry.vary, headers))
+            .max_by_key(|(_, entry)| entry.used);
+        let (name, entry) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        entry.used = millis(SystemTime::now());
+        let now = seconds(SystemTime::now());
+        let hit = Hit {
+            path: self.dir.join(name),
+            size: entry.size,
+            fresh: entry.expires.is_some_and(|expires| now < expires),
+        };
+        if state.saved.elapsed() >= SAVE_INTERVAL {
+            self.save(&mut state).await?;
+        }
+        Ok(Some(hit))
+    }
+
+    /// Store the downloaded `body` of `url`, which was requested with
+    /// `request` headers and answered with `response` headers, unless the
+    /// response forbids it.  Evicts the least recently used bodies if the
+    /// cache grows beyond its budget.  Returns whether the body was stored.
+    pub async fn store(
+        &self,
+        url: &Url,
+        request: &HeaderMap,
+        response: &HeaderMap,
+        body: &Path,
+    ) -> Result<bool, util::Error> {
+        let now = SystemTime::now();
+        let expires = match freshness(response, now) {
+            Some(expires) => expires,
+            None => return Ok(false),
+        };
+        let vary = match vary(request, response) {
+            Some(vary) => vary,
+            None => return Ok(false),
+        };
+        let size = rt::fs::metadata(body)
+            .await
+            .map_err(|e| cache_error(&self.dir, e))?
+            .len();
+        if size > self.budget {
+            return Ok(false);
+        }
+
+        // Copy the body in under a temporary name, so a lookup never sees it
+        // half-written.
+        let name = name_for(url, &vary);
+        let path = self.dir.join(&name);
+        let temp = self.dir.join(format!(
+            "{}.{}-{}.tmp",
+            name,
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        if let Err(e) = rt::fs::copy(body, &temp).await {
+            let _ = rt::fs::remove_file(&temp).await;
+            return Err(cache_error(&self.dir, e));
+        }
+        rt::fs::rename(&temp, &path)
+            .await
+            .map_err(|e| cache_error(&self.dir, e))?;
+
+        let mut state = self.state.lock().await;
+        let entry = Entry {
+            url: url.clone(),
+            vary,
+            size,
+            expires: expires.map(seconds),
+            used: millis(now),
+        };
+        state.index.entries.insert(name, entry);
+        self.evict(&mut state.index).await?;
+        self.save(&mut state).await?;
+        Ok(true)
+    }
+
+    /// Save when each body was last used, which lookups only save now and
+    /// then.  Call it once the batch is done.
+    pub async fn flush(&self) -> Result<(), util::Error> {
+        let mut state = self.state.lock().await;
+        self.save(&mut state).await
+    }
+
+    /// Remove the least recently used bodies until the cache fits its budget.
+    async fn evict(&self, index: &mut Index) -> Result<(), util::Error> {
+        let mut total: u64 = index.entries.values().map(|entry| entry.size).sum();
+        while total > self.budget {
+            let oldest = index
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(name, _)| name.clone());
+            let name = match oldest {
+                Some(name) => name,
+                None => break,
+            };
+            if let Some(entry) = index.entries.remove(&name) {
+                total -= entry.size;
+            }
+            match rt::fs::remove_file(self.dir.join(&name)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(cache_error(&self.dir, e))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the index file.
+    async fn save(&self, state: &mut State) -> Result<(), util::Error> {
+        let json =
+            serde_json::to_vec_pretty(&state.index).map_err(|e| cache_error(&self.dir, e))?;
+        write_atomic(&self.dir.join(INDEX), &json)
+            .await
+            .map_err(|e| cache_error(&self.dir, e))?;
+        state.saved = Instant::now();
+        Ok(())
+    }
+}
+impl std::fmt::Debug for Cache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cache")
+            .field("dir", &self.dir)
+            .field("budget", &self.budget)
+            .finish()
+    }
+}
+
+/// When a response with `headers` received at `now` stops being fresh:
+/// `None` if it must not be stored at all, `Some(None)` if it may be stored
+/// but is stale straight away.
+fn freshness(headers: &HeaderMap, now: SystemTime) -> Option<Option<SystemTime>> {
+    let mut max_age = None;
+    for directive in header_values(headers, header::CACHE_CONTROL) {
+        let directive = directive.to_ascii_lowercase();
+        match directive.as_str() {
+            "no-store" => return None,
+            "no-cache" => return Some(None),
+            _ => {
+                if let Some(age) = directive.strip_prefix("max-age=") {
+                    max_age = age.trim_matches('"').parse::<u64>().ok();
+                }
+            }
+        }
+    }
+
+    // max-age counts from when the response was generated, which was `Age`
+    // seconds ago if it came through another cache.
+    if let Some(max_age) = max_age {
+        let age = header_values(headers, header::AGE)
+            .next()
+            .and_then(|age| age.parse().ok())
+            .unwrap_or(0);
+        return Some(now.checked_add(Duration::from_secs(max_age.saturating_sub(age))));
+    }
+
+    // Expires is relative to the server's clock, so we go by how far it is
+    // past the server's Date.  Dates have commas of their own, so they aren't
+    // split into items.
+    let date = |name| {
+        headers
+            .get(name)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| httpdate::parse_http_date(date.trim()).ok())
+    };
+    match (date(header::EXPIRES), date(header::DATE)) {
+        (Some(expires), Some(date)) => Some(
+            expires
+                .duration_since(date)
+                .ok()
+                .and_then(|lifetime| now.checked_add(lifetime)),
+        ),
+        (Some(expires), None) => Some(Some(expires)),
+        _ => Some(None),
+    }
+}
+
+/// Request headers named by the `Vary` header of the response, with their
+/// values in `request`, or `None` if the response varies on something we
+/// can't see.
+fn vary(request: &HeaderMap, response: &HeaderMap) -> Option<Vec<(String, Option<String>)>> {
+    let mut vary = Vec::new();
+    for name in header_values(response, header::VARY) {
+        if name == "*" {
+            return None;
+        }
+        let name = name.to_ascii_lowercase();
+        let value = request
+            .get(name.as_str())
+            .and_then(|value| value.to_str().ok());
+        vary.push((name, value.map(String::from)));
+    }
+    vary.sort();
+    vary.dedup();
+    Some(vary)
+}
+
+/// Do `headers` have the values recorded in `vary`?
+fn vary_matches(vary: &[(String, Option<String>)], headers: &HeaderMap) -> bool {
+    vary.iter().all(|(name, value)| {
+        let actual = headers
+            .get(name.as_str())
+            .and_then(|value| value.to_str().ok());
+        actual == value.as_deref()
+    })
+}
+
+/// Name of the file storing the body of `url` for the headers in `vary`.
+fn name_for(url: &Url, vary: &[(String, Option<String>)]) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(url.as_str());
+    for (name, value) in vary {
+        hasher.update(b"\n");
+        hasher.update(name);
+        hasher.update(b":");
+        hasher.update(value.as_deref().unwrap_or(""));
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Comma-separated items of every `name` header.
+fn header_values<'a>(
+    headers: &'a HeaderMap,
+    name: header::HeaderName,
+) -> impl Iterator<Item = String> + 'a {
+    headers
+        .get_all(name)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+}
+
+/// `time` in seconds since the Unix epoch.
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// `time` in milliseconds since the Unix epoch.
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Error for a problem with the cache in `dir`.
+fn cache_error(dir: &Path, error: impl Into<util::BoxError>) -> util::Error {
+    util::Error {
+        what: format!("Couldn't update cache {}.", dir.display()),
+        source: Some(error.into()),
+        kind: util::ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Response headers made of `(name, value)` pairs.
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            let name = header::HeaderName::from_bytes(name.as_bytes()).unwrap();
+            headers.append(name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    /// Response headers and the freshness expected for them.
+    type Case<'a> = (&'a [(&'a str, &'a str)], Option<Option<SystemTime>>);
+
+    #[test]
+    fn computes_freshness() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let after = |secs| Some(Some(now + Duration::from_secs(secs)));
+        let date = httpdate::fmt_http_date(now - Duration::from_secs(1000));
+        let expires = httpdate::fmt_http_date(now - Duration::from_secs(900));
+        let expired = httpdate::fmt_http_date(now - Duration::from_secs(1100));
+        let cases: &[Case] = &[
+            (&[], Some(None)),
+            (&[("cache-control", "no-store")], None),
+            (&[("cache-control", "max-age=60, No-Store")], None),
+            (&[("cache-control", "no-cache")], Some(None)),
+            (&[("cache-control", "no-cache, max-age=60")], Some(None)),
+            (&[("cache-control", "public, max-age=60")], after(60)),
+            (&[("cache-control", "max-age=\"60\"")], after(60)),
+            // The response spent some of its lifetime in another cache.
+            (&[("cache-control", "max-age=60"), ("age", "20")], after(40)),
+            (&[("cache-control", "max-age=60"), ("age", "100")], after(0)),
+            // max-age wins over Expires.
+            (
+                &[("cache-control", "max-age=60"), ("expires", &expires), ("date", &date)],
+                after(60),
+            ),
+            // Expires counts from the server's Date, not our clock.
+            (&[("expires", &expires), ("date", &date)], after(100)),
+            (&[("expires", &expired), ("date", &date)], Some(None)),
+            (&[("expires", &expires)], Some(Some(now - Duration::from_secs(900)))),
+            (&[("expires", "0")], Some(None)),
+        ];
+        for (pairs, expected) in cases {
+            assert_eq!(freshness(&headers(pairs), now), *expected, "{:?}", pairs);
+        }
+    }
+
+    #[test]
+    fn refuses_to_store_what_varies_on_anything() {
+        let request = headers(&[("accept-language", "en")]);
+        assert_eq!(vary(&request, &headers(&[("vary", "*")])), None);
+        let response = headers(&[("vary", "Accept-Language, accept-encoding")]);
+        let expected = vec![
+            ("accept-encoding".to_string(), None),
+            ("accept-language".to_string(), Some("en".to_string())),
+        ];
+        assert_eq!(vary(&request, &response), Some(expected.clone()));
+        assert!(vary_matches(&expected, &request));
+        assert!(!vary_matches(&expected, &headers(&[("accept-language", "de")])));
+    }
+}
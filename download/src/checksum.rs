@@ -0,0 +1,170 @@
+//! Checksums for verifying the integrity of downloads.
+
+use std::fmt;
+use std::str::FromStr;
+
+use sha2::Digest;
+
+/// Hash algorithms supported for checksums.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    /// SHA-256
+    Sha256,
+    /// SHA-512
+    Sha512,
+}
+impl Algorithm {
+    /// Name of the algorithm as written in a checksum, e.g. `sha256`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// Start hashing with this algorithm.
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+        }
+    }
+}
+impl FromStr for Algorithm {
+    type Err = util::Error;
+
+    /// Parse an algorithm name.  Case and dashes are ignored, so `sha256`,
+    /// `SHA256`, and `sha-256` are all accepted.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Ok(Algorithm::Sha256),
+            "sha512" => Ok(Algorithm::Sha512),
+            _ => Err(util::Error {
+                what: format!("Unsupported hash algorithm {:?}.", name),
+                source: None,
+                kind: util::ErrorKind::Other,
+            }),
+        }
+    }
+}
+
+/// Incremental hash of a stream of bytes.
+#[derive(Clone, Debug)]
+pub enum Hasher {
+    /// SHA-256 in progress.
+    Sha256(sha2::Sha256),
+    /// SHA-512 in progress.
+    Sha512(sha2::Sha512),
+}
+impl Hasher {
+    /// Add `data` to the hash.
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Finish hashing and return the digest.
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// Expected digest of a file, written as `algorithm:hex`, e.g.
+/// `sha256:e3b0c442...`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checksum {
+    /// Algorithm used to compute the digest.
+    pub algorithm: Algorithm,
+    /// Expected digest.
+    pub digest: Vec<u8>,
+}
+impl Checksum {
+    /// Start hashing with this checksum's algorithm.
+    pub fn hasher(&self) -> Hasher {
+        self.algorithm.hasher()
+    }
+
+    /// Check that `digest`, computed with this checksum's algorithm, matches.
+    pub fn verify(&self, digest: &[u8]) -> Result<(), util::Error> {
+        if digest == &self.digest[..] {
+            return Ok(());
+        }
+        Err(util::Error {
+            what: format!(
+                "Checksum mismatch, expected {} but got {}:{}.",
+                self,
+                self.algorithm.name(),
+                hex::encode(digest)
+            ),
+            source: None,
+            kind: util::ErrorKind::Integrity,
+        })
+    }
+}
+impl FromStr for Checksum {
+    type Err = util::Error;
+
+    fn from_str(checksum: &str) -> Result<Self, Self::Err> {
+        let invalid = |source: Option<util::BoxError>| util::Error {
+            what: format!(
+                "Invalid checksum {:?}, expected algorithm:hex such as sha256:e3b0c442...",
+                checksum
+            ),
+            source,
+            kind: util::ErrorKind::Other,
+        };
+        let mut parts = checksum.splitn(2, ':');
+        let (algorithm, digest) = match (parts.next(), parts.next()) {
+            (Some(algorithm), Some(digest)) => (algorithm, digest),
+            _ => return Err(invalid(None)),
+        };
+        let algorithm: Algorithm = algorithm.parse().map_err(|e: util::Error| invalid(Some(e.into())))?;
+        let digest = hex::decode(digest).map_err(|e| invalid(Some(e.into())))?;
+        if digest.len() != algorithm.hasher().finish().len() {
+            return Err(invalid(None));
+        }
+        Ok(Checksum { algorithm, digest })
+    }
+}
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), hex::encode(&self.digest))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Checksum {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let checksum = String::deserialize(deserializer)?;
+        checksum.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Hashes of consecutive, fixed-length pieces of a file, so that a corrupted
+/// download can be repaired by fetching only the pieces that don't match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pieces {
+    /// Length of every piece but the last, which may be shorter.
+    pub length: u64,
+    /// Algorithm used to compute the digests.
+    pub algorithm: Algorithm,
+    /// Expected digest of each piece, in order.
+    pub digests: Vec<Vec<u8>>,
+}
+impl Pieces {
+    /// Byte range `start..end` of piece `i` in a file of `size` bytes.
+    pub fn range(&self, i: usize, size: u64) -> (u64, u64) {
+        let start = i as u64 * self.length;
+        (start.min(size), (start + self.length).min(size))
+    }
+
+    /// Is there a digest for each piece of a file of `size` bytes, and no
+    /// more?
+    pub fn fits(&self, size: u64) -> bool {
+        self.length > 0 && self.digests.len() as u64 == size.div_ceil(self.length)
+    }
+}
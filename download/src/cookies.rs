@@ -0,0 +1,496 @@
+//! A cookie jar shared by the requests of a batch.
+//!
+//! Some download portals set a session cookie on a landing page, often on
+//! the way through a redirect, and only serve the file to requests that send
+//! it back.  With a `CookieJar` in `Options::cookies`, the HTTP fetcher
+//! sends each request the cookies that match its URL and keeps the ones its
+//! responses set, following RFC 6265: a cookie goes back to the host that
+//! set it, or to the domain it names and its subdomains, for URLs under its
+//! path, only over HTTPS if it is secure, and until it expires.  Example:
+//!
+//! ```ignore
+//! let jar = Arc::new(CookieJar::load("cookies.txt".as_ref()).await?);
+//! let client = jar.apply(Client::builder()).build()?;
+//! let options = Options {
+//!     cookies: Some(jar.clone()),
+//!     ..Options::new(client)
+//! };
+//! // ... download ...
+//! jar.save("cookies.txt".as_ref()).await?;
+//! ```
+//!
+//! The jar is loaded from and saved to a `cookies.txt` file in the Netscape
+//! format that curl, wget, and browser extensions use: a line of seven tab
+//! separated fields per cookie, the domain, `TRUE` if subdomains get it too,
+//! the path, `TRUE` if it is secure, when it expires in seconds since the
+//! Unix epoch or 0 when the session ends, its name, and its value.  Session
+//! cookies are saved too, like curl does, so the next run can use them.
+
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::Url;
+
+use crate::rt;
+
+/// First line of a `cookies.txt` file.
+const HEADER: &str = "# Netscape HTTP Cookie File\n\
+                      # Written by the download crate.  Edit at your own risk.\n\n";
+
+/// Prefix of the lines of HTTP-only cookies in a `cookies.txt` file.
+const HTTP_ONLY: &str = "#HttpOnly_";
+
+/// 9999-12-31T23:59:59Z, the last date an `Expires` attribute can give, and
+/// the expiry of cookies meant to last longer, which never expire.
+const NEVER: Duration = Duration::from_secs(253_402_300_799);
+
+/// A cookie and where it goes.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    /// Never included in `Debug` output.
+    pub value: String,
+    /// Lowercase host or domain the cookie is sent to, without a leading
+    /// dot.
+    pub domain: String,
+    /// Is the cookie sent to `domain` only, and not its subdomains?
+    pub host_only: bool,
+    /// Path the cookie is sent for, and the paths under it.
+    pub path: String,
+    /// Is the cookie sent over HTTPS only?
+    pub secure: bool,
+    /// Is the cookie hidden from scripts?  Kept for saving.
+    pub http_only: bool,
+    /// When the cookie expires, or `None` for a session cookie.
+    pub expires: Option<SystemTime>,
+}
+impl Cookie {
+    /// Has the cookie expired at `now`?
+    fn expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Is the cookie sent with a request for `url`?
+    fn matches(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        };
+        let domain = if self.host_only {
+            host == self.domain
+        } else {
+            domain_match(&host, &self.domain)
+        };
+        domain && path_match(url.path(), &self.path) && (!self.secure || url.scheme() == "https")
+    }
+
+    /// Is this the same cookie as `other`, which replaces it?
+    fn same(&self, other: &Cookie) -> bool {
+        self.name == other.name && self.domain == other.domain && self.path == other.path
+    }
+}
+impl std::fmt::Debug for Cookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cookie")
+            .field("name", &self.name)
+            .field("domain", &self.domain)
+            .field("host_only", &self.host_only)
+            .field("path", &self.path)
+            .field("secure", &self.secure)
+            .field("http_only", &self.http_only)
+            .field("expires", &self.expires)
+            .finish()
+    }
+}
+
+/// Cookies shared by the requests of a batch.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Mutex<Vec<Cookie>>,
+}
+
+impl CookieJar {
+    /// An empty jar.
+    pub fn new() -> Self {
+        CookieJar::default()
+    }
+
+    /// The jar in the `cookies.txt` file at `path`, or an empty jar if there
+    /// is no file yet.  Expired cookies and lines that aren't cookies are
+    /// left out.
+    pub async fn load(path: &Path) -> Result<Self, util::Error> {
+        let text = match rt::fs::read_to_string(path).await {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(CookieJar::new()),
+            Err(e) => return Err(file_error("read", path, e)),
+        };
+        let now = SystemTime::now();
+        let cookies = text
+            .lines()
+            .filter_map(parse_line)
+            .filter(|cookie| !cookie.expired(now))
+            .collect();
+        Ok(CookieJar {
+            cookies: Mutex::new(cookies),
+        })
+    }
+
+    /// Save the cookies that haven't expired to a `cookies.txt` file at
+    /// `path`, which only its owner can read.
+    pub async fn save(&self, path: &Path) -> Result<(), util::Error> {
+        let mut text = HEADER.to_string();
+        for cookie in self.cookies() {
+            let expires = cookie
+                .expires
+                .and_then(|expires| expires.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |expires| expires.as_secs().max(1));
+            text.push_str(&format!(
+                "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if cookie.http_only { HTTP_ONLY } else { "" },
+                if cookie.host_only { "" } else { "." },
+                cookie.domain,
+                if cookie.host_only { "FALSE" } else { "TRUE" },
+                cookie.path,
+                if cookie.secure { "TRUE" } else { "FALSE" },
+                expires,
+                cookie.name,
+                cookie.value
+            ));
+        }
+
+        // Write a new file and move it into place, so an interrupted save
+        // doesn't lose the jar.
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".part");
+        let (partial, target) = (std::path::PathBuf::from(partial), path.to_path_buf());
+        rt::fs::blocking(move || {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            let mut file = options.open(&partial)?;
+            file.write_all(text.as_bytes())?;
+            file.sync_all()?;
+            std::fs::rename(&partial, &target)
+        })
+        .await
+        .map_err(|e| file_error("save", path, e))
+    }
+
+    /// Leave redirects to the HTTP fetcher, which keeps the cookies set
+    /// along the way, instead of the client.  The fetcher refuses the same
+    /// redirects as `auth::redirect_policy()`.  Without this, the client
+    /// follows redirects itself and the cookies they set are lost.
+    pub fn apply(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        builder.redirect(reqwest::redirect::Policy::none())
+    }
+
+    /// The cookies that haven't expired.
+    pub fn cookies(&self) -> Vec<Cookie> {
+        let now = SystemTime::now();
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|cookie| !cookie.expired(now));
+        cookies.clone()
+    }
+
+    /// Add `cookie`, in place of the one with the same name, domain and
+    /// path.  An expired cookie only removes that one.
+    pub fn insert(&self, cookie: Cookie) {
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|old| !old.same(&cookie));
+        if !cookie.expired(SystemTime::now()) {
+            cookies.push(cookie);
+        }
+    }
+
+    /// `Cookie` header for a request for `url`, if any cookies match it.
+    /// Cookies with longer paths come first.
+    pub fn header(&self, url: &Url) -> Option<HeaderValue> {
+        let mut cookies: Vec<Cookie> = self
+            .cookies()
+            .into_iter()
+            .filter(|cookie| cookie.matches(url))
+            .collect();
+        if cookies.is_empty() {
+            return None;
+        }
+        cookies.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        let pairs: Vec<String> = cookies
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect();
+        let mut value = HeaderValue::from_str(&pairs.join("; ")).ok()?;
+        value.set_sensitive(true);
+        Some(value)
+    }
+
+    /// Keep the cookies that the response for `url` with `headers` sets.
+    /// Cookies for another domain, or secure ones set over plain HTTP, are
+    /// ignored.
+    pub fn store(&self, url: &Url, headers: &HeaderMap) {
+        let now = SystemTime::now();
+        for set_cookie in headers.get_all(header::SET_COOKIE) {
+            if let Some(cookie) = set_cookie
+                .to_str()
+                .ok()
+                .and_then(|set_cookie| parse_set_cookie(url, set_cookie, now))
+            {
+                self.insert(cookie);
+            }
+        }
+    }
+}
+
+/// Cookie from the `Set-Cookie` header `set_cookie` of the response for
+/// `url`, as in section 5.2 of RFC 6265, or `None` if it is invalid or not
+/// for `url`.
+fn parse_set_cookie(url: &Url, set_cookie: &str, now: SystemTime) -> Option<Cookie> {
+    let host = url.host_str()?.to_lowercase();
+    let mut attributes = set_cookie.split(';');
+    let pair = attributes.next()?;
+    let eq = pair.find('=')?;
+    let name = pair[..eq].trim();
+    if name.is_empty() {
+        return None;
+    }
+    let mut cookie = Cookie {
+        name: name.to_string(),
+        value: pair[eq + 1..].trim().to_string(),
+        domain: host.clone(),
+        host_only: true,
+        path: default_path(url.path()),
+        secure: false,
+        http_only: false,
+        expires: None,
+    };
+    let mut max_age = None;
+    for attribute in attributes {
+        let (key, value) = match attribute.find('=') {
+            Some(eq) => (attribute[..eq].trim(), attribute[eq + 1..].trim()),
+            None => (attribute.trim(), ""),
+        };
+        match key.to_lowercase().as_str() {
+            "expires" => cookie.expires = parse_date(value),
+            "max-age" => {
+                if let Ok(seconds) = value.parse::<i64>() {
+                    max_age = Some(match seconds {
+                        seconds if seconds <= 0 => UNIX_EPOCH,
+                        seconds => after(now, seconds as u64),
+                    });
+                }
+            }
+            "domain" if !value.is_empty() => {
+                let domain = value.trim_start_matches('.').to_lowercase();
+                // Without a list of public suffixes, a domain must at least
+                // have a dot, so no cookie goes to a whole top level domain.
+                // A host without one keeps its cookies to itself.
+                if !domain_match(&host, &domain) || (!domain.contains('.') && domain != host) {
+                    return None;
+                }
+                cookie.host_only = !domain.contains('.');
+                cookie.domain = domain;
+            }
+            "path" if value.starts_with('/') => cookie.path = value.to_string(),
+            "secure" => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            _ => {}
+        }
+    }
+    if max_age.is_some() {
+        cookie.expires = max_age;
+    }
+    if cookie.secure && url.scheme() != "https" {
+        return None;
+    }
+    Some(cookie)
+}
+
+/// Cookie from a line of a `cookies.txt` file, unless it is a comment or
+/// invalid.
+fn parse_line(line: &str) -> Option<Cookie> {
+    let (line, http_only) = match line.strip_prefix(HTTP_ONLY) {
+        Some(line) => (line, true),
+        None => (line, false),
+    };
+    if line.starts_with('#') || line.trim().is_empty() {
+        return None;
+    }
+    let fields: Vec<&str> = line.trim_end_matches(['\r', '\n']).split('\t').collect();
+    if fields.len() != 7 {
+        return None;
+    }
+    let expires: u64 = fields[4].parse().ok()?;
+    let domain = fields[0].trim_start_matches('.').to_lowercase();
+    if domain.is_empty() || fields[5].is_empty() {
+        return None;
+    }
+    Some(Cookie {
+        name: fields[5].to_string(),
+        value: fields[6].to_string(),
+        domain,
+        host_only: !fields[1].eq_ignore_ascii_case("TRUE"),
+        path: fields[2].to_string(),
+        secure: fields[3].eq_ignore_ascii_case("TRUE"),
+        http_only,
+        expires: match expires {
+            0 => None,
+            secs => Some(after(UNIX_EPOCH, secs)),
+        },
+    })
+}
+
+/// `secs` seconds after `time`, or `NEVER` if that is later, so a lifetime
+/// too long to represent doesn't overflow.
+fn after(time: SystemTime, secs: u64) -> SystemTime {
+    let never = UNIX_EPOCH + NEVER;
+    time.checked_add(Duration::from_secs(secs))
+        .map_or(never, |expires| expires.min(never))
+}
+
+/// The date `value` of an `Expires` attribute.  Servers also send dates with
+/// dashes, like `Wed, 21-Oct-2015 07:28:00 GMT`.
+fn parse_date(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value)
+        .or_else(|_| httpdate::parse_http_date(&value.replace('-', " ")))
+        .ok()
+}
+
+/// Does `host` domain-match `domain`, as in section 5.1.3 of RFC 6265?
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.ends_with(domain)
+            && host[..host.len() - domain.len()].ends_with('.')
+            && host.parse::<std::net::IpAddr>().is_err())
+}
+
+/// Does the request path `path` path-match `cookie_path`, as in section
+/// 5.1.4 of RFC 6265?
+fn path_match(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+/// Path of cookies without a `Path` attribute for a request for `path`: its
+/// directory.
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(slash) => path[..slash].to_string(),
+    }
+}
+
+/// Error for a `cookies.txt` file at `path` that couldn't be `verb`ed.
+fn file_error(verb: &str, path: &Path, error: std::io::Error) -> util::Error {
+    util::Error {
+        what: format!("Couldn't {} the cookie jar {}.", verb, path.display()),
+        source: Some(error.into()),
+        kind: util::ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The cookie that `set_cookie` sets in the response for `url`.
+    fn set(url: &str, set_cookie: &str) -> Option<Cookie> {
+        parse_set_cookie(&Url::parse(url).unwrap(), set_cookie, SystemTime::now())
+    }
+
+    fn sent_to(cookie: &Cookie, url: &str) -> bool {
+        cookie.matches(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn keeps_cookies_without_a_domain_to_their_host() {
+        let cookie = set("https://Portal.example.com/files/a.zip", "session=1").unwrap();
+        assert_eq!(cookie.domain, "portal.example.com");
+        assert!(cookie.host_only);
+        assert_eq!(cookie.path, "/files");
+        assert!(sent_to(&cookie, "https://portal.example.com/files/b.zip"));
+        assert!(!sent_to(&cookie, "https://cdn.portal.example.com/files/b.zip"));
+        assert!(!sent_to(&cookie, "https://example.com/files/b.zip"));
+    }
+
+    #[test]
+    fn sends_cookies_with_a_domain_to_its_subdomains() {
+        let cookie = set("https://portal.example.com/", "session=1; Domain=.Example.com").unwrap();
+        assert_eq!(cookie.domain, "example.com");
+        assert!(!cookie.host_only);
+        assert!(sent_to(&cookie, "https://example.com/"));
+        assert!(sent_to(&cookie, "https://cdn.example.com/"));
+        assert!(!sent_to(&cookie, "https://badexample.com/"));
+        assert!(!sent_to(&cookie, "https://example.org/"));
+    }
+
+    #[test]
+    fn rejects_domains_that_are_too_wide_or_foreign() {
+        // A whole top level domain, or someone else's.
+        assert_eq!(set("https://portal.example.com/", "a=1; Domain=com"), None);
+        assert_eq!(set("https://portal.example.com/", "a=1; Domain=example.org"), None);
+        assert_eq!(set("https://portal.example.com/", "a=1; Domain=cdn.example.com"), None);
+        assert_eq!(set("https://example.com/", "a=1; Domain=portal.example.com"), None);
+        // Nor may an address set one for its neighbours.
+        assert_eq!(set("http://10.0.0.1/", "a=1; Domain=0.0.1"), None);
+        // A host without a dot may name itself, and keeps the cookie.
+        let cookie = set("http://localhost/", "a=1; Domain=localhost").unwrap();
+        assert!(cookie.host_only);
+    }
+
+    #[test]
+    fn matches_paths() {
+        let cases = [
+            ("/", "/", true),
+            ("/anything", "/", true),
+            ("/files", "/files", true),
+            ("/files/a.zip", "/files", true),
+            ("/files/a.zip", "/files/", true),
+            ("/filesystem", "/files", false),
+            ("/file", "/files", false),
+            ("/", "/files", false),
+        ];
+        for (path, cookie_path, expected) in &cases {
+            assert_eq!(path_match(path, cookie_path), *expected, "{} {}", path, cookie_path);
+        }
+        assert_eq!(default_path("/"), "/");
+        assert_eq!(default_path("/a.zip"), "/");
+        assert_eq!(default_path("/files/a.zip"), "/files");
+    }
+
+    #[test]
+    fn keeps_secure_cookies_to_https() {
+        assert_eq!(set("http://portal.example.com/", "a=1; Secure"), None);
+        let cookie = set("https://portal.example.com/", "a=1; Secure").unwrap();
+        assert!(sent_to(&cookie, "https://portal.example.com/"));
+        assert!(!sent_to(&cookie, "http://portal.example.com/"));
+    }
+
+    #[test]
+    fn deletes_cookies_with_no_age_left() {
+        let url = Url::parse("https://portal.example.com/").unwrap();
+        let jar = CookieJar::new();
+        let mut headers = HeaderMap::new();
+        headers.append(header::SET_COOKIE, HeaderValue::from_static("a=1"));
+        headers.append(header::SET_COOKIE, HeaderValue::from_static("b=2; Max-Age=60"));
+        jar.store(&url, &headers);
+        assert_eq!(jar.cookies().len(), 2);
+
+        // Max-Age wins over Expires, and 0 or less means now.
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("a=; Max-Age=0; Expires=Fri, 31 Dec 9999 23:59:59 GMT"),
+        );
+        headers.append(header::SET_COOKIE, HeaderValue::from_static("b=; Max-Age=-1"));
+        jar.store(&url, &headers);
+        assert!(jar.cookies().is_empty());
+        assert_eq!(jar.header(&url), None);
+    }
+}
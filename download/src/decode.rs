@@ -0,0 +1,177 @@
+//! Decoding compressed response bodies.
+
+use std::io::Write;
+use std::mem::take;
+
+use bytes::Bytes;
+
+/// What to ask servers for and what to do with a response body that has a
+/// `Content-Encoding`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Ask for the file as it is, and decode it if the server compresses it
+    /// anyway.
+    #[default]
+    Identity,
+    /// Ask for compressed transfer with gzip, deflate, Brotli, or zstd, and
+    /// decode the body as it arrives.
+    Decode,
+    /// Ask for compressed transfer and save the body as the server sent it,
+    /// still encoded.
+    Preserve,
+}
+impl Compression {
+    /// Value of the `Accept-Encoding` header for a request.  Byte ranges of a
+    /// compressed body can't be decoded on their own, so a `ranged` request
+    /// asks for the file as it is unless the encoded form is to be kept.
+    pub(crate) fn accept_encoding(self, ranged: bool) -> &'static str {
+        match self {
+            Compression::Identity => "identity",
+            Compression::Decode if ranged => "identity",
+            Compression::Decode | Compression::Preserve => "gzip, deflate, br, zstd",
+        }
+    }
+}
+
+/// Decodes a body with a `Content-Encoding` one chunk at a time.  Example:
+///
+/// ```ignore
+/// let mut decoder = Decoder::new("gzip")?;
+/// while let Some(chunk) = download.chunk().await? {
+///     writer.write(decoder.decode(&chunk)?).await?;
+/// }
+/// writer.write(decoder.finish()?).await?;
+/// ```
+pub struct Decoder {
+    encoding: String,
+    inner: Inner,
+}
+
+/// The decompressor for each encoding, writing into a buffer we empty after
+/// every chunk.
+enum Inner {
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+    Deflate(flate2::write::ZlibDecoder<Vec<u8>>),
+    Brotli(Box<brotli_decompressor::DecompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::zio::Writer<Vec<u8>, zstd::stream::raw::Decoder<'static>>),
+}
+
+impl Decoder {
+    /// Decoder for the `Content-Encoding` header value `encoding`, or `None`
+    /// for `identity`.  Fails for encodings we don't support, including more
+    /// than one encoding applied in turn.
+    pub fn new(encoding: &str) -> Result<Option<Self>, util::Error> {
+        let encoding = encoding.trim().to_ascii_lowercase();
+        let inner = match encoding.as_str() {
+            "" | "identity" => return Ok(None),
+            "gzip" | "x-gzip" => Inner::Gzip(flate2::write::GzDecoder::new(Vec::new())),
+            "deflate" => Inner::Deflate(flate2::write::ZlibDecoder::new(Vec::new())),
+            "br" => Inner::Brotli(Box::new(brotli_decompressor::DecompressorWriter::new(
+                Vec::new(),
+                64 * 1024,
+            ))),
+            "zstd" => Inner::Zstd(zstd::stream::zio::Writer::new(
+                Vec::new(),
+                zstd::stream::raw::Decoder::new().map_err(|e| decode_error(&encoding, e))?,
+            )),
+            _ => {
+                return Err(util::Error {
+                    what: format!("Unsupported Content-Encoding {:?}.", encoding),
+                    source: None,
+                    kind: util::ErrorKind::Other,
+                })
+            }
+        };
+        Ok(Some(Decoder { encoding, inner }))
+    }
+
+    /// Decode the next `chunk` of the body and return whatever it decoded
+    /// to so far, which may be nothing.
+    pub fn decode(&mut self, chunk: &[u8]) -> Result<Bytes, util::Error> {
+        let encoding = &self.encoding;
+        let output = match &mut self.inner {
+            Inner::Gzip(decoder) => decoder.write_all(chunk).map(|()| take(decoder.get_mut())),
+            Inner::Deflate(decoder) => decoder.write_all(chunk).map(|()| take(decoder.get_mut())),
+            Inner::Brotli(decoder) => decoder.write_all(chunk).map(|()| take(decoder.get_mut())),
+            Inner::Zstd(decoder) => decoder.write_all(chunk).map(|()| take(decoder.writer_mut())),
+        };
+        let output = output.map_err(|e| decode_error(encoding, e))?;
+        Ok(Bytes::from(output))
+    }
+
+    /// Finish decoding at the end of the body and return the rest of the
+    /// output.  Fails if the body was cut short, e.g. in the middle of a zstd
+    /// frame.
+    pub fn finish(self) -> Result<Bytes, util::Error> {
+        let encoding = self.encoding;
+        let output = match self.inner {
+            Inner::Gzip(decoder) => decoder.finish(),
+            Inner::Deflate(decoder) => decoder.finish(),
+            Inner::Brotli(mut decoder) => decoder.close().and_then(|()| {
+                decoder.into_inner().map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "incomplete stream")
+                })
+            }),
+            // Unlike flush(), finish() fails unless the last frame ended.
+            Inner::Zstd(mut decoder) => decoder.finish().map(|()| decoder.into_inner().0),
+        };
+        let output = output.map_err(|e| decode_error(&encoding, e))?;
+        Ok(Bytes::from(output))
+    }
+}
+impl std::fmt::Debug for Decoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Decoder")
+            .field("encoding", &self.encoding)
+            .finish()
+    }
+}
+
+/// Error for a body that failed to decode from `encoding`.
+fn decode_error(encoding: &str, error: std::io::Error) -> util::Error {
+    util::Error {
+        what: format!("Couldn't decode {} response.", encoding),
+        source: Some(error.into()),
+        kind: util::ErrorKind::Integrity,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode `body` with a new decoder for `encoding`, a few bytes at a time.
+    fn decode_all(encoding: &str, body: &[u8]) -> Result<Vec<u8>, util::Error> {
+        let mut decoder = Decoder::new(encoding)?.unwrap();
+        let mut output = Vec::new();
+        for chunk in body.chunks(7) {
+            output.extend_from_slice(&decoder.decode(chunk)?);
+        }
+        output.extend_from_slice(&decoder.finish()?);
+        Ok(output)
+    }
+
+    #[test]
+    fn decodes_zstd() {
+        let content = b"hello, world\n".repeat(100);
+        let body = zstd::encode_all(&content[..], 3).unwrap();
+        assert_eq!(decode_all("zstd", &body).unwrap(), content);
+    }
+
+    #[test]
+    fn fails_a_truncated_zstd_frame() {
+        let content = b"hello, world\n".repeat(100);
+        let body = zstd::encode_all(&content[..], 3).unwrap();
+        let error = decode_all("zstd", &body[..body.len() - 4]).unwrap_err();
+        assert_eq!(error.kind, util::ErrorKind::Integrity);
+    }
+
+    #[test]
+    fn fails_a_truncated_gzip_stream() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&b"hello, world\n".repeat(100)).unwrap();
+        let body = encoder.finish().unwrap();
+        let error = decode_all("gzip", &body[..body.len() - 4]).unwrap_err();
+        assert_eq!(error.kind, util::ErrorKind::Integrity);
+    }
+}
@@ -0,0 +1,326 @@
+//! Extracting downloaded archives.
+//!
+//! Tar archives compressed with gzip or zstd are extracted while they
+//! download, straight from the network, without writing the archive to disk.
+//! They are unpacked into a staging directory next to the destination and
+//! only moved into place once the download has been verified.
+//! Zip archives keep their index at the end, so they are extracted once the
+//! download is finished.  Entries that would land outside the destination
+//! directory, through `..`, an absolute path, or a link, are refused.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{rt, Progress, Writer};
+
+/// How often progress is reported while extracting a finished download.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Archive formats that can be extracted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Tar archive compressed with gzip, `.tar.gz` or `.tgz`.
+    TarGz,
+    /// Tar archive compressed with zstd, `.tar.zst` or `.tzst`.
+    TarZst,
+    /// Zip archive, `.zip`.
+    Zip,
+}
+
+/// File name extensions of each format, in lowercase.
+const EXTENSIONS: &[(&str, Format)] = &[
+    (".tar.gz", Format::TarGz),
+    (".tgz", Format::TarGz),
+    (".tar.zst", Format::TarZst),
+    (".tzst", Format::TarZst),
+    (".zip", Format::Zip),
+];
+
+impl Format {
+    /// Recognize the format from the extension of `path`, e.g. `.tar.gz`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        extension(path).map(|(_, format)| format)
+    }
+
+    /// Directory to extract the archive at `path` into by default: `path`
+    /// without its archive extension.
+    pub fn directory_for(path: &Path) -> Option<PathBuf> {
+        let (len, _) = extension(path)?;
+        let name = path.file_name()?.to_str()?;
+        let stem = &name[..name.len() - len];
+        if stem.is_empty() {
+            return None;
+        }
+        Some(path.with_file_name(stem))
+    }
+
+    /// Can archives in this format be extracted as they download?
+    pub fn streams(self) -> bool {
+        self != Format::Zip
+    }
+}
+
+/// Length of the archive extension of `path` and the format it stands for.
+fn extension(path: &Path) -> Option<(usize, Format)> {
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    EXTENSIONS
+        .iter()
+        .find(|(ext, _)| name.ends_with(ext))
+        .map(|&(ext, format)| (ext.len(), format))
+}
+
+/// Writer that extracts a tar archive in `format` into `dest` as its chunks
+/// arrive.  Example:
+///
+/// ```ignore
+/// let mut writer = extract::unpacker(Format::TarGz, PathBuf::from("out"));
+/// while let Some(chunk) = download.chunk().await? {
+///     writer.write(chunk).await?;
+/// }
+/// writer.finish().await?;
+/// ```
+pub fn unpacker(format: Format, dest: PathBuf) -> Writer {
+    Writer::spawn_blocking(move |reader| unpack_tar(reader, format, &dest))
+}
+
+/// Directory next to `dest` that an archive extracted as it downloads is
+/// unpacked into until the download has been verified, e.g. `out.extracting`
+/// for `out`.  `None` if `dest` has no name to put one next to, like `.`.
+pub fn staging(dest: &Path) -> Option<PathBuf> {
+    let mut name = dest.file_name()?.to_owned();
+    name.push(".extracting");
+    Some(dest.with_file_name(name))
+}
+
+/// Move everything unpacked into `staging` into `dest`, replacing files that
+/// are already there, and remove `staging`.
+pub async fn move_into(staging: &Path, dest: &Path) -> Result<(), util::Error> {
+    let (staging, dest) = (staging.to_owned(), dest.to_owned());
+    rt::spawn_blocking(move || {
+        merge(&staging, &dest).map_err(|e| extract_error(&dest, e))?;
+        match std::fs::remove_dir_all(&staging) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(extract_error(&dest, e)),
+            _ => Ok(()),
+        }
+    })
+    .await?
+}
+
+/// Extract the finished archive at `archive` in `format` into `dest`.  The
+/// bytes of the archive read so far are reported to `progress` as a new
+/// phase.
+pub async fn extract_file<P: Progress>(
+    archive: &Path,
+    format: Format,
+    dest: &Path,
+    progress: &P,
+) -> Result<(), util::Error> {
+    let total = rt::fs::metadata(archive)
+        .await
+        .map_err(|e| extract_error(dest, e))?
+        .len();
+    progress.restart(total);
+
+    let read = Arc::new(AtomicU64::new(0));
+    let mut task = {
+        let (archive, dest, read) = (archive.to_owned(), dest.to_owned(), read.clone());
+        rt::spawn_blocking(move || {
+            let file = std::fs::File::open(&archive).map_err(|e| extract_error(&dest, e))?;
+            let reader = Counting { inner: file, read };
+            match format {
+                Format::Zip => unpack_zip(reader, &dest),
+                _ => unpack_tar(reader, format, &dest),
+            }
+        })
+    };
+
+    // The extraction runs on its own thread, so we look in on it regularly.
+    // Zip archives are read partly out of order, so the count may pass the
+    // total.
+    let mut reported = 0;
+    loop {
+        let done = rt::timeout(PROGRESS_INTERVAL, &mut task).await;
+        let now = read.load(Ordering::Relaxed).min(total);
+        progress.advance(now.saturating_sub(reported));
+        reported = reported.max(now);
+        if let Some(result) = done {
+            return result?;
+        }
+    }
+}
+
+/// Extract the tar archive compressed in `format` that `reader` reads into
+/// `dest`.
+fn unpack_tar<R: Read>(reader: R, format: Format, dest: &Path) -> Result<(), util::Error> {
+    match format {
+        Format::TarGz => unpack_entries(
+            tar::Archive::new(flate2::read::GzDecoder::new(reader)),
+            dest,
+        ),
+        Format::TarZst => {
+            let decoder = zstd::Decoder::new(reader).map_err(|e| extract_error(dest, e))?;
+            unpack_entries(tar::Archive::new(decoder), dest)
+        }
+        Format::Zip => Err(util::Error {
+            what: "Zip archives can't be extracted while they download.".to_string(),
+            source: None,
+            kind: util::ErrorKind::Other,
+        }),
+    }
+}
+
+/// Extract every entry of the tar `archive` into `dest`.
+fn unpack_entries<R: Read>(mut archive: tar::Archive<R>, dest: &Path) -> Result<(), util::Error> {
+    std::fs::create_dir_all(dest).map_err(|e| extract_error(dest, e))?;
+    for entry in archive.entries().map_err(|e| extract_error(dest, e))? {
+        let mut entry = entry.map_err(|e| extract_error(dest, e))?;
+        let path = entry
+            .path()
+            .map_err(|e| extract_error(dest, e))?
+            .into_owned();
+        if !contained(&path) {
+            return Err(outside(dest, &path));
+        }
+
+        // Symbolic links point relative to their own directory, hard links
+        // relative to the top of the archive.
+        if let Some(target) = entry.link_name().map_err(|e| extract_error(dest, e))? {
+            let target = match entry.header().entry_type() {
+                tar::EntryType::Symlink => path.parent().unwrap_or(&path).join(target),
+                _ => target.into_owned(),
+            };
+            if !contained(&target) {
+                return Err(outside(dest, &path));
+            }
+        }
+
+        // unpack_in() makes its own checks and skips entries that fail them.
+        if !entry.unpack_in(dest).map_err(|e| extract_error(dest, e))? {
+            return Err(outside(dest, &path));
+        }
+    }
+    Ok(())
+}
+
+/// Extract every entry of the zip archive that `reader` reads into `dest`.
+fn unpack_zip<R: Read + Seek>(reader: R, dest: &Path) -> Result<(), util::Error> {
+    let mut archive = zip::ZipArchive::new(reader).map_err(|e| extract_error(dest, e))?;
+    std::fs::create_dir_all(dest).map_err(|e| extract_error(dest, e))?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(|e| extract_error(dest, e))?;
+        let path = match file.enclosed_name() {
+            Some(name) if contained(name) => dest.join(name),
+            _ => return Err(outside(dest, Path::new(file.name()))),
+        };
+        if file.is_dir() {
+            std::fs::create_dir_all(&path).map_err(|e| extract_error(dest, e))?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| extract_error(dest, e))?;
+        }
+        let mut output = std::fs::File::create(&path).map_err(|e| extract_error(dest, e))?;
+        std::io::copy(&mut file, &mut output).map_err(|e| extract_error(dest, e))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Some(mode) = file.unix_mode() {
+                let permissions = std::fs::Permissions::from_mode(mode & 0o777);
+                std::fs::set_permissions(&path, permissions).map_err(|e| extract_error(dest, e))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Move the directory `from` to `to`, merging it with a directory already
+/// there and replacing anything else.  Links in `to` are replaced, never
+/// followed.
+fn merge(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(to) {
+        Ok(meta) if meta.is_dir() => {}
+        Ok(_) => {
+            std::fs::remove_file(to)?;
+            return std::fs::rename(from, to);
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return std::fs::rename(from, to),
+        Err(e) => return Err(e),
+    }
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            merge(&entry.path(), &target)?;
+            continue;
+        }
+        if let Ok(meta) = std::fs::symlink_metadata(&target) {
+            if meta.is_dir() {
+                std::fs::remove_dir_all(&target)?;
+            }
+        }
+        std::fs::rename(entry.path(), &target)?;
+    }
+    Ok(())
+}
+
+/// Does the relative `path` stay inside the directory it is relative to?
+/// Only the path itself is considered, not links already on disk.
+fn contained(path: &Path) -> bool {
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return false,
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// Counts the bytes read from a file for reporting progress.
+struct Counting<R> {
+    inner: R,
+    read: Arc<AtomicU64>,
+}
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+impl<R: Seek> Seek for Counting<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Error for an archive entry at `path` that would be extracted outside
+/// `dest`.
+fn outside(dest: &Path, path: &Path) -> util::Error {
+    util::Error {
+        what: format!(
+            "Refusing to extract {} because it would end up outside {}.",
+            path.display(),
+            dest.display()
+        ),
+        source: None,
+        kind: util::ErrorKind::Other,
+    }
+}
+
+/// Error for a problem extracting an archive into `dest`.
+fn extract_error(dest: &Path, error: impl Into<util::BoxError>) -> util::Error {
+    util::Error {
+        what: format!("Couldn't extract archive into {}.", dest.display()),
+        source: Some(error.into()),
+        kind: util::ErrorKind::Other,
+    }
+}
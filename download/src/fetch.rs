@@ -0,0 +1,756 @@
+//! Transports that files can be downloaded over.
+//!
+//! A `Fetcher` asks for the metadata of a file and streams its bytes, or a
+//! range of them.  A download picks the fetcher for each of its URLs by the
+//! URL's scheme: `Http` for `http:` and `https:`, `File` for `file:`, and
+//! `Data` for `data:`, unless `Options::fetchers` says otherwise.  `Mock`
+//! serves files from memory, for testing downloads without a server.
+
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::future::{self, BoxFuture};
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::{StatusCode, Url};
+use sha2::{Digest, Sha256};
+
+use crate::auth::{check_redirect, Credential, Credentials, MAX_REDIRECTS};
+use crate::cookies::CookieJar;
+use crate::aws::SigV4;
+use crate::rt;
+use crate::stall::request_error;
+use crate::{Options, Timeouts};
+
+/// Size of the chunks read from files and served from memory.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bytes `start..end` of a file, or from `start` to the end of the file if
+/// `end` is `None`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Range {
+    /// Offset of the first byte.
+    pub start: u64,
+    /// Offset just past the last byte, if not the end of the file.
+    pub end: Option<u64>,
+}
+
+/// What a fetcher can tell about a file without fetching it.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    /// Size of the file as it would be sent, from `Content-Length`.
+    pub size: Option<u64>,
+    /// `ETag` of the file.
+    pub etag: Option<String>,
+    /// `Last-Modified` date of the file.
+    pub last_modified: Option<String>,
+    /// Can ranges of the file be fetched?
+    pub ranges: bool,
+    /// `Content-Encoding` of the file, unless it is sent as it is.
+    pub encoding: Option<String>,
+    /// Is the file unchanged since the `If-None-Match` or
+    /// `If-Modified-Since` headers of the probe?
+    pub not_modified: bool,
+    /// The response headers, or headers describing the file the way a server
+    /// would, for deciding whether to cache it.
+    pub headers: HeaderMap,
+}
+impl Metadata {
+    /// Metadata from the `headers` of an HTTP response.
+    pub fn from_headers(headers: &HeaderMap, not_modified: bool) -> Self {
+        Metadata {
+            size: header_str(headers, header::CONTENT_LENGTH).and_then(|len| len.parse().ok()),
+            etag: header_str(headers, header::ETAG).map(String::from),
+            last_modified: header_str(headers, header::LAST_MODIFIED).map(String::from),
+            ranges: header_str(headers, header::ACCEPT_RANGES) == Some("bytes"),
+            encoding: content_encoding(headers),
+            not_modified,
+            headers: headers.clone(),
+        }
+    }
+}
+
+/// The bytes of a file, or of a range of it, as they arrive.
+pub struct Body {
+    /// Does the body start at the start of the range asked for?  If not, it
+    /// starts at the beginning of the file.
+    pub partial: bool,
+    /// `Content-Encoding` of the body, unless it is sent as it is.
+    pub encoding: Option<String>,
+    /// Headers of the response carrying the body, or none if the transport
+    /// has no headers.
+    pub headers: HeaderMap,
+    chunks: BoxStream<'static, Result<Bytes, util::Error>>,
+}
+impl Body {
+    /// Body made of `chunks`.
+    pub fn new(
+        partial: bool,
+        encoding: Option<String>,
+        chunks: BoxStream<'static, Result<Bytes, util::Error>>,
+    ) -> Self {
+        Body {
+            partial,
+            encoding,
+            headers: HeaderMap::new(),
+            chunks,
+        }
+    }
+
+    /// Wait for the next chunk, or `None` at the end of the body.
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>, util::Error> {
+        self.chunks.next().await.transpose()
+    }
+}
+impl std::fmt::Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Body")
+            .field("partial", &self.partial)
+            .field("encoding", &self.encoding)
+            .finish()
+    }
+}
+
+/// Something files can be downloaded over, such as HTTP.  Example:
+///
+/// ```ignore
+/// let fetcher = options.fetcher(&url)?;
+/// let metadata = fetcher.probe(&url, &HeaderMap::new()).await?;
+/// let mut body = fetcher.fetch(&url, &HeaderMap::new(), None).await?;
+/// while let Some(chunk) = body.chunk().await? {
+///     writer.write(chunk).await?;
+/// }
+/// ```
+pub trait Fetcher: std::fmt::Debug + Send + Sync {
+    /// Find out about the file at `url`.  `headers` are the headers of the
+    /// request, including any conditional ones; transports without headers
+    /// make of them what they can.
+    fn probe<'a>(
+        &'a self,
+        url: &'a Url,
+        headers: &'a HeaderMap,
+    ) -> BoxFuture<'a, Result<Metadata, util::Error>>;
+
+    /// Start fetching `range` of the file at `url`, or all of it.  A fetcher
+    /// may send the whole file instead of the range, and says so in the
+    /// body.
+    fn fetch<'a>(
+        &'a self,
+        url: &'a Url,
+        headers: &'a HeaderMap,
+        range: Option<Range>,
+    ) -> BoxFuture<'a, Result<Body, util::Error>>;
+}
+
+/// Fetches `http:` and `https:` URLs with a `reqwest::Client`.
+#[derive(Clone, Debug)]
+pub struct Http {
+    client: reqwest::Client,
+    timeouts: Timeouts,
+    credentials: Option<Arc<Credentials>>,
+    cookies: Option<Arc<CookieJar>>,
+}
+impl Http {
+    /// Fetch with `client`, waiting for responses as long as `timeouts`
+    /// allow.
+    pub fn new(client: reqwest::Client, timeouts: Timeouts) -> Self {
+        Http {
+            client,
+            timeouts,
+            credentials: None,
+            cookies: None,
+        }
+    }
+
+    /// Authenticate to servers we have `credentials` for.
+    pub fn with_credentials(mut self, credentials: Arc<Credentials>) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Keep cookies in `cookies`, and send them back.  The client must be
+    /// set up with `CookieJar::apply()` to keep the cookies set on redirects.
+    pub fn with_cookies(mut self, cookies: Arc<CookieJar>) -> Self {
+        self.cookies = Some(cookies);
+        self
+    }
+
+    /// Send the request that `request` makes for `url` with `headers`, and
+    /// follow the redirects the client hands back, so the cookie jar keeps
+    /// the cookies set along the way.  `headers` don't take an
+    /// `Authorization` header of theirs to another origin.  Unsuccessful
+    /// responses other than 304 Not Modified are errors.
+    async fn send<F>(&self, url: &Url, headers: &HeaderMap, request: F) -> Result<reqwest::Response, util::Error>
+    where
+        F: Fn(&Url) -> reqwest::RequestBuilder + Sync,
+    {
+        let mut url = url.clone();
+        let mut headers = headers.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let (resp, authenticated) = self.send_authorized(&url, &headers, &request).await?;
+            if let Some(cookies) = &self.cookies {
+                cookies.store(&url, resp.headers());
+            }
+
+            let status = resp.status();
+            let location = header_str(resp.headers(), header::LOCATION).and_then(|location| url.join(location).ok());
+            match location {
+                Some(next) if status.is_redirection() && status != StatusCode::NOT_MODIFIED => {
+                    check_redirect(&url, &next).map_err(|reason| redirect_error(&url, &reason))?;
+                    if next.origin() != url.origin() {
+                        headers.remove(header::AUTHORIZATION);
+                    }
+                    url = next;
+                }
+                _ if !status.is_success() && status != StatusCode::NOT_MODIFIED => {
+                    return Err(status_error(&url, status, authenticated));
+                }
+                _ => return Ok(resp),
+            }
+        }
+        Err(redirect_error(&url, "too many redirects"))
+    }
+
+    /// Send the request that `request` makes for `url` with `headers`,
+    /// adding our credentials for `url` unless `headers` has its own, or
+    /// signing it for an object store we have AWS keys for.  If the server
+    /// refuses our credentials and a credential helper has new ones, the
+    /// request is sent again with those.  Also says whether the request was
+    /// authenticated.
+    async fn send_authorized<F>(
+        &self,
+        url: &Url,
+        headers: &HeaderMap,
+        request: &F,
+    ) -> Result<(reqwest::Response, bool), util::Error>
+    where
+        F: Fn(&Url) -> reqwest::RequestBuilder + Sync,
+    {
+        let credentials = match &self.credentials {
+            Some(credentials) if !headers.contains_key(header::AUTHORIZATION) => Some(credentials),
+            _ => None,
+        };
+        let signer = credentials.and_then(|credentials| credentials.signer(url));
+        let mut credential = match credentials {
+            Some(credentials) if signer.is_none() => credentials.lookup(url).await?,
+            _ => None,
+        };
+        let mut resp = self.send_as(request(url), url, headers, credential.as_ref(), signer).await?;
+
+        if let (StatusCode::UNAUTHORIZED, Some(credentials)) = (resp.status(), credentials) {
+            let challenges: Vec<String> = resp
+                .headers()
+                .get_all(header::WWW_AUTHENTICATE)
+                .iter()
+                .filter_map(|challenge| challenge.to_str().ok())
+                .map(str::to_string)
+                .collect();
+            if let Some(fresh) = credentials.refresh(url, credential.as_ref(), &challenges).await? {
+                resp = self.send_as(request(url), url, headers, Some(&fresh), None).await?;
+                credential = Some(fresh);
+            }
+        }
+
+        let authenticated = credential.is_some() || signer.is_some() || headers.contains_key(header::AUTHORIZATION);
+        Ok((resp, authenticated))
+    }
+
+    /// Send `request` for `url` with `headers` and the cookies in our jar
+    /// for `url`, authenticated with `credential` or signed by `signer`.
+    async fn send_as(
+        &self,
+        request: reqwest::RequestBuilder,
+        url: &Url,
+        headers: &HeaderMap,
+        credential: Option<&Credential>,
+        signer: Option<&SigV4>,
+    ) -> Result<reqwest::Response, util::Error> {
+        let mut request = request.headers(headers.clone());
+        if let Some(cookie) = self.cookies.as_ref().and_then(|cookies| cookies.header(url)) {
+            if !headers.contains_key(header::COOKIE) {
+                request = request.header(header::COOKIE, cookie);
+            }
+        }
+        if let Some(credential) = credential {
+            request = request.header(header::AUTHORIZATION, credential.header_value()?);
+        }
+        if let Some(signer) = signer {
+            request = signer.sign_request(request)?;
+        }
+        self.timeouts.send(request).await
+    }
+}
+impl Fetcher for Http {
+    fn probe<'a>(
+        &'a self,
+        url: &'a Url,
+        headers: &'a HeaderMap,
+    ) -> BoxFuture<'a, Result<Metadata, util::Error>> {
+        Box::pin(async move {
+            let resp = self.send(url, headers, |url| self.client.head(url.as_str())).await?;
+            let not_modified = resp.status() == StatusCode::NOT_MODIFIED;
+            Ok(Metadata::from_headers(resp.headers(), not_modified))
+        })
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        url: &'a Url,
+        headers: &'a HeaderMap,
+        range: Option<Range>,
+    ) -> BoxFuture<'a, Result<Body, util::Error>> {
+        Box::pin(async move {
+            let range = range.map(|range| match range.end {
+                Some(end) => format!("bytes={}-{}", range.start, end.saturating_sub(1)),
+                None => format!("bytes={}-", range.start),
+            });
+            let get = |url: &Url| match &range {
+                Some(range) => self.client.get(url.as_str()).header(header::RANGE, range.as_str()),
+                None => self.client.get(url.as_str()),
+            };
+            let resp = self.send(url, headers, get).await?;
+
+            // A server that ignores the range header sends the whole file.
+            let partial = match resp.status() {
+                StatusCode::PARTIAL_CONTENT => true,
+                status if status.is_success() => false,
+                status => return Err(status_error(url, status, false)),
+            };
+            let encoding = content_encoding(resp.headers());
+            let headers = resp.headers().clone();
+            let chunks = resp.bytes_stream().map_err(request_error).boxed();
+            Ok(Body {
+                headers,
+                ..Body::new(partial, encoding, chunks)
+            })
+        })
+    }
+}
+
+/// Fetches `file:` URLs from the local file system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct File;
+impl Fetcher for File {
+    fn probe<'a>(
+        &'a self,
+        url: &'a Url,
+        headers: &'a HeaderMap,
+    ) -> BoxFuture<'a, Result<Metadata, util::Error>> {
+        Box::pin(async move {
+            let path = file_path(url)?;
+            let meta = rt::fs::metadata(&path).await.map_err(|e| read_error(url, e))?;
+            if !meta.is_file() {
+                return Err(util::Error {
+                    what: format!("{} is not a file.", path.display()),
+                    source: None,
+                    kind: util::ErrorKind::Other,
+                });
+            }
+
+            // Describe the file the way a server would, and answer a
+            // conditional request by its modification time.
+            let mut described = HeaderMap::new();
+            described.insert(header::CONTENT_LENGTH, HeaderValue::from(meta.len()));
+            described.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            let modified = meta.modified().ok().map(httpdate::fmt_http_date);
+            if let Some(value) = modified.as_ref().and_then(|date| date.parse().ok()) {
+                described.insert(header::LAST_MODIFIED, value);
+            }
+            let since = header_str(headers, header::IF_MODIFIED_SINCE);
+            let not_modified = match (&modified, since) {
+                (Some(modified), Some(since)) => {
+                    match (httpdate::parse_http_date(modified), httpdate::parse_http_date(since)) {
+                        (Ok(modified), Ok(since)) => modified <= since,
+                        _ => false,
+                    }
+                }
+                _ => false,
+            };
+            Ok(Metadata::from_headers(&described, not_modified))
+        })
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        url: &'a Url,
+        _headers: &'a HeaderMap,
+        range: Option<Range>,
+    ) -> BoxFuture<'a, Result<Body, util::Error>> {
+        Box::pin(async move {
+            use futures::io::AsyncReadExt;
+            let path = file_path(url)?;
+            let mut file = rt::File::open(&path).await.map_err(|e| read_error(url, e))?;
+            let start = range.map_or(0, |range| range.start);
+            file.seek(SeekFrom::Start(start)).await.map_err(|e| read_error(url, e))?;
+            let remaining = range.and_then(|range| range.end).map(|end| end.saturating_sub(start));
+
+            // Read the file a chunk at a time, stopping at the end of the
+            // range.
+            let state = (file, remaining, url.clone());
+            let chunks = stream::try_unfold(state, |(mut file, remaining, url)| async move {
+                let want = match remaining {
+                    Some(remaining) => remaining.min(CHUNK_SIZE as u64) as usize,
+                    None => CHUNK_SIZE,
+                };
+                if want == 0 {
+                    return Ok(None);
+                }
+                let mut buf = vec![0u8; want];
+                let n = file.read(&mut buf).await.map_err(|e| read_error(&url, e))?;
+                if n == 0 {
+                    return Ok(None);
+                }
+                buf.truncate(n);
+                let remaining = remaining.map(|remaining| remaining - n as u64);
+                Ok(Some((Bytes::from(buf), (file, remaining, url))))
+            });
+            Ok(Body::new(range.is_some(), None, chunks.boxed()))
+        })
+    }
+}
+
+/// Serves `data:` URLs, which carry their content in the URL itself.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Data;
+impl Fetcher for Data {
+    fn probe<'a>(
+        &'a self,
+        url: &'a Url,
+        _headers: &'a HeaderMap,
+    ) -> BoxFuture<'a, Result<Metadata, util::Error>> {
+        Box::pin(async move {
+            let (media_type, content) = parse_data(url)?;
+            let mut described = HeaderMap::new();
+            described.insert(header::CONTENT_LENGTH, HeaderValue::from(content.len()));
+            described.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            if let Ok(value) = HeaderValue::from_str(&media_type) {
+                described.insert(header::CONTENT_TYPE, value);
+            }
+            Ok(Metadata::from_headers(&described, false))
+        })
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        url: &'a Url,
+        _headers: &'a HeaderMap,
+        range: Option<Range>,
+    ) -> BoxFuture<'a, Result<Body, util::Error>> {
+        Box::pin(async move {
+            let (_, content) = parse_data(url)?;
+            Ok(memory_body(content, range))
+        })
+    }
+}
+
+/// Serves files from memory, for testing downloads without a server.
+/// Register it under a scheme of its own, or in place of `http` and `https`.
+/// Each file gets an `ETag` derived from its content.  A URL can be made to
+/// misbehave like a real server: to cut every body short, or to send the
+/// whole file whatever range is asked for.  Example:
+///
+/// ```ignore
+/// let mock = Arc::new(Mock::new());
+/// mock.insert(Url::parse("mock://host/file.bin")?, vec![0u8; 1024]);
+/// options.fetchers.insert("mock".to_string(), mock.clone());
+/// download::download(&request, &options, &progress).await?;
+/// assert_eq!(mock.fetches().len(), 1);
+/// ```
+#[derive(Debug, Default)]
+pub struct Mock {
+    files: Mutex<HashMap<Url, Bytes>>,
+    fetches: Mutex<Vec<(Url, Option<Range>)>>,
+    /// Bodies of these URLs fail after this many bytes.
+    fail_after: Mutex<HashMap<Url, u64>>,
+    /// These URLs send the whole file when asked for a range.
+    whole: Mutex<Vec<Url>>,
+    /// Bodies of these URLs stop for a while after this many bytes.
+    pauses: Mutex<HashMap<Url, (u64, Duration)>>,
+    /// Bodies of these URLs have the byte at this offset flipped.
+    corrupt: Mutex<HashMap<Url, u64>>,
+    /// Extra headers describing these URLs.
+    headers: Mutex<HashMap<Url, HeaderMap>>,
+}
+impl Mock {
+    /// Mock without any files.
+    pub fn new() -> Self {
+        Mock::default()
+    }
+
+    /// Serve `content` at `url`, replacing whatever was there.
+    pub fn insert(&self, url: Url, content: impl Into<Bytes>) {
+        self.files.lock().unwrap().insert(url, content.into());
+    }
+
+    /// Stop serving `url`, as if the server lost the file.
+    pub fn remove(&self, url: &Url) {
+        self.files.lock().unwrap().remove(url);
+    }
+
+    /// Fail every body fetched from `url` after `bytes` bytes, as if the
+    /// connection was reset.
+    pub fn fail_after(&self, url: &Url, bytes: u64) {
+        self.fail_after.lock().unwrap().insert(url.clone(), bytes);
+    }
+
+    /// Pause every body fetched from `url` for `duration` after `bytes`
+    /// bytes, or at the first chunk boundary after that, as if the server
+    /// stalled.
+    pub fn pause(&self, url: &Url, bytes: u64, duration: Duration) {
+        self.pauses.lock().unwrap().insert(url.clone(), (bytes, duration));
+    }
+
+    /// Describe the file at `url` with `headers` too, such as
+    /// `Cache-Control`, as if the server sent them with every response.
+    pub fn respond_with(&self, url: &Url, headers: HeaderMap) {
+        self.headers.lock().unwrap().insert(url.clone(), headers);
+    }
+
+    /// Flip the byte at `offset` of the file in every body fetched from
+    /// `url` that includes it, as if it was damaged on the way.  Probes still
+    /// describe the undamaged file.
+    pub fn corrupt(&self, url: &Url, offset: u64) {
+        self.corrupt.lock().unwrap().insert(url.clone(), offset);
+    }
+
+    /// Send the whole file at `url` when asked for a range, like a server
+    /// that ignores the `Range` header.
+    pub fn ignore_ranges(&self, url: &Url) {
+        self.whole.lock().unwrap().push(url.clone());
+    }
+
+    /// The URL and range of every fetch so far, in order.
+    pub fn fetches(&self) -> Vec<(Url, Option<Range>)> {
+        self.fetches.lock().unwrap().clone()
+    }
+
+    /// Content at `url`.
+    fn get(&self, url: &Url) -> Result<Bytes, util::Error> {
+        self.files.lock().unwrap().get(url).cloned().ok_or_else(|| util::Error {
+            what: format!("{} not found.", url),
+            source: None,
+            kind: util::ErrorKind::Other,
+        })
+    }
+}
+impl Fetcher for Mock {
+    fn probe<'a>(
+        &'a self,
+        url: &'a Url,
+        headers: &'a HeaderMap,
+    ) -> BoxFuture<'a, Result<Metadata, util::Error>> {
+        Box::pin(async move {
+            let content = self.get(url)?;
+            let etag = format!("\"{}\"", hex::encode(&Sha256::digest(&content)[..8]));
+            let mut described = HeaderMap::new();
+            described.insert(header::CONTENT_LENGTH, HeaderValue::from(content.len()));
+            described.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            if let Ok(value) = HeaderValue::from_str(&etag) {
+                described.insert(header::ETAG, value);
+            }
+            if let Some(headers) = self.headers.lock().unwrap().get(url) {
+                described.extend(headers.clone());
+            }
+            let not_modified = header_str(headers, header::IF_NONE_MATCH) == Some(etag.as_str());
+            Ok(Metadata::from_headers(&described, not_modified))
+        })
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        url: &'a Url,
+        _headers: &'a HeaderMap,
+        range: Option<Range>,
+    ) -> BoxFuture<'a, Result<Body, util::Error>> {
+        Box::pin(async move {
+            self.fetches.lock().unwrap().push((url.clone(), range));
+            let content = self.get(url)?;
+            let range = range.filter(|_| !self.whole.lock().unwrap().contains(url));
+            let limit = self.fail_after.lock().unwrap().get(url).copied();
+            let mut sent = slice(&content, range);
+            if let Some(&offset) = self.corrupt.lock().unwrap().get(url) {
+                let start = range.map_or(0, |range| range.start);
+                if offset >= start && offset - start < sent.len() as u64 {
+                    let mut damaged = sent.to_vec();
+                    damaged[(offset - start) as usize] ^= 0xff;
+                    sent = damaged.into();
+                }
+            }
+            let cut = limit.filter(|&limit| limit < sent.len() as u64);
+            if let Some(limit) = cut {
+                sent.truncate(limit as usize);
+            }
+            let mut chunks = chunked(&sent);
+            if cut.is_some() {
+                chunks.push(Err(util::Error {
+                    what: format!("Connection to {} was reset.", url),
+                    source: None,
+                    kind: util::ErrorKind::Network,
+                }));
+            }
+            let chunks = match self.pauses.lock().unwrap().get(url).copied() {
+                Some((bytes, duration)) => {
+                    let mut sent = 0;
+                    let at = chunks
+                        .iter()
+                        .position(|chunk| {
+                            let start = sent;
+                            sent += chunk.as_ref().map_or(0, |chunk| chunk.len() as u64);
+                            start >= bytes
+                        })
+                        .unwrap_or(chunks.len());
+                    let rest = chunks.split_off(at);
+                    let pause = stream::once(rt::sleep(duration)).filter_map(|()| future::ready(None));
+                    stream::iter(chunks).chain(pause).chain(stream::iter(rest)).boxed()
+                }
+                None => stream::iter(chunks).boxed(),
+            };
+            Ok(Body::new(range.is_some(), None, chunks))
+        })
+    }
+}
+
+/// The built-in fetcher for URLs with `scheme`, if there is one.  HTTP uses
+/// the client, timeouts, credentials and cookie jar of `options`.
+pub(crate) fn builtin(scheme: &str, options: &Options) -> Option<Arc<dyn Fetcher>> {
+    match scheme {
+        "http" | "https" => {
+            let mut http = Http::new(options.client.clone(), options.timeouts);
+            if let Some(credentials) = &options.credentials {
+                http = http.with_credentials(credentials.clone());
+            }
+            if let Some(cookies) = &options.cookies {
+                http = http.with_cookies(cookies.clone());
+            }
+            Some(Arc::new(http))
+        }
+        "file" => Some(Arc::new(File)),
+        "data" => Some(Arc::new(Data)),
+        _ => None,
+    }
+}
+
+/// Body serving `range` of `content`, or all of it, in chunks.
+fn memory_body(content: Bytes, range: Option<Range>) -> Body {
+    let chunks = chunked(&slice(&content, range));
+    Body::new(range.is_some(), None, stream::iter(chunks).boxed())
+}
+
+/// `range` of `content`, or all of it.  A range past the end is empty.
+fn slice(content: &Bytes, range: Option<Range>) -> Bytes {
+    let len = content.len() as u64;
+    let start = range.map_or(0, |range| range.start).min(len);
+    let end = range.and_then(|range| range.end).unwrap_or(len).clamp(start, len);
+    content.slice(start as usize..end as usize)
+}
+
+/// `content` split into chunks to serve.
+fn chunked(content: &Bytes) -> Vec<Result<Bytes, util::Error>> {
+    (0..content.len())
+        .step_by(CHUNK_SIZE)
+        .map(|i| Ok(content.slice(i..(i + CHUNK_SIZE).min(content.len()))))
+        .collect()
+}
+
+/// Media type and content of the `data:` URL `url`, e.g.
+/// `data:text/plain;base64,SGVsbG8=`.
+fn parse_data(url: &Url) -> Result<(String, Bytes), util::Error> {
+    let invalid = |source: Option<util::BoxError>| util::Error {
+        what: format!("Invalid data: URL {}.", url),
+        source,
+        kind: util::ErrorKind::Other,
+    };
+    // Everything after the scheme, without the fragment.
+    let rest = &url[url::Position::BeforePath..url::Position::AfterQuery];
+    let (meta, data) = rest.split_once(',').ok_or_else(|| invalid(None))?;
+    let meta = percent_encoding::percent_decode_str(meta).decode_utf8_lossy();
+    let data: Vec<u8> = percent_encoding::percent_decode_str(data).collect();
+
+    let (media_type, base64) = match meta.len().checked_sub(";base64".len()) {
+        Some(at) if meta[at..].eq_ignore_ascii_case(";base64") => (&meta[..at], true),
+        _ => (&meta[..], false),
+    };
+    let content = if base64 {
+        let data: Vec<u8> = data.into_iter().filter(|b| !b.is_ascii_whitespace()).collect();
+        base64::decode(&data).map_err(|e| invalid(Some(e.into())))?
+    } else {
+        data
+    };
+    let media_type = match media_type {
+        "" => "text/plain;charset=US-ASCII".to_string(),
+        _ => media_type.to_string(),
+    };
+    Ok((media_type, Bytes::from(content)))
+}
+
+/// Path of the `file:` URL `url`.
+fn file_path(url: &Url) -> Result<PathBuf, util::Error> {
+    url.to_file_path().map_err(|()| util::Error {
+        what: format!("{} is not a local file path.", url),
+        source: None,
+        kind: util::ErrorKind::Other,
+    })
+}
+
+/// Value of header `name` as a string, if present and valid.
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// `Content-Encoding` of a response, unless it is `identity`.
+fn content_encoding(headers: &HeaderMap) -> Option<String> {
+    header_str(headers, header::CONTENT_ENCODING)
+        .filter(|encoding| !encoding.eq_ignore_ascii_case("identity"))
+        .map(String::from)
+}
+
+/// Error for a server that answered with an unsuccessful `status`.  Refusing
+/// access is an auth error, whether or not we were `authenticated`, and so is
+/// a proxy that refuses access.
+fn status_error(url: &Url, status: StatusCode, authenticated: bool) -> util::Error {
+    let host = url.host_str().unwrap_or_default();
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN if authenticated => util::Error {
+            what: format!("The credentials for {} were refused for {}. Error: {}", host, url, status),
+            source: None,
+            kind: util::ErrorKind::Auth,
+        },
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => util::Error {
+            what: format!("{} needs credentials for {}, but we have none. Error: {}", url, host, status),
+            source: None,
+            kind: util::ErrorKind::Auth,
+        },
+        StatusCode::PROXY_AUTHENTICATION_REQUIRED => util::Error {
+            what: format!("The proxy for {} refused access to {}, check its credentials. Error: {}", host, url, status),
+            source: None,
+            kind: util::ErrorKind::Auth,
+        },
+        _ => util::Error {
+            what: format!("Couldn't download URL: {}. Error: {}", url, status),
+            source: None,
+            kind: util::ErrorKind::Other,
+        },
+    }
+}
+
+/// Error for a redirect from `url` that we won't follow, for `reason`.
+fn redirect_error(url: &Url, reason: &str) -> util::Error {
+    util::Error {
+        what: format!("Couldn't follow the redirect from {}: {}.", url, reason),
+        source: None,
+        kind: util::ErrorKind::Other,
+    }
+}
+
+/// Error for a local file at `url` that couldn't be read.
+fn read_error(url: &Url, error: std::io::Error) -> util::Error {
+    util::Error {
+        what: format!("Couldn't read {}.", url),
+        source: Some(error.into()),
+        kind: util::ErrorKind::Other,
+    }
+}
@@ -0,0 +1,314 @@
+//! Persistent record of a batch of downloads, so an interrupted batch can be
+//! run again without starting over.
+//!
+//! The journal is a JSON file mapping each output file to what is known about
+//! its download.  Example:
+//!
+//! ```json
+//! { "downloads": {
+//!     "audio/example.wav": {
+//!         "url": "https://example.com/file_example_WAV_10MG.wav",
+//!         "status": "partial",
+//!         "etag": "\"5a1b-5e7b3c\"",
+//!         "last_modified": "Wed, 21 Oct 2015 07:28:00 GMT",
+//!         "size": 10485760,
+//!         "offset": 4194304
+//!     }
+//! } }
+//! ```
+//!
+//! Running the batch again skips files that are `complete`, still on disk, and
+//! unchanged on the server, resumes `partial` and `failed` downloads from
+//! their offset if the server still has the same file, and starts everything
+//! else from scratch.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use futures::lock::Mutex;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::rt;
+use crate::writer::write_atomic;
+
+/// Offsets are saved at most this often, so large downloads don't spend
+/// their time rewriting the journal.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far a download got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Started but not finished, e.g. because the downloader crashed.
+    Partial,
+    /// Finished and verified.
+    Complete,
+    /// Gave up with an error.
+    Failed,
+}
+
+/// What the journal knows about one download.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// Main URL of the download.  A record for a different URL doesn't
+    /// count.
+    pub url: Url,
+    /// How far the download got.
+    pub status: Status,
+    /// `ETag` of the file when the download started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// `Last-Modified` date of the file when the download started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    /// Size of the whole file, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Number of bytes at the start of the output file that were downloaded.
+    /// Some of them may not have reached the disk before a crash.
+    pub offset: u64,
+    /// Why the download failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Contents of the journal file.
+#[derive(Default, Serialize, Deserialize)]
+struct Contents {
+    downloads: BTreeMap<PathBuf, Record>,
+}
+
+/// In-memory state of the journal.
+struct State {
+    contents: Contents,
+    /// When the journal file was last written.
+    saved: Instant,
+}
+
+/// Journal of a batch of downloads, saved to a JSON file as the downloads
+/// progress.  Shared by every download in the batch.  Example:
+///
+/// ```ignore
+/// let options = Options {
+///     journal: Some(Arc::new(Journal::open(".download-journal.json").await?)),
+///     ..Options::new(client)
+/// };
+/// ```
+pub struct Journal {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+impl Journal {
+    /// Open the journal at `path`, or start an empty one if it doesn't exist
+    /// yet.
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self, util::Error> {
+        let path = path.into();
+        let contents = match rt::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| util::Error {
+                what: format!(
+                    "Invalid journal {} at line {}, column {}.",
+                    path.display(),
+                    e.line(),
+                    e.column()
+                ),
+                source: Some(e.into()),
+                kind: util::ErrorKind::Other,
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Contents::default(),
+            Err(e) => return Err(journal_error(&path, e)),
+        };
+        Ok(Journal {
+            path,
+            state: Mutex::new(State {
+                contents,
+                saved: Instant::now(),
+            }),
+        })
+    }
+
+    /// Path of the journal file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// What the journal knows about the download into `output`.
+    pub async fn get(&self, output: &Path) -> Option<Record> {
+        self.state
+            .lock()
+            .await
+            .contents
+            .downloads
+            .get(output)
+            .cloned()
+    }
+
+    /// Record what is known about the download into `output` and save the
+    /// journal.
+    pub async fn record(&self, output: &Path, record: Record) -> Result<(), util::Error> {
+        let mut state = self.state.lock().await;
+        state
+            .contents
+            .downloads
+            .insert(output.to_path_buf(), record);
+        self.save(&mut state).await
+    }
+
+    /// Record that the download into `output` got to `offset`.  The journal
+    /// is saved at most once a second.
+    pub async fn checkpoint(&self, output: &Path, offset: u64) -> Result<(), util::Error> {
+        let mut state = self.state.lock().await;
+        match state.contents.downloads.get_mut(output) {
+            Some(record) => record.offset = offset,
+            None => return Ok(()),
+        }
+        if state.saved.elapsed() < CHECKPOINT_INTERVAL {
+            return Ok(());
+        }
+        self.save(&mut state).await
+    }
+
+    /// Record that the download into `output` failed with `error`.  After an
+    /// integrity error the download must start over.
+    pub async fn fail(&self, output: &Path, error: &util::Error) -> Result<(), util::Error> {
+        let mut state = self.state.lock().await;
+        match state.contents.downloads.get_mut(output) {
+            Some(record) => {
+                record.status = Status::Failed;
+                record.error = Some(error.what.clone());
+                // Bytes that failed verification can't be trusted.
+                if error.kind == util::ErrorKind::Integrity {
+                    record.offset = 0;
+                }
+            }
+            None => return Ok(()),
+        }
+        self.save(&mut state).await
+    }
+
+    /// Write the journal, replacing the old one in a single step.
+    async fn save(&self, state: &mut State) -> Result<(), util::Error> {
+        let json =
+            serde_json::to_vec_pretty(&state.contents).map_err(|e| journal_error(&self.path, e))?;
+        write_atomic(&self.path, &json)
+            .await
+            .map_err(|e| journal_error(&self.path, e))?;
+        state.saved = Instant::now();
+        Ok(())
+    }
+}
+impl std::fmt::Debug for Journal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Journal").field("path", &self.path).finish()
+    }
+}
+
+/// Error for a problem reading or writing the journal at `path`.
+fn journal_error(path: &Path, error: impl Into<util::BoxError>) -> util::Error {
+    util::Error {
+        what: format!("Couldn't update journal {}.", path.display()),
+        source: Some(error.into()),
+        kind: util::ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scratch journal path for the test called `name`, without a file.
+    fn scratch(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "download-journal-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// Partial download of `a.bin` that got to `offset`.
+    fn record(offset: u64) -> Record {
+        Record {
+            url: Url::parse("https://example.com/a.bin").unwrap(),
+            status: Status::Partial,
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+            size: Some(1000),
+            offset,
+            error: None,
+        }
+    }
+
+    /// What a fresh look at the journal file says about `a.bin`.
+    async fn reread(path: &Path) -> Option<Record> {
+        Journal::open(path).await.unwrap().get(Path::new("a.bin")).await
+    }
+
+    #[test]
+    fn saves_and_loads_records() {
+        let path = scratch("round-trip");
+        rt::block_on(async {
+            let journal = Journal::open(&path).await.unwrap();
+            assert_eq!(journal.get(Path::new("a.bin")).await, None);
+            journal.record(Path::new("a.bin"), record(100)).await.unwrap();
+            assert_eq!(reread(&path).await, Some(record(100)));
+
+            // Bytes that failed verification are forgotten.
+            let error = util::Error {
+                what: "Checksum mismatch.".to_string(),
+                source: None,
+                kind: util::ErrorKind::Integrity,
+            };
+            journal.fail(Path::new("a.bin"), &error).await.unwrap();
+            let failed = Record {
+                status: Status::Failed,
+                offset: 0,
+                error: Some("Checksum mismatch.".to_string()),
+                ..record(100)
+            };
+            assert_eq!(reread(&path).await, Some(failed));
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn saves_checkpoints_at_most_once_an_interval() {
+        let path = scratch("checkpoints");
+        rt::block_on(async {
+            let journal = Journal::open(&path).await.unwrap();
+            journal.record(Path::new("a.bin"), record(0)).await.unwrap();
+
+            // Right after a save, the checkpoint is only kept in memory.
+            journal.checkpoint(Path::new("a.bin"), 500).await.unwrap();
+            assert_eq!(journal.get(Path::new("a.bin")).await, Some(record(500)));
+            assert_eq!(reread(&path).await, Some(record(0)));
+
+            // An interval later it is saved.
+            {
+                let mut state = journal.state.lock().await;
+                state.saved = Instant::now().checked_sub(CHECKPOINT_INTERVAL).unwrap();
+            }
+            journal.checkpoint(Path::new("a.bin"), 900).await.unwrap();
+            assert_eq!(reread(&path).await, Some(record(900)));
+
+            // Checkpoints of downloads the journal doesn't know are ignored.
+            journal.checkpoint(Path::new("b.bin"), 900).await.unwrap();
+            assert_eq!(journal.get(Path::new("b.bin")).await, None);
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_corrupt_journal() {
+        let path = scratch("corrupt");
+        std::fs::write(&path, "{ \"downloads\": {\n  \"a.bin\": 42").unwrap();
+        let error = rt::block_on(Journal::open(&path)).unwrap().unwrap_err();
+        let message = format!("Invalid journal {} at line 2, column", path.display());
+        assert!(error.what.starts_with(&message), "{}", error.what);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
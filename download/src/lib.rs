@@ -0,0 +1,102 @@
+//! Download machinery shared by the downloader examples.
+
+pub mod auth;
+pub mod aws;
+
+mod batch;
+/// Download a batch of `Request`s a few at a time.
+pub use batch::download_batch;
+/// Like `download_batch()`, into a `Sink` for each request.
+pub use batch::download_batch_to;
+
+mod blocking;
+/// Download a `Request` from synchronous code, blocking until it is done.
+pub use blocking::download_blocking;
+/// Like `download_blocking()`, with options and progress.
+pub use blocking::download_blocking_with;
+/// Download a batch of `Request`s from synchronous code.
+pub use blocking::download_batch_blocking;
+
+mod checksum;
+/// Hash algorithms supported for checksums.
+pub use checksum::Algorithm;
+/// Expected digest of a file.
+pub use checksum::Checksum;
+/// Incremental hash of a stream of bytes.
+pub use checksum::Hasher;
+/// Expected digests of the pieces of a file.
+pub use checksum::Pieces;
+
+pub mod cache;
+pub mod cookies;
+pub mod extract;
+pub mod fetch;
+
+mod decode;
+/// How to handle compressed responses.
+pub use decode::Compression;
+/// Decodes a compressed response body one chunk at a time.
+pub use decode::Decoder;
+
+pub mod journal;
+pub mod manifest;
+pub mod metalink;
+pub mod oauth2;
+pub mod proxy;
+pub mod sink;
+pub mod store;
+
+mod read;
+/// Convert a `reqwest::Response` into a `tokio::io::AsyncRead`.
+#[cfg(feature = "rt-tokio")]
+pub use read::into_async_read;
+/// Something that can be told how many bytes have been transferred.
+pub use read::Progress;
+/// `AsyncRead` wrapper that reports bytes read.
+pub use read::ProgressReader;
+
+mod request;
+/// Description of a single file to download.
+pub use request::Request;
+/// Name of the output file for a URL.
+pub use request::output_for;
+
+pub mod rt;
+
+mod stall;
+/// Timeouts for connecting, waiting for the first byte, and reading the body.
+pub use stall::Timeouts;
+/// Minimum acceptable download speed over a window of time.
+pub use stall::MinSpeed;
+/// Enforces the idle timeout and minimum speed while reading a body.
+pub use stall::Watchdog;
+
+mod task;
+/// Download a `Request`, failing over between mirrors.
+pub use task::download;
+/// Download a `Request` into a `Sink` instead of a file.
+pub use task::download_to;
+/// Settings shared by every download in a batch.
+pub use task::Options;
+/// What a successful download did.
+pub use task::Outcome;
+/// How often to try the same mirror again.
+pub use task::Retries;
+
+pub mod tls;
+
+mod throttle;
+/// Shared async token bucket for limiting bandwidth.
+pub use throttle::TokenBucket;
+/// Parse a human-readable rate such as `2M` into bytes per second.
+pub use throttle::parse_rate;
+/// Parse a human-readable size such as `1G` into bytes.
+pub use throttle::parse_size;
+
+mod writer;
+/// Buffered writer stage that decouples the network from the disk.
+pub use writer::Writer;
+/// Blocking reader of the chunks queued for a `Writer`.
+pub use writer::ChunkReader;
+/// Bytes written and time taken by a `Writer`.
+pub use writer::WriteStats;
@@ -0,0 +1,245 @@
+//! Manifests describing a batch of downloads in TOML or JSON.
+//!
+//! A manifest lists one or more downloads.  Only `url` is required.  In TOML:
+//!
+//! ```toml
+//! [[download]]
+//! url = "https://example.com/file_example_WAV_10MG.wav"
+//! output = "audio/example.wav"
+//! size = 10485760
+//! checksum = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+//! mirrors = ["https://mirror.example.com/file_example_WAV_10MG.wav"]
+//! priority = 10
+//!
+//! [download.headers]
+//! Accept = "audio/wav"
+//! ```
+//!
+//! The same manifest in JSON:
+//!
+//! ```json
+//! { "download": [ {
+//!     "url": "https://example.com/file_example_WAV_10MG.wav",
+//!     "output": "audio/example.wav",
+//!     "size": 10485760,
+//!     "checksum": "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+//!     "mirrors": ["https://mirror.example.com/file_example_WAV_10MG.wav"],
+//!     "priority": 10,
+//!     "headers": { "Accept": "audio/wav" }
+//! } ] }
+//! ```
+//!
+//! An archive may also give an `extract` directory to extract it into, such
+//! as `extract = "data"` for a `.tar.gz`, `.tar.zst`, or `.zip` file.
+//!
+//! Outputs and extract directories are relative paths that stay inside the
+//! current directory, so a manifest can't write anywhere else.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::request::safe_path;
+use crate::{rt, Checksum, Request};
+
+/// File formats a manifest may be written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// [TOML](https://toml.io)
+    Toml,
+    /// [JSON](https://json.org)
+    Json,
+}
+impl Format {
+    /// Guess the format from the extension of `path`.
+    pub fn from_path(path: &Path) -> Result<Self, util::Error> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => Ok(Format::Toml),
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Ok(Format::Json),
+            _ => Err(util::Error {
+                what: format!(
+                    "Can't tell the format of manifest {}, expected a .toml or .json extension.",
+                    path.display()
+                ),
+                source: None,
+                kind: util::ErrorKind::Other,
+            }),
+        }
+    }
+}
+
+/// Top level of a manifest file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    download: Vec<Entry>,
+}
+
+/// One download in a manifest file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    url: Url,
+    #[serde(default)]
+    mirrors: Vec<Url>,
+    output: Option<PathBuf>,
+    size: Option<u64>,
+    checksum: Option<Checksum>,
+    #[serde(default, deserialize_with = "deserialize_headers")]
+    headers: HeaderMap,
+    #[serde(default)]
+    priority: i64,
+    extract: Option<PathBuf>,
+}
+
+/// Read the manifest at `path` and return its downloads, highest priority
+/// first.  The format is chosen by the file extension.
+pub async fn load(path: &Path) -> Result<Vec<Request>, util::Error> {
+    let format = Format::from_path(path)?;
+    let text = rt::fs::read_to_string(path).await.map_err(|e| util::Error {
+        what: format!("Couldn't read manifest {}.", path.display()),
+        source: Some(e.into()),
+        kind: util::ErrorKind::Other,
+    })?;
+    parse(&text, format).map_err(|e| util::Error {
+        what: format!("{}: {}", path.display(), e.what),
+        ..e
+    })
+}
+
+/// Parse the manifest `text` written in `format` and return its downloads,
+/// highest priority first.  Errors give the line and column of the problem.
+pub fn parse(text: &str, format: Format) -> Result<Vec<Request>, util::Error> {
+    let manifest: Manifest = match format {
+        Format::Toml => toml::from_str(text).map_err(|e| {
+            // toml counts lines and columns from zero.
+            let position = e.line_col().map(|(line, col)| (line + 1, col + 1));
+            invalid(position, e.into())
+        })?,
+        Format::Json => serde_json::from_str(text).map_err(|e| {
+            let position = Some((e.line(), e.column()));
+            invalid(position, e.into())
+        })?,
+    };
+
+    // Two downloads writing the same file would clobber each other.
+    let mut outputs = HashMap::new();
+    let mut requests = Vec::with_capacity(manifest.download.len());
+    for (i, entry) in manifest.download.into_iter().enumerate() {
+        let mut request = Request::new(entry.url);
+        if let Some(output) = entry.output {
+            request.output = output;
+        }
+        // A manifest from elsewhere mustn't write anywhere it likes.
+        for path in std::iter::once(&request.output).chain(&entry.extract) {
+            if safe_path(path).is_none() {
+                return Err(util::Error {
+                    what: format!(
+                        "Invalid manifest: download {} writes outside the current directory, to {}.",
+                        i + 1,
+                        path.display()
+                    ),
+                    source: None,
+                    kind: util::ErrorKind::Other,
+                });
+            }
+        }
+        if let Some(first) = outputs.insert(request.output.clone(), i) {
+            return Err(util::Error {
+                what: format!(
+                    "Invalid manifest: downloads {} and {} both write {}.",
+                    first + 1,
+                    i + 1,
+                    request.output.display()
+                ),
+                source: None,
+                kind: util::ErrorKind::Other,
+            });
+        }
+        request.mirrors = entry.mirrors;
+        request.size = entry.size;
+        request.checksum = entry.checksum;
+        request.headers = entry.headers;
+        request.priority = entry.priority;
+        request.extract = entry.extract;
+        requests.push(request);
+    }
+
+    // The sort is stable, so downloads with equal priority keep their order.
+    requests.sort_by_key(|request| std::cmp::Reverse(request.priority));
+    Ok(requests)
+}
+
+/// Error for a manifest that failed to parse at `position`.
+fn invalid(position: Option<(usize, usize)>, source: util::BoxError) -> util::Error {
+    let what = match position {
+        Some((line, col)) => format!("Invalid manifest at line {}, column {}.", line, col),
+        None => "Invalid manifest.".to_string(),
+    };
+    util::Error {
+        what,
+        source: Some(source),
+        kind: util::ErrorKind::Other,
+    }
+}
+
+/// Deserialize a table of header names and values, rejecting invalid ones.
+fn deserialize_headers<'de, D>(deserializer: D) -> Result<HeaderMap, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    let table = BTreeMap::<String, String>::deserialize(deserializer)?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in table {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| D::Error::custom(format!("invalid header name {:?}", name)))?;
+        let value = HeaderValue::from_str(&value)
+            .map_err(|_| D::Error::custom(format!("invalid value for header {}", name)))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_relative_outputs() {
+        let text = r#"
+            [[download]]
+            url = "https://example.com/a.tar.gz"
+            output = "data/a.tar.gz"
+            extract = "data/a"
+        "#;
+        let requests = parse(text, Format::Toml).unwrap();
+        assert_eq!(requests[0].output, Path::new("data/a.tar.gz"));
+        assert_eq!(requests[0].extract.as_deref(), Some(Path::new("data/a")));
+    }
+
+    #[test]
+    fn rejects_outputs_outside_the_current_directory() {
+        for output in &["/etc/passwd", "../a.bin", "data/../../a.bin"] {
+            let text = format!(
+                r#"{{ "download": [ {{ "url": "https://example.com/a.bin", "output": "{}" }} ] }}"#,
+                output
+            );
+            assert!(parse(&text, Format::Json).is_err(), "{}", output);
+        }
+    }
+
+    #[test]
+    fn rejects_extract_directories_outside_the_current_directory() {
+        for extract in &["/tmp", "..", "data/../.."] {
+            let text = format!(
+                "[[download]]\nurl = \"https://example.com/a.zip\"\nextract = \"{}\"\n",
+                extract
+            );
+            assert!(parse(&text, Format::Toml).is_err(), "{}", extract);
+        }
+    }
+}
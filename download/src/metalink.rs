@@ -0,0 +1,326 @@
+//! [Metalink 4](https://tools.ietf.org/html/rfc5854) files describing
+//! downloads with their mirrors, sizes, and hashes.
+//!
+//! A metalink lists one or more files.  Example:
+//!
+//! ```xml
+//! <?xml version="1.0" encoding="UTF-8"?>
+//! <metalink xmlns="urn:ietf:params:xml:ns:metalink">
+//!   <file name="audio/example.wav">
+//!     <size>10485760</size>
+//!     <hash type="sha-256">e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855</hash>
+//!     <pieces length="1048576" type="sha-256">
+//!       <hash>...</hash>
+//!       ...
+//!     </pieces>
+//!     <url location="de" priority="1">https://de.example.com/file_example_WAV_10MG.wav</url>
+//!     <url priority="2">https://example.com/file_example_WAV_10MG.wav</url>
+//!   </file>
+//! </metalink>
+//! ```
+//!
+//! Mirrors are tried in order of priority, lowest first, but mirrors in the
+//! preferred location come before all others.  Only HTTP and HTTPS URLs are
+//! used, and hashes with unsupported algorithms are ignored.
+
+use std::path::Path;
+
+use reqwest::Url;
+use roxmltree::{Document, Node};
+
+use crate::request::safe_path;
+use crate::{rt, Algorithm, Checksum, Pieces, Request};
+
+/// XML namespace of Metalink 4 elements.
+const NAMESPACE: &str = "urn:ietf:params:xml:ns:metalink";
+
+/// Priority of a URL without a `priority` attribute, after every URL with
+/// one.
+const LOWEST_PRIORITY: u32 = 999_999;
+
+/// Read the metalink at `path` and return its downloads.  Mirrors in
+/// `location`, an ISO 3166-1 country code such as `de`, are preferred.
+pub async fn load(path: &Path, location: Option<&str>) -> Result<Vec<Request>, util::Error> {
+    let text = rt::fs::read_to_string(path)
+        .await
+        .map_err(|e| util::Error {
+            what: format!("Couldn't read metalink {}.", path.display()),
+            source: Some(e.into()),
+            kind: util::ErrorKind::Other,
+        })?;
+    parse(&text, location).map_err(|e| util::Error {
+        what: format!("{}: {}", path.display(), e.what),
+        ..e
+    })
+}
+
+/// Parse the metalink `text` and return its downloads, in the order they are
+/// listed.  Mirrors in `location` are preferred.  Errors give the line and
+/// column of the problem.
+pub fn parse(text: &str, location: Option<&str>) -> Result<Vec<Request>, util::Error> {
+    let doc = Document::parse(text).map_err(|e| {
+        let pos = e.pos();
+        invalid(
+            &format!("line {}, column {}", pos.row, pos.col),
+            Some(e.into()),
+        )
+    })?;
+    let root = doc.root_element();
+    if !root.has_tag_name((NAMESPACE, "metalink")) {
+        return Err(invalid_at(
+            &root,
+            "expected a Metalink 4 <metalink> element",
+        ));
+    }
+
+    let mut requests: Vec<Request> = Vec::new();
+    for file in children(root, "file") {
+        let request = parse_file(&file, location)?;
+        // Two downloads writing the same file would clobber each other.
+        if requests.iter().any(|other| other.output == request.output) {
+            return Err(invalid_at(
+                &file,
+                &format!("more than one file is named {}", request.output.display()),
+            ));
+        }
+        requests.push(request);
+    }
+    if requests.is_empty() {
+        return Err(invalid_at(&root, "no <file> elements"));
+    }
+    Ok(requests)
+}
+
+/// Turn a `<file>` element into a request.
+fn parse_file(file: &Node, location: Option<&str>) -> Result<Request, util::Error> {
+    let name = file
+        .attribute("name")
+        .ok_or_else(|| invalid_at(file, "<file> without a name"))?;
+    let output =
+        safe_path(Path::new(name)).ok_or_else(|| invalid_at(file, &format!("unsafe file name {:?}", name)))?;
+
+    // Mirrors we can't use are skipped, the rest sorted by preference.  The
+    // sort is stable, so mirrors with equal priority keep their order.
+    let mut urls = Vec::new();
+    for url in children(*file, "url") {
+        let text = url.text().unwrap_or("").trim();
+        let parsed = Url::parse(text)
+            .map_err(|e| invalid_at(&url, &format!("invalid URL {:?}: {}", text, e)))?;
+        if parsed.scheme() != "http" && parsed.scheme() != "https" {
+            continue;
+        }
+        let priority = match url.attribute("priority") {
+            Some(priority) => priority
+                .parse::<u32>()
+                .map_err(|_| invalid_at(&url, &format!("invalid priority {:?}", priority)))?,
+            None => LOWEST_PRIORITY,
+        };
+        let preferred = match (location, url.attribute("location")) {
+            (Some(wanted), Some(actual)) => wanted.eq_ignore_ascii_case(actual),
+            _ => false,
+        };
+        urls.push((!preferred, priority, parsed));
+    }
+    urls.sort_by_key(|(not_preferred, priority, _)| (*not_preferred, *priority));
+    let mut urls = urls.into_iter().map(|(_, _, url)| url);
+    let url = urls
+        .next()
+        .ok_or_else(|| invalid_at(file, &format!("no HTTP or HTTPS URL for {}", name)))?;
+
+    let mut request = Request::new(url);
+    request.output = output;
+    request.mirrors = urls.collect();
+
+    if let Some(size) = children(*file, "size").next() {
+        let text = size.text().unwrap_or("").trim();
+        request.size = Some(
+            text.parse()
+                .map_err(|_| invalid_at(&size, &format!("invalid size {:?}", text)))?,
+        );
+    }
+
+    // Use the strongest whole-file hash we support.
+    for hash in children(*file, "hash") {
+        if let Some(algorithm) = hash
+            .attribute("type")
+            .and_then(|t| t.parse::<Algorithm>().ok())
+        {
+            let digest = parse_digest(&hash, algorithm)?;
+            if request
+                .checksum
+                .as_ref()
+                .is_none_or(|c| c.digest.len() < digest.len())
+            {
+                request.checksum = Some(Checksum { algorithm, digest });
+            }
+        }
+    }
+
+    for pieces in children(*file, "pieces") {
+        let algorithm = match pieces
+            .attribute("type")
+            .and_then(|t| t.parse::<Algorithm>().ok())
+        {
+            Some(algorithm) => algorithm,
+            None => continue,
+        };
+        let length = pieces
+            .attribute("length")
+            .and_then(|length| length.parse().ok())
+            .filter(|&length| length > 0)
+            .ok_or_else(|| invalid_at(&pieces, "<pieces> without a valid length"))?;
+        let digests = children(pieces, "hash")
+            .map(|hash| parse_digest(&hash, algorithm))
+            .collect::<Result<_, _>>()?;
+        let parsed = Pieces {
+            length,
+            algorithm,
+            digests,
+        };
+        if request.size.is_some_and(|size| !parsed.fits(size)) {
+            return Err(invalid_at(&pieces, "<pieces> don't match the size of the file"));
+        }
+        request.pieces = Some(parsed);
+    }
+    Ok(request)
+}
+
+/// Child elements of `node` named `name` in the Metalink namespace.
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children()
+        .filter(move |child| child.has_tag_name((NAMESPACE, name)))
+}
+
+/// Digest in the hex text of a `<hash>` element.
+fn parse_digest(hash: &Node, algorithm: Algorithm) -> Result<Vec<u8>, util::Error> {
+    let text = hash.text().unwrap_or("").trim();
+    match hex::decode(text) {
+        Ok(digest) if digest.len() == algorithm.hasher().finish().len() => Ok(digest),
+        _ => Err(invalid_at(
+            hash,
+            &format!("invalid {} hash {:?}", algorithm.name(), text),
+        )),
+    }
+}
+
+/// Error for a metalink that is invalid at `node`.
+fn invalid_at(node: &Node, problem: &str) -> util::Error {
+    let pos = node.document().text_pos_at(node.range().start);
+    invalid(
+        &format!("line {}, column {}: {}", pos.row, pos.col, problem),
+        None,
+    )
+}
+
+/// Error for a metalink that is invalid at `position`.
+fn invalid(position: &str, source: Option<util::BoxError>) -> util::Error {
+    util::Error {
+        what: format!("Invalid metalink at {}.", position),
+        source,
+        kind: util::ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Metalink for a file of `size` bytes with `count` pieces of 4 bytes.
+    fn metalink(size: u64, count: usize) -> String {
+        let hash = format!("<hash>{}</hash>", "00".repeat(32));
+        format!(
+            r#"<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+                 <file name="a.bin">
+                   <size>{}</size>
+                   <pieces length="4" type="sha-256">{}</pieces>
+                   <url>https://example.com/a.bin</url>
+                 </file>
+               </metalink>"#,
+            size,
+            hash.repeat(count)
+        )
+    }
+
+    #[test]
+    fn accepts_one_hash_per_piece() {
+        let requests = parse(&metalink(10, 3), None).unwrap();
+        assert_eq!(requests[0].pieces.as_ref().unwrap().digests.len(), 3);
+    }
+
+    #[test]
+    fn rejects_pieces_that_dont_match_the_size() {
+        assert!(parse(&metalink(10, 2), None).is_err());
+        assert!(parse(&metalink(10, 4), None).is_err());
+    }
+
+    /// Metalink for one file with `body` inside its `<file>` element.
+    fn file(body: &str) -> String {
+        format!(
+            r#"<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+                 <file name="a.bin">{}</file>
+               </metalink>"#,
+            body
+        )
+    }
+
+    #[test]
+    fn orders_mirrors_by_location_then_priority() {
+        let text = file(
+            r#"<url priority="2">https://b.example.com/a.bin</url>
+               <url>https://last.example.com/a.bin</url>
+               <url location="de" priority="3">https://de.example.com/a.bin</url>
+               <url priority="1">https://a.example.com/a.bin</url>
+               <url priority="1">https://also-a.example.com/a.bin</url>
+               <url priority="0">ftp://ftp.example.com/a.bin</url>"#,
+        );
+        let hosts = |request: &Request| -> Vec<String> {
+            let urls = std::iter::once(&request.url).chain(&request.mirrors);
+            urls.map(|url| url.host_str().unwrap().to_string()).collect()
+        };
+
+        // Without a location only the priority counts, and FTP is skipped.
+        let requests = parse(&text, None).unwrap();
+        let expected = ["a", "also-a", "b", "de", "last"];
+        let expected: Vec<_> = expected.iter().map(|h| format!("{}.example.com", h)).collect();
+        assert_eq!(hosts(&requests[0]), expected);
+
+        // The preferred location comes first, whatever its priority.
+        let requests = parse(&text, Some("DE")).unwrap();
+        let expected = ["de", "a", "also-a", "b", "last"];
+        let expected: Vec<_> = expected.iter().map(|h| format!("{}.example.com", h)).collect();
+        assert_eq!(hosts(&requests[0]), expected);
+    }
+
+    #[test]
+    fn uses_the_strongest_supported_whole_file_hash() {
+        let text = file(&format!(
+            r#"<hash type="md5">{}</hash>
+               <hash type="sha-256">{}</hash>
+               <hash type="sha-512">{}</hash>
+               <url>https://example.com/a.bin</url>"#,
+            "11".repeat(16),
+            "22".repeat(32),
+            "33".repeat(64)
+        ));
+        let checksum = parse(&text, None).unwrap()[0].checksum.clone().unwrap();
+        assert_eq!(checksum.algorithm, Algorithm::Sha512);
+        assert_eq!(checksum.digest, vec![0x33; 64]);
+
+        // A digest of the wrong length is an error, not a weaker hash.
+        let text = file(&format!(
+            r#"<hash type="sha-256">{}</hash>
+               <url>https://example.com/a.bin</url>"#,
+            "22".repeat(31)
+        ));
+        assert!(parse(&text, None).is_err());
+    }
+
+    #[test]
+    fn rejects_unsafe_file_names() {
+        let text = metalink(10, 3).replace("a.bin\"", "../a.bin\"");
+        assert!(parse(&text, None).is_err());
+    }
+}
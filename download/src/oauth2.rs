@@ -0,0 +1,256 @@
+//! OAuth2 access tokens for servers behind an authorization server.
+//!
+//! With the client credentials grant of RFC 6749, a program trades its
+//! client id and secret for an access token at the token endpoint and sends
+//! the token with Bearer authentication.  Added to `Credentials` for a host,
+//! the token is fetched before the first request to it and shared by every
+//! download with the same `Credentials`.  A new one is fetched shortly
+//! before it expires, or when a server refuses it with 401 Unauthorized.
+//! Example:
+//!
+//! ```ignore
+//! let token_url = Url::parse("https://auth.example.com/oauth2/token")?;
+//! let oauth2 = OAuth2 {
+//!     scopes: vec!["artifacts:read".to_string()],
+//!     ..OAuth2::new(client.clone(), token_url, "downloader", secret)
+//! };
+//! credentials.add_oauth2("artifacts.example.com", oauth2)?;
+//! ```
+
+use std::time::{Duration, SystemTime};
+
+use reqwest::header;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::auth::Credential;
+
+/// Tokens are replaced this long before they expire, so they don't expire
+/// on their way to the server.  Tokens that live less than twice as long
+/// are replaced halfway.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+/// Client of an OAuth2 authorization server, using the client credentials
+/// grant.
+#[derive(Clone)]
+pub struct OAuth2 {
+    /// Client to talk to the token endpoint with.
+    pub client: reqwest::Client,
+    /// URL of the token endpoint.
+    pub token_url: Url,
+    /// Who we are to the authorization server.
+    pub client_id: String,
+    /// Never included in `Debug` output or error messages.
+    pub client_secret: String,
+    /// Scopes to ask for, or none for the server's default.
+    pub scopes: Vec<String>,
+}
+impl OAuth2 {
+    /// Client `client_id` of the authorization server at `token_url`, with
+    /// the default scopes.
+    pub fn new(
+        client: reqwest::Client,
+        token_url: Url,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+    ) -> Self {
+        OAuth2 {
+            client,
+            token_url,
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            scopes: Vec::new(),
+        }
+    }
+
+    /// Get a new access token from the token endpoint.
+    pub async fn token(&self) -> Result<Token, util::Error> {
+        let mut form = vec![("grant_type", "client_credentials".to_string())];
+        if !self.scopes.is_empty() {
+            form.push(("scope", self.scopes.join(" ")));
+        }
+        let requested = SystemTime::now();
+        // RFC 6749 wants the id and secret form encoded before they are
+        // sent with Basic authentication.
+        let resp = self
+            .client
+            .post(self.token_url.clone())
+            .basic_auth(
+                form_encode(&self.client_id),
+                Some(form_encode(&self.client_secret)),
+            )
+            .header(header::ACCEPT, "application/json")
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| self.request_error(e))?;
+        let status = resp.status();
+        let body = resp.bytes().await.map_err(|e| self.request_error(e))?;
+
+        if !status.is_success() {
+            let reason = match serde_json::from_slice::<ErrorResponse>(&body) {
+                Ok(ErrorResponse {
+                    error,
+                    error_description: Some(description),
+                }) => format!("{} ({})", error, description),
+                Ok(ErrorResponse { error, .. }) => error,
+                Err(_) => status.to_string(),
+            };
+            let kind = if status.is_client_error() {
+                util::ErrorKind::Auth
+            } else {
+                util::ErrorKind::Other
+            };
+            return Err(self.error(format!("refused with {}", reason), kind));
+        }
+
+        // The body holds the token, so a parse error, which may quote it,
+        // isn't passed on.
+        let resp: TokenResponse = serde_json::from_slice(&body).map_err(|_| {
+            self.error(
+                "sent an invalid response".to_string(),
+                util::ErrorKind::Other,
+            )
+        })?;
+        if !resp.token_type.eq_ignore_ascii_case("bearer") {
+            return Err(self.error(
+                format!("sent an unsupported token type {}", resp.token_type),
+                util::ErrorKind::Auth,
+            ));
+        }
+        let expires = resp
+            .expires_in
+            .as_ref()
+            .and_then(seconds)
+            .and_then(|secs| expiry(requested, secs));
+        Credential::Bearer(resp.access_token.clone()).header_value()?;
+        Ok(Token {
+            access_token: resp.access_token,
+            expires,
+        })
+    }
+
+    /// Tokens of clients with the same key are interchangeable.
+    pub(crate) fn key(&self) -> String {
+        format!(
+            "oauth2 {} {} {}",
+            self.token_url,
+            self.client_id,
+            self.scopes.join(" ")
+        )
+    }
+
+    /// Error for a token request that failed `how`.
+    fn error(&self, how: String, kind: util::ErrorKind) -> util::Error {
+        util::Error {
+            what: format!(
+                "The token endpoint {} {} for client {}.",
+                self.token_url, how, self.client_id
+            ),
+            source: None,
+            kind,
+        }
+    }
+
+    /// Error for a token request that didn't get through.
+    fn request_error(&self, error: reqwest::Error) -> util::Error {
+        util::Error {
+            what: format!("Couldn't get an access token from {}.", self.token_url),
+            kind: if error.is_timeout() {
+                util::ErrorKind::Timeout
+            } else {
+                util::ErrorKind::Other
+            },
+            source: Some(error.into()),
+        }
+    }
+}
+impl std::fmt::Debug for OAuth2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OAuth2")
+            .field("token_url", &self.token_url)
+            .field("client_id", &self.client_id)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+/// An access token from the token endpoint.
+#[derive(Clone)]
+pub struct Token {
+    /// Never included in `Debug` output.
+    pub access_token: String,
+    /// When the token should be replaced, if the server said when it
+    /// expires.
+    pub expires: Option<SystemTime>,
+}
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Token")
+            .field("expires", &self.expires)
+            .finish()
+    }
+}
+
+/// Successful response of the token endpoint, from RFC 6749 section 5.1.
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: String,
+    /// Lifetime of the token in seconds.  Some servers send it as a string.
+    #[serde(default)]
+    expires_in: Option<serde_json::Value>,
+}
+
+/// Error response of the token endpoint, from RFC 6749 section 5.2.
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+/// Number of seconds in `value`, a number or a string.
+fn seconds(value: &serde_json::Value) -> Option<u64> {
+    match value {
+        serde_json::Value::Number(secs) => secs.as_u64(),
+        serde_json::Value::String(secs) => secs.parse().ok(),
+        _ => None,
+    }
+}
+
+/// When a token requested at `requested` that lasts `secs` seconds should be
+/// replaced, a little before it expires.  `None` if that is too far off to
+/// represent, as if the token didn't say.
+fn expiry(requested: SystemTime, secs: u64) -> Option<SystemTime> {
+    let lifetime = Duration::from_secs(secs);
+    requested
+        .checked_add(lifetime)?
+        .checked_sub(EXPIRY_MARGIN.min(lifetime / 2))
+}
+
+/// Encode `value` like a form field.
+fn form_encode(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_a_little_early() {
+        let requested = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert_eq!(
+            expiry(requested, 3600),
+            Some(requested + Duration::from_secs(3600 - 30))
+        );
+        assert_eq!(expiry(requested, 10), Some(requested + Duration::from_secs(5)));
+        assert_eq!(expiry(requested, 0), Some(requested));
+    }
+
+    #[test]
+    fn ignores_a_lifetime_too_long_to_represent() {
+        assert_eq!(expiry(SystemTime::now(), u64::MAX), None);
+    }
+}
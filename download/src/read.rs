@@ -0,0 +1,180 @@
+//! Reading a response body through `tokio::io::AsyncRead` or
+//! `futures::io::AsyncRead`.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Convert the body of `response` into a `tokio::io::AsyncRead` so it can be
+/// used with `tokio::io::copy()`.  Example:
+///
+/// ```ignore
+/// let mut download = download::into_async_read(response);
+/// tokio::io::copy(&mut download, &mut outfile).await?;
+/// ```
+///
+/// A `reqwest::Response` does not implement `AsyncRead` directly.  Instead we
+/// convert it into a `futures::stream::Stream` of chunks, then into a
+/// `futures::io::AsyncRead`, and finally into a `tokio::io::AsyncRead` with
+/// the compatibility layer in `tokio_util::compat`.  See the
+/// reqwest-tokio-compat example for details.
+#[cfg(feature = "rt-tokio")]
+pub fn into_async_read(response: reqwest::Response) -> impl tokio::io::AsyncRead + Send + Unpin {
+    use futures::stream::TryStreamExt;
+    use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+    response
+        .bytes_stream()
+        // AsyncRead uses futures::io::Error, so we must convert the
+        // reqwest::Error first.
+        .map_err(futures::io::Error::other)
+        .into_async_read()
+        .compat()
+}
+
+/// Something that can be told how many bytes have been transferred, such as a
+/// progress bar.  Implemented for `indicatif::ProgressBar` and for closures
+/// taking the number of new bytes, which can forward them to any event sink.
+pub trait Progress {
+    /// Report that `n` more bytes have been transferred.
+    fn advance(&self, n: u64);
+
+    /// Report the total number of bytes expected.  Ignored by default.
+    fn set_total(&self, _total: u64) {}
+
+    /// Report a short, human-readable status such as the file name and the
+    /// server it is coming from.  Ignored by default.
+    fn set_status(&self, _status: &str) {}
+
+    /// Start a second phase, such as extracting a finished download, that
+    /// will process `total` bytes from the beginning.  Ignored by default.
+    fn restart(&self, _total: u64) {}
+}
+impl Progress for indicatif::ProgressBar {
+    fn advance(&self, n: u64) {
+        self.inc(n);
+    }
+
+    fn set_total(&self, total: u64) {
+        self.set_length(total);
+    }
+
+    fn set_status(&self, status: &str) {
+        self.set_message(status);
+    }
+
+    fn restart(&self, total: u64) {
+        self.reset();
+        self.set_length(total);
+    }
+}
+impl<F: Fn(u64)> Progress for F {
+    fn advance(&self, n: u64) {
+        self(n);
+    }
+}
+
+/// Wraps a `tokio::io::AsyncRead` or `futures::io::AsyncRead` and reports
+/// every byte read to `progress`.  Example:
+///
+/// ```ignore
+/// let download = download::into_async_read(response);
+/// let mut download = ProgressReader::new(download, progress_bar);
+/// tokio::io::copy(&mut download, &mut outfile).await?;
+/// ```
+#[derive(Debug)]
+pub struct ProgressReader<R, P> {
+    inner: R,
+    progress: P,
+}
+impl<R, P> ProgressReader<R, P> {
+    /// Wrap `inner`, reporting bytes read to `progress`.
+    pub fn new(inner: R, progress: P) -> Self {
+        ProgressReader { inner, progress }
+    }
+
+    /// Unwrap the reader, returning the inner reader and progress sink.
+    pub fn into_inner(self) -> (R, P) {
+        (self.inner, self.progress)
+    }
+}
+#[cfg(feature = "rt-tokio")]
+impl<R, P> tokio::io::AsyncRead for ProgressReader<R, P>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    P: Progress + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = &poll {
+            this.progress.advance(*n as u64);
+        }
+        poll
+    }
+}
+impl<R, P> futures::io::AsyncRead for ProgressReader<R, P>
+where
+    R: futures::io::AsyncRead + Unpin,
+    P: Progress + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = &poll {
+            this.progress.advance(*n as u64);
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn reports_every_read_to_the_progress() {
+        let content: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let reads = Mutex::new(Vec::new());
+        let progress = |n| reads.lock().unwrap().push(n);
+        let mut reader = ProgressReader::new(futures::io::Cursor::new(content.clone()), progress);
+        let mut buf = [0u8; 4096];
+        let mut read = Vec::new();
+        futures::executor::block_on(async {
+            use futures::io::AsyncReadExt;
+            loop {
+                let n = reader.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                read.extend_from_slice(&buf[..n]);
+            }
+        });
+        assert_eq!(read, content);
+        // The end of the input is reported as a read of 0 bytes.
+        assert_eq!(*reads.lock().unwrap(), [4096, 4096, 1808, 0]);
+    }
+
+    #[cfg(feature = "rt-tokio")]
+    #[test]
+    fn reports_tokio_reads_to_the_progress() {
+        let content = vec![7u8; 100_000];
+        let total = Mutex::new(0);
+        let progress = |n| *total.lock().unwrap() += n;
+        let mut reader = ProgressReader::new(&content[..], progress);
+        let mut copied = Vec::new();
+        crate::rt::block_on(tokio::io::copy(&mut reader, &mut copied))
+            .unwrap()
+            .unwrap();
+        assert_eq!(copied, content);
+        assert_eq!(*total.lock().unwrap(), 100_000);
+    }
+}
@@ -0,0 +1,76 @@
+//! Description of a single file to download.
+
+use std::path::{Component, Path, PathBuf};
+
+use reqwest::header::HeaderMap;
+use reqwest::Url;
+
+use crate::{Checksum, Pieces};
+
+/// A single file to download, as listed in a manifest.  Example:
+///
+/// ```ignore
+/// let mut request = Request::new(Url::parse("https://example.com/file.wav")?);
+/// request.checksum = Some("sha256:e3b0c442...".parse()?);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Request {
+    /// Where to download the file from.
+    pub url: Url,
+    /// Alternative URLs serving the same file.
+    pub mirrors: Vec<Url>,
+    /// Where to save the file.
+    pub output: PathBuf,
+    /// Expected size of the file in bytes, if known.
+    pub size: Option<u64>,
+    /// Expected checksum of the file, if known.
+    pub checksum: Option<Checksum>,
+    /// Expected checksums of the pieces of the file, if known.
+    pub pieces: Option<Pieces>,
+    /// Extra headers to send with every request for the file.
+    pub headers: HeaderMap,
+    /// Requests with higher priority are started first.
+    pub priority: i64,
+    /// Directory to extract the file into, if it is an archive.
+    pub extract: Option<PathBuf>,
+}
+impl Request {
+    /// Request to download `url` into a file named after the last segment of
+    /// its path.
+    pub fn new(url: Url) -> Self {
+        Request {
+            output: output_for(&url),
+            url,
+            mirrors: Vec::new(),
+            size: None,
+            checksum: None,
+            pieces: None,
+            headers: HeaderMap::new(),
+            priority: 0,
+            extract: None,
+        }
+    }
+}
+
+/// Name of the output file for `url`, taken from the last segment of its path.
+pub fn output_for(url: &Url) -> PathBuf {
+    url.path_segments() // Splits into segments of the URL
+        .and_then(|mut segments| segments.next_back()) // Retrieves the last segment
+        .filter(|segment| !segment.is_empty())
+        .unwrap_or("file.download") // Fallback to generic filename
+        .into()
+}
+
+/// `path` if it is a relative path that stays inside the current directory,
+/// or `None` if it is absolute or climbs out with `..`.  Manifests and
+/// metalinks from elsewhere mustn't write anywhere else.
+pub(crate) fn safe_path(path: &Path) -> Option<PathBuf> {
+    let safe = path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if safe && path.components().any(|c| matches!(c, Component::Normal(_))) {
+        Some(path.to_path_buf())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,416 @@
+//! Thin adapters over the async runtime.
+//!
+//! The download core is written against the `futures` traits and needs only
+//! a few things from a runtime: spawning tasks, running blocking code on a
+//! thread where that is allowed, timers, and files and stdout that implement
+//! `futures::io::AsyncRead` and `AsyncWrite`.  Each runtime provides them
+//! behind a cargo feature, `rt-tokio` (the default) or `rt-async-std`.  With
+//! both features, code running on an async-std task uses async-std and
+//! everything else uses tokio.
+
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::{BoxFuture, Either};
+
+#[cfg(not(any(feature = "rt-tokio", feature = "rt-async-std")))]
+compile_error!("The download crate needs the rt-tokio or rt-async-std feature.");
+
+/// Runtimes we have adapters for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Runtime {
+    #[cfg(feature = "rt-tokio")]
+    Tokio,
+    #[cfg(feature = "rt-async-std")]
+    AsyncStd,
+}
+
+/// The runtime of the task we are running on.
+fn current() -> Runtime {
+    #[cfg(all(feature = "rt-tokio", feature = "rt-async-std"))]
+    {
+        if async_std::task::try_current().is_some() {
+            Runtime::AsyncStd
+        } else {
+            Runtime::Tokio
+        }
+    }
+    #[cfg(all(feature = "rt-tokio", not(feature = "rt-async-std")))]
+    {
+        Runtime::Tokio
+    }
+    #[cfg(all(feature = "rt-async-std", not(feature = "rt-tokio")))]
+    {
+        Runtime::AsyncStd
+    }
+}
+
+/// Handle to a spawned task, which resolves to the task's output.  Dropping
+/// the handle lets the task run on by itself.
+pub struct JoinHandle<T> {
+    inner: BoxFuture<'static, Result<T, util::Error>>,
+}
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, util::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.as_mut().poll(cx)
+    }
+}
+impl<T> std::fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JoinHandle").finish()
+    }
+}
+
+/// Run `future` as a task of its own.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let inner: BoxFuture<'static, _> = match current() {
+        #[cfg(feature = "rt-tokio")]
+        Runtime::Tokio => {
+            let task = tokio::spawn(future);
+            Box::pin(async move { task.await.map_err(join_error) })
+        }
+        #[cfg(feature = "rt-async-std")]
+        Runtime::AsyncStd => {
+            let task = async_std::task::spawn(future);
+            Box::pin(async move { Ok(task.await) })
+        }
+    };
+    JoinHandle { inner }
+}
+
+/// Run the blocking function `f` on a thread where blocking is allowed.
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let inner: BoxFuture<'static, _> = match current() {
+        #[cfg(feature = "rt-tokio")]
+        Runtime::Tokio => {
+            let task = tokio::task::spawn_blocking(f);
+            Box::pin(async move { task.await.map_err(join_error) })
+        }
+        #[cfg(feature = "rt-async-std")]
+        Runtime::AsyncStd => {
+            let task = async_std::task::spawn_blocking(f);
+            Box::pin(async move { Ok(task.await) })
+        }
+    };
+    JoinHandle { inner }
+}
+
+/// Wait for `duration`.
+pub async fn sleep(duration: Duration) {
+    match current() {
+        #[cfg(feature = "rt-tokio")]
+        Runtime::Tokio => tokio::time::delay_for(duration).await,
+        #[cfg(feature = "rt-async-std")]
+        Runtime::AsyncStd => async_std::task::sleep(duration).await,
+    }
+}
+
+/// Wait at most `duration` for `future`.  Returns `None` if it took longer.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    let sleep = sleep(duration);
+    futures::pin_mut!(future, sleep);
+    match futures::future::select(future, sleep).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+/// Run `future` to completion on a runtime of its own, blocking the current
+/// thread until it is done.  This is the way in for synchronous code, so it
+/// must not be called from a task.
+pub fn block_on<F: Future>(future: F) -> io::Result<F::Output> {
+    match current() {
+        #[cfg(feature = "rt-tokio")]
+        Runtime::Tokio => {
+            let mut runtime = tokio::runtime::Builder::new()
+                .basic_scheduler()
+                .enable_all()
+                .build()?;
+            Ok(runtime.block_on(future))
+        }
+        #[cfg(feature = "rt-async-std")]
+        Runtime::AsyncStd => Ok(async_std::task::block_on(future)),
+    }
+}
+
+/// An open file that implements `futures::io::AsyncRead` and `AsyncWrite`.
+#[derive(Debug)]
+pub struct File {
+    inner: FileInner,
+}
+
+/// The file type of each runtime.
+#[derive(Debug)]
+enum FileInner {
+    #[cfg(feature = "rt-tokio")]
+    Tokio(tokio_util::compat::Compat<tokio::fs::File>),
+    #[cfg(feature = "rt-async-std")]
+    AsyncStd(async_std::fs::File),
+}
+
+impl File {
+    /// Open the file at `path` for reading.
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        File::open_with(path, std::fs::OpenOptions::new().read(true)).await
+    }
+
+    /// Create the file at `path` for writing, replacing any file already
+    /// there.
+    pub async fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        File::open_with(path, &options).await
+    }
+
+    /// Open the file at `path` with `options`.
+    pub async fn open_with(
+        path: impl AsRef<Path>,
+        options: &std::fs::OpenOptions,
+    ) -> io::Result<Self> {
+        let (path, options) = (path.as_ref().to_owned(), options.clone());
+        let file = fs::blocking(move || options.open(path)).await?;
+        let inner = match current() {
+            #[cfg(feature = "rt-tokio")]
+            Runtime::Tokio => {
+                use tokio_util::compat::Tokio02AsyncReadCompatExt;
+                FileInner::Tokio(tokio::fs::File::from_std(file).compat())
+            }
+            #[cfg(feature = "rt-async-std")]
+            Runtime::AsyncStd => FileInner::AsyncStd(async_std::fs::File::from(file)),
+        };
+        Ok(File { inner })
+    }
+
+    /// Move to `pos` in the file.
+    pub async fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match &mut self.inner {
+            #[cfg(feature = "rt-tokio")]
+            FileInner::Tokio(file) => file.get_mut().seek(pos).await,
+            #[cfg(feature = "rt-async-std")]
+            FileInner::AsyncStd(file) => futures::io::AsyncSeekExt::seek(file, pos).await,
+        }
+    }
+
+    /// Cut or extend the file to `size` bytes.
+    pub async fn set_len(&mut self, size: u64) -> io::Result<()> {
+        match &mut self.inner {
+            #[cfg(feature = "rt-tokio")]
+            FileInner::Tokio(file) => file.get_mut().set_len(size).await,
+            #[cfg(feature = "rt-async-std")]
+            FileInner::AsyncStd(file) => file.set_len(size).await,
+        }
+    }
+}
+
+impl futures::io::AsyncRead for File {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().inner {
+            #[cfg(feature = "rt-tokio")]
+            FileInner::Tokio(file) => Pin::new(file).poll_read(cx, buf),
+            #[cfg(feature = "rt-async-std")]
+            FileInner::AsyncStd(file) => Pin::new(file).poll_read(cx, buf),
+        }
+    }
+}
+
+impl futures::io::AsyncWrite for File {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().inner {
+            #[cfg(feature = "rt-tokio")]
+            FileInner::Tokio(file) => Pin::new(file).poll_write(cx, buf),
+            #[cfg(feature = "rt-async-std")]
+            FileInner::AsyncStd(file) => Pin::new(file).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            #[cfg(feature = "rt-tokio")]
+            FileInner::Tokio(file) => Pin::new(file).poll_flush(cx),
+            #[cfg(feature = "rt-async-std")]
+            FileInner::AsyncStd(file) => Pin::new(file).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            #[cfg(feature = "rt-tokio")]
+            FileInner::Tokio(file) => Pin::new(file).poll_close(cx),
+            #[cfg(feature = "rt-async-std")]
+            FileInner::AsyncStd(file) => Pin::new(file).poll_close(cx),
+        }
+    }
+}
+
+/// Standard output as a `futures::io::AsyncWrite`.
+#[derive(Debug)]
+pub struct Stdout {
+    inner: StdoutInner,
+}
+
+/// The stdout type of each runtime.
+#[derive(Debug)]
+enum StdoutInner {
+    #[cfg(feature = "rt-tokio")]
+    Tokio(tokio_util::compat::Compat<tokio::io::Stdout>),
+    #[cfg(feature = "rt-async-std")]
+    AsyncStd(async_std::io::Stdout),
+}
+
+/// Standard output of the process.
+pub fn stdout() -> Stdout {
+    let inner = match current() {
+        #[cfg(feature = "rt-tokio")]
+        Runtime::Tokio => {
+            use tokio_util::compat::Tokio02AsyncWriteCompatExt;
+            StdoutInner::Tokio(tokio::io::stdout().compat_write())
+        }
+        #[cfg(feature = "rt-async-std")]
+        Runtime::AsyncStd => StdoutInner::AsyncStd(async_std::io::stdout()),
+    };
+    Stdout { inner }
+}
+
+impl futures::io::AsyncWrite for Stdout {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().inner {
+            #[cfg(feature = "rt-tokio")]
+            StdoutInner::Tokio(stdout) => Pin::new(stdout).poll_write(cx, buf),
+            #[cfg(feature = "rt-async-std")]
+            StdoutInner::AsyncStd(stdout) => Pin::new(stdout).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            #[cfg(feature = "rt-tokio")]
+            StdoutInner::Tokio(stdout) => Pin::new(stdout).poll_flush(cx),
+            #[cfg(feature = "rt-async-std")]
+            StdoutInner::AsyncStd(stdout) => Pin::new(stdout).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            #[cfg(feature = "rt-tokio")]
+            StdoutInner::Tokio(stdout) => Pin::new(stdout).poll_close(cx),
+            #[cfg(feature = "rt-async-std")]
+            StdoutInner::AsyncStd(stdout) => Pin::new(stdout).poll_close(cx),
+        }
+    }
+}
+
+/// Wrap an error joining a task, which means it panicked or was cancelled.
+#[cfg(feature = "rt-tokio")]
+fn join_error(error: tokio::task::JoinError) -> util::Error {
+    util::Error {
+        what: "Task failed.".to_string(),
+        source: Some(error.into()),
+        kind: util::ErrorKind::Other,
+    }
+}
+
+/// File system operations that don't need an open file.  They block, so like
+/// `tokio::fs` they run on a thread where that is allowed, whatever the
+/// runtime.
+pub mod fs {
+    use std::io;
+    use std::path::Path;
+
+    /// Run the blocking file system operation `f`.
+    pub(crate) async fn blocking<F, T>(f: F) -> io::Result<T>
+    where
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        super::spawn_blocking(f)
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)))
+    }
+
+    /// Like `std::fs::metadata()`.
+    pub async fn metadata(path: impl AsRef<Path>) -> io::Result<std::fs::Metadata> {
+        let path = path.as_ref().to_owned();
+        blocking(move || std::fs::metadata(path)).await
+    }
+
+    /// Like `std::fs::create_dir_all()`.
+    pub async fn create_dir_all(path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref().to_owned();
+        blocking(move || std::fs::create_dir_all(path)).await
+    }
+
+    /// Like `std::fs::read()`.
+    pub async fn read(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+        let path = path.as_ref().to_owned();
+        blocking(move || std::fs::read(path)).await
+    }
+
+    /// Like `std::fs::read_to_string()`.
+    pub async fn read_to_string(path: impl AsRef<Path>) -> io::Result<String> {
+        let path = path.as_ref().to_owned();
+        blocking(move || std::fs::read_to_string(path)).await
+    }
+
+    /// Like `std::fs::write()`.
+    pub async fn write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+        let (path, contents) = (path.as_ref().to_owned(), contents.as_ref().to_owned());
+        blocking(move || std::fs::write(path, contents)).await
+    }
+
+    /// Like `std::fs::copy()`.
+    pub async fn copy(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<u64> {
+        let (from, to) = (from.as_ref().to_owned(), to.as_ref().to_owned());
+        blocking(move || std::fs::copy(from, to)).await
+    }
+
+    /// Like `std::fs::rename()`.
+    pub async fn rename(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
+        let (from, to) = (from.as_ref().to_owned(), to.as_ref().to_owned());
+        blocking(move || std::fs::rename(from, to)).await
+    }
+
+    /// Like `std::fs::hard_link()`.
+    pub async fn hard_link(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
+        let (from, to) = (from.as_ref().to_owned(), to.as_ref().to_owned());
+        blocking(move || std::fs::hard_link(from, to)).await
+    }
+
+    /// Like `std::fs::remove_file()`.
+    pub async fn remove_file(path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref().to_owned();
+        blocking(move || std::fs::remove_file(path)).await
+    }
+
+    /// Like `std::fs::remove_dir_all()`.
+    pub async fn remove_dir_all(path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref().to_owned();
+        blocking(move || std::fs::remove_dir_all(path)).await
+    }
+}
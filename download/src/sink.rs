@@ -0,0 +1,684 @@
+//! Destinations for the bytes of a download.
+//!
+//! A `Writer` hands every chunk to a `Sink`.  Besides files and anything else
+//! that implements `futures::io::AsyncWrite`, such as stdout, a download can go
+//! into memory, into several sinks at once, or into an entry of a tar archive
+//! that is written as a stream.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use futures::future::BoxFuture;
+use futures::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use futures::lock::OwnedMutexGuard;
+
+use crate::rt;
+use crate::writer::{write_error, BUFFER_SIZE};
+
+/// Tar archives are made of blocks of this size.
+const BLOCK: usize = 512;
+
+/// Most bytes of a tar entry held in memory while it waits to be written.
+/// Larger entries wait in a temporary file.
+const MEMORY_LIMIT: usize = 4 * BUFFER_SIZE;
+
+/// Numbers the temporary files of tar entries, so concurrent entries each
+/// have their own.
+static SPOOL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Something a download can be written to, one chunk at a time.  Example:
+///
+/// ```ignore
+/// let mut writer = Writer::for_sink(sink::stdout());
+/// while let Some(chunk) = download.chunk().await? {
+///     writer.write(chunk).await?;
+/// }
+/// writer.finish().await?;
+/// ```
+pub trait Sink: Send {
+    /// Learn the size of the download, if known, before the first chunk.
+    /// Ignored by default.
+    fn expect(&mut self, _size: Option<u64>) {}
+
+    /// Write all of `chunk`.
+    fn write(&mut self, chunk: Bytes) -> BoxFuture<'_, Result<(), util::Error>>;
+
+    /// Finish writing after the last chunk, e.g. by flushing buffers.
+    fn finish(&mut self) -> BoxFuture<'_, Result<(), util::Error>>;
+}
+impl<S: Sink + ?Sized> Sink for Box<S> {
+    fn expect(&mut self, size: Option<u64>) {
+        (**self).expect(size)
+    }
+
+    fn write(&mut self, chunk: Bytes) -> BoxFuture<'_, Result<(), util::Error>> {
+        (**self).write(chunk)
+    }
+
+    fn finish(&mut self) -> BoxFuture<'_, Result<(), util::Error>> {
+        (**self).finish()
+    }
+}
+
+/// Sink for any `futures::io::AsyncWrite`, through a buffer that coalesces
+/// small chunks into large writes.
+#[derive(Debug)]
+pub struct Output<W> {
+    output: BufWriter<W>,
+}
+impl<W: AsyncWrite + Unpin + Send> Output<W> {
+    /// Write to `output` through a buffer of `buffer_size` bytes.
+    pub fn with_capacity(output: W, buffer_size: usize) -> Self {
+        Output {
+            output: BufWriter::with_capacity(buffer_size, output),
+        }
+    }
+}
+impl<W: AsyncWrite + Unpin + Send> Sink for Output<W> {
+    fn write(&mut self, chunk: Bytes) -> BoxFuture<'_, Result<(), util::Error>> {
+        // write() may write only part of the chunk; write_all() retries
+        // until all of it is written.
+        Box::pin(async move { self.output.write_all(&chunk).await.map_err(write_error) })
+    }
+
+    fn finish(&mut self) -> BoxFuture<'_, Result<(), util::Error>> {
+        // Must flush futures::io::BufWriter manually.
+        // It will *not* flush itself automatically when dropped.
+        Box::pin(async move { self.output.flush().await.map_err(write_error) })
+    }
+}
+
+/// Sink that creates the file at `path`, replacing any file already there.
+pub async fn file(path: &Path) -> Result<Output<rt::File>, util::Error> {
+    let file = rt::File::create(path).await.map_err(|e| util::Error {
+        what: format!("Couldn't create {}.", path.display()),
+        source: Some(e.into()),
+        kind: util::ErrorKind::Other,
+    })?;
+    Ok(Output::with_capacity(file, BUFFER_SIZE))
+}
+
+/// Sink that writes to standard output, for piping a download into another
+/// program.
+pub fn stdout() -> Output<rt::Stdout> {
+    Output::with_capacity(rt::stdout(), BUFFER_SIZE)
+}
+
+/// Sink that keeps the download in memory, up to a limit.  Clones share the
+/// same buffer, so keep a clone to get the contents once the writer has
+/// finished.  Example:
+///
+/// ```ignore
+/// let memory = Memory::new(1024 * 1024);
+/// let mut writer = Writer::for_sink(memory.clone());
+/// ...
+/// writer.finish().await?;
+/// let body = memory.contents();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Memory {
+    buffer: Arc<Mutex<BytesMut>>,
+    limit: usize,
+}
+impl Memory {
+    /// Keep up to `limit` bytes in memory.  Writing more fails.
+    pub fn new(limit: usize) -> Self {
+        Memory {
+            buffer: Arc::new(Mutex::new(BytesMut::new())),
+            limit,
+        }
+    }
+
+    /// Everything written so far.
+    pub fn contents(&self) -> Bytes {
+        Bytes::copy_from_slice(&self.buffer.lock().unwrap())
+    }
+
+    /// Error for a download larger than the limit.
+    fn too_large(&self) -> util::Error {
+        util::Error {
+            what: format!("Download is larger than the {} bytes allowed in memory.", self.limit),
+            source: None,
+            kind: util::ErrorKind::Other,
+        }
+    }
+}
+impl Sink for Memory {
+    fn expect(&mut self, size: Option<u64>) {
+        // Reserve the space up front if it fits.
+        if let Some(size) = size.filter(|&size| size <= self.limit as u64) {
+            self.buffer.lock().unwrap().reserve(size as usize);
+        }
+    }
+
+    fn write(&mut self, chunk: Bytes) -> BoxFuture<'_, Result<(), util::Error>> {
+        let result = {
+            let mut buffer = self.buffer.lock().unwrap();
+            if buffer.len() + chunk.len() > self.limit {
+                Err(self.too_large())
+            } else {
+                buffer.extend_from_slice(&chunk);
+                Ok(())
+            }
+        };
+        Box::pin(futures::future::ready(result))
+    }
+
+    fn finish(&mut self) -> BoxFuture<'_, Result<(), util::Error>> {
+        Box::pin(futures::future::ready(Ok(())))
+    }
+}
+
+/// Sink that writes every chunk to several sinks at the same time, e.g. to
+/// save a download while also keeping it in memory.
+pub struct Tee {
+    sinks: Vec<Box<dyn Sink>>,
+}
+impl Tee {
+    /// Write to all of `sinks`.
+    pub fn new(sinks: Vec<Box<dyn Sink>>) -> Self {
+        Tee { sinks }
+    }
+}
+impl Sink for Tee {
+    fn expect(&mut self, size: Option<u64>) {
+        for sink in &mut self.sinks {
+            sink.expect(size);
+        }
+    }
+
+    fn write(&mut self, chunk: Bytes) -> BoxFuture<'_, Result<(), util::Error>> {
+        let writes = self.sinks.iter_mut().map(move |sink| sink.write(chunk.clone()));
+        Box::pin(async move { futures::future::try_join_all(writes).await.map(drop) })
+    }
+
+    fn finish(&mut self) -> BoxFuture<'_, Result<(), util::Error>> {
+        let finishes = self.sinks.iter_mut().map(|sink| sink.finish());
+        Box::pin(async move { futures::future::try_join_all(finishes).await.map(drop) })
+    }
+}
+impl std::fmt::Debug for Tee {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tee").field("sinks", &self.sinks.len()).finish()
+    }
+}
+
+/// Tar archive written as a stream, with one entry per download.  Clones
+/// write to the same archive.  Example:
+///
+/// ```ignore
+/// let archive = TarArchive::new(rt::File::create("downloads.tar").await?);
+/// let outcome = download::download_to(&request, &options, &bar, archive.entry("a.wav")).await?;
+/// archive.finish().await?;
+/// ```
+#[derive(Debug)]
+pub struct TarArchive<W> {
+    output: Arc<futures::lock::Mutex<W>>,
+    /// Path of the entry that was cut short after its header was written, if
+    /// any.  The rest of the archive can't be read past it.
+    broken: Arc<Mutex<Option<PathBuf>>>,
+}
+impl<W> Clone for TarArchive<W> {
+    fn clone(&self) -> Self {
+        TarArchive {
+            output: self.output.clone(),
+            broken: self.broken.clone(),
+        }
+    }
+}
+impl<W: AsyncWrite + Unpin + Send + 'static> TarArchive<W> {
+    /// Write the archive to `output`.
+    pub fn new(output: W) -> Self {
+        TarArchive {
+            output: Arc::new(futures::lock::Mutex::new(output)),
+            broken: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Sink for a new entry at `path` in the archive.  Entries are written
+    /// one at a time, in the order they finish if they overlap.
+    pub fn entry(&self, path: impl Into<PathBuf>) -> TarEntry<W> {
+        TarEntry {
+            output: self.output.clone(),
+            broken: self.broken.clone(),
+            path: path.into(),
+            size: None,
+            state: EntryState::Pending,
+        }
+    }
+
+    /// End the archive once every entry has finished, and flush it.  Fails
+    /// if an entry was cut short, since the archive is unreadable past it.
+    pub async fn finish(self) -> Result<(), util::Error> {
+        let mut output = self.output.lock().await;
+        check_broken(&self.broken)?;
+        output.write_all(&[0; 2 * BLOCK]).await.map_err(write_error)?;
+        output.flush().await.map_err(write_error)
+    }
+}
+
+/// Sink for one entry of a `TarArchive`.  The header of an entry gives its
+/// size, so the entry is written straight to the archive only if the size of
+/// the download is known and no other entry is being written.  Otherwise it
+/// is held back until it is complete, in memory up to `MEMORY_LIMIT` bytes
+/// and in a temporary file beyond that.  An entry written straight to the
+/// archive that ends short of its size, or is dropped before it ends, breaks
+/// the archive: later entries and `TarArchive::finish()` fail.
+#[derive(Debug)]
+pub struct TarEntry<W> {
+    output: Arc<futures::lock::Mutex<W>>,
+    broken: Arc<Mutex<Option<PathBuf>>>,
+    path: PathBuf,
+    /// Size promised by `expect()`.
+    size: Option<u64>,
+    state: EntryState<W>,
+}
+
+/// How far a `TarEntry` has got.
+#[derive(Debug)]
+enum EntryState<W> {
+    /// Nothing written yet.
+    Pending,
+    /// The header promising `size` bytes is written and the archive is ours
+    /// until the entry ends.
+    Writing {
+        output: OwnedMutexGuard<W>,
+        size: u64,
+        written: u64,
+    },
+    /// Waiting for the end of the entry, or for the archive to be free.
+    Buffered(Spool),
+    /// Finished, or failed before anything was written.
+    Done,
+}
+
+/// Bytes of an entry that is held back, in memory until there are more than
+/// `MEMORY_LIMIT` of them and then in a temporary file.
+#[derive(Debug, Default)]
+struct Spool {
+    chunks: Vec<Bytes>,
+    /// Bytes held, in memory or in the file.
+    len: u64,
+    /// The temporary file and its path, once the bytes outgrew memory.
+    file: Option<(rt::File, PathBuf)>,
+}
+impl Spool {
+    /// Hold `chunk` after the bytes already held.
+    async fn push(&mut self, chunk: Bytes) -> std::io::Result<()> {
+        self.len += chunk.len() as u64;
+        if self.file.is_none() && self.len > MEMORY_LIMIT as u64 {
+            let path = std::env::temp_dir().join(format!(
+                "download-tar-{}-{}.tmp",
+                std::process::id(),
+                SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut options = std::fs::OpenOptions::new();
+            options.read(true).write(true).create_new(true);
+            let file = rt::File::open_with(&path, &options).await?;
+            let (file, _) = self.file.get_or_insert((file, path));
+            for chunk in self.chunks.drain(..) {
+                file.write_all(&chunk).await?;
+            }
+        }
+        match &mut self.file {
+            Some((file, _)) => file.write_all(&chunk).await,
+            None => {
+                self.chunks.push(chunk);
+                Ok(())
+            }
+        }
+    }
+}
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Some((file, path)) = self.file.take() {
+            drop(file);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send + 'static> TarEntry<W> {
+    /// Header for this entry with `size` bytes.
+    fn header(&self, size: u64) -> Result<tar::Header, util::Error> {
+        let mut header = tar::Header::new_gnu();
+        header.set_path(&self.path).map_err(|e| self.entry_error(e))?;
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+        header.set_cksum();
+        Ok(header)
+    }
+
+    /// Error for a download that didn't match the size it promised.
+    fn size_error(&self, size: u64, actual: u64) -> util::Error {
+        util::Error {
+            what: format!(
+                "Expected {} bytes for {} in the archive but got {}.",
+                size,
+                self.path.display(),
+                actual
+            ),
+            source: None,
+            kind: util::ErrorKind::Integrity,
+        }
+    }
+
+    /// Take the archive for this entry and write a header promising `size`
+    /// bytes.
+    async fn start(&mut self, size: u64) -> Result<(), util::Error> {
+        let mut output = self.output.clone().lock_owned().await;
+        check_broken(&self.broken)?;
+        let header = self.header(size)?;
+        output.write_all(header.as_bytes()).await.map_err(write_error)?;
+        self.state = EntryState::Writing {
+            output,
+            size,
+            written: 0,
+        };
+        Ok(())
+    }
+
+    /// Write the entry held in the temporary `file` to the archive.
+    async fn copy(&mut self, file: &mut rt::File) -> Result<(), util::Error> {
+        let rewound = async {
+            file.flush().await?;
+            file.seek(std::io::SeekFrom::Start(0)).await
+        };
+        rewound.await.map_err(|e| self.entry_error(e))?;
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let n = file
+                .read(&mut buffer)
+                .await
+                .map_err(|e| self.entry_error(e))?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.write(Bytes::copy_from_slice(&buffer[..n])).await?;
+        }
+    }
+
+    /// Error for an entry that can't be stored in the archive.
+    fn entry_error(&self, error: std::io::Error) -> util::Error {
+        util::Error {
+            what: format!("Couldn't add {} to the archive.", self.path.display()),
+            source: Some(error.into()),
+            kind: util::ErrorKind::Other,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send + 'static> Sink for TarEntry<W> {
+    fn expect(&mut self, size: Option<u64>) {
+        self.size = size;
+    }
+
+    fn write(&mut self, chunk: Bytes) -> BoxFuture<'_, Result<(), util::Error>> {
+        Box::pin(async move {
+            if let EntryState::Pending = self.state {
+                self.state = match (self.size, self.output.try_lock_owned()) {
+                    (Some(size), Some(mut output)) => {
+                        check_broken(&self.broken)?;
+                        let header = self.header(size)?;
+                        output.write_all(header.as_bytes()).await.map_err(write_error)?;
+                        EntryState::Writing {
+                            output,
+                            size,
+                            written: 0,
+                        }
+                    }
+                    _ => EntryState::Buffered(Spool::default()),
+                };
+            }
+            match &mut self.state {
+                EntryState::Writing {
+                    output,
+                    size,
+                    written,
+                } => {
+                    let total = *written + chunk.len() as u64;
+                    if total > *size {
+                        let size = *size;
+                        return Err(self.size_error(size, total));
+                    }
+                    output.write_all(&chunk).await.map_err(write_error)?;
+                    *written = total;
+                }
+                EntryState::Buffered(spool) => {
+                    if let Err(e) = spool.push(chunk).await {
+                        return Err(self.entry_error(e));
+                    }
+                }
+                EntryState::Pending | EntryState::Done => {}
+            }
+            Ok(())
+        })
+    }
+
+    fn finish(&mut self) -> BoxFuture<'_, Result<(), util::Error>> {
+        Box::pin(async move {
+            match std::mem::replace(&mut self.state, EntryState::Done) {
+                state @ EntryState::Writing { .. } => self.state = state,
+                EntryState::Buffered(mut spool) => {
+                    let size = spool.len;
+                    if let Some(expected) = self.size.filter(|&expected| expected != size) {
+                        return Err(self.size_error(expected, size));
+                    }
+                    self.start(size).await?;
+                    for chunk in std::mem::take(&mut spool.chunks) {
+                        self.write(chunk).await?;
+                    }
+                    if let Some((file, _)) = &mut spool.file {
+                        self.copy(file).await?;
+                    }
+                }
+                EntryState::Pending => {
+                    if let Some(expected) = self.size.filter(|&expected| expected != 0) {
+                        return Err(self.size_error(expected, 0));
+                    }
+                    self.start(0).await?;
+                }
+                EntryState::Done => return Ok(()),
+            }
+            let (size, written) = match &self.state {
+                EntryState::Writing { size, written, .. } => (*size, *written),
+                _ => return Ok(()),
+            };
+            // The header promised more.  Zeros would pass for the missing
+            // bytes, so the archive is left broken instead.
+            if written < size {
+                self.break_archive();
+                self.state = EntryState::Done;
+                return Err(self.size_error(size, written));
+            }
+            // Entries fill whole blocks.
+            if let EntryState::Writing { output, .. } = &mut self.state {
+                let padding = (BLOCK - (written % BLOCK as u64) as usize) % BLOCK;
+                output.write_all(&[0; BLOCK][..padding]).await.map_err(write_error)?;
+            }
+            self.state = EntryState::Done;
+            Ok(())
+        })
+    }
+}
+impl<W> TarEntry<W> {
+    /// Record that this entry broke the archive.
+    fn break_archive(&self) {
+        self.broken
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| self.path.clone());
+    }
+}
+impl<W> Drop for TarEntry<W> {
+    fn drop(&mut self) {
+        // Part of the entry is in the archive, but not all of it.
+        if let EntryState::Writing { .. } = self.state {
+            self.break_archive();
+        }
+    }
+}
+
+/// Fail if an entry broke the archive.
+fn check_broken(broken: &Mutex<Option<PathBuf>>) -> Result<(), util::Error> {
+    match &*broken.lock().unwrap() {
+        Some(path) => Err(util::Error {
+            what: format!(
+                "The archive is unreadable past {}, which was cut short.",
+                path.display()
+            ),
+            source: None,
+            kind: util::ErrorKind::Other,
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Archive output that the test can look at afterwards.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl AsyncWrite for Shared {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn writes_entries_of_any_size() {
+        let shared = Shared::default();
+        let archive = TarArchive::new(shared.clone());
+        rt::block_on(async {
+            let mut streamed = archive.entry("streamed.txt");
+            streamed.expect(Some(5));
+            streamed.write(Bytes::from_static(b"hello")).await.unwrap();
+            streamed.finish().await.unwrap();
+            let mut buffered = archive.entry("buffered.txt");
+            buffered.write(Bytes::from_static(b"world")).await.unwrap();
+            buffered.finish().await.unwrap();
+            archive.finish().await.unwrap();
+        })
+        .unwrap();
+
+        let bytes = shared.0.lock().unwrap().clone();
+        let mut tar = tar::Archive::new(&bytes[..]);
+        let entries: Vec<_> = tar
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut content = String::new();
+                std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+                (entry.path().unwrap().display().to_string(), content)
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("streamed.txt".to_string(), "hello".to_string()),
+                ("buffered.txt".to_string(), "world".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn spools_large_waiting_entries_to_a_temporary_file() {
+        let shared = Shared::default();
+        let archive = TarArchive::new(shared.clone());
+        let large: Vec<u8> = (0..MEMORY_LIMIT + 3 * BLOCK).map(|i| i as u8).collect();
+        rt::block_on(async {
+            // The first entry holds the archive, so the second must wait.
+            let mut first = archive.entry("first.txt");
+            first.expect(Some(5));
+            first.write(Bytes::from_static(b"hello")).await.unwrap();
+            let mut second = archive.entry("second.bin");
+            for chunk in large.chunks(BUFFER_SIZE / 3) {
+                second.write(Bytes::copy_from_slice(chunk)).await.unwrap();
+            }
+            let spooled = match &second.state {
+                EntryState::Buffered(spool) => {
+                    assert!(spool.chunks.is_empty());
+                    spool.file.as_ref().unwrap().1.clone()
+                }
+                _ => panic!("the second entry should be waiting"),
+            };
+            assert!(spooled.exists());
+
+            first.finish().await.unwrap();
+            second.finish().await.unwrap();
+            archive.finish().await.unwrap();
+            assert!(!spooled.exists());
+        })
+        .unwrap();
+
+        let bytes = shared.0.lock().unwrap().clone();
+        let mut tar = tar::Archive::new(&bytes[..]);
+        let mut entries = tar.entries().unwrap();
+        entries.next().unwrap().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        let mut content = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut content).unwrap();
+        assert_eq!(content, large);
+    }
+
+    #[test]
+    fn breaks_the_archive_instead_of_padding_a_short_entry() {
+        let shared = Shared::default();
+        let archive = TarArchive::new(shared.clone());
+        rt::block_on(async {
+            let mut short = archive.entry("short.txt");
+            short.expect(Some(10));
+            short.write(Bytes::from_static(b"hell")).await.unwrap();
+            let error = short.finish().await.unwrap_err();
+            assert_eq!(error.kind, util::ErrorKind::Integrity);
+
+            let mut next = archive.entry("next.txt");
+            next.write(Bytes::from_static(b"world")).await.unwrap();
+            assert!(next.finish().await.is_err());
+            assert!(archive.finish().await.is_err());
+        })
+        .unwrap();
+        // Only the header and the bytes that arrived were written.
+        assert_eq!(shared.0.lock().unwrap().len(), BLOCK + 4);
+    }
+
+    #[test]
+    fn breaks_the_archive_when_an_entry_is_dropped_half_written() {
+        let archive = TarArchive::new(Shared::default());
+        rt::block_on(async {
+            let mut entry = archive.entry("dropped.txt");
+            entry.expect(Some(10));
+            entry.write(Bytes::from_static(b"hell")).await.unwrap();
+            drop(entry);
+            assert!(archive.finish().await.is_err());
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+//! Timeouts and stall detection for downloads.
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::fetch::Body;
+use crate::rt;
+
+/// How often a waiting `Watchdog` wakes up to check its limits.
+const TICK: Duration = Duration::from_millis(250);
+
+/// How long without data before a download is reported as stalled, unless the
+/// idle timeout is shorter.
+const STALLED_AFTER: Duration = Duration::from_secs(5);
+
+/// Limits on how long a download may wait for the server.  Every field
+/// defaults to `None`, meaning wait forever.  Example:
+///
+/// ```ignore
+/// let timeouts = Timeouts {
+///     connect: Some(Duration::from_secs(10)),
+///     idle: Some(Duration::from_secs(60)),
+///     min_speed: Some(MinSpeed { bytes_per_sec: 1024, window: Duration::from_secs(30) }),
+///     ..Default::default()
+/// };
+/// let client = timeouts.apply(Client::builder()).build()?;
+/// let mut download = timeouts.send(client.get(url)).await?;
+/// let mut watchdog = timeouts.watchdog();
+/// while let Some(chunk) = watchdog.chunk(&mut download, |stalled| ()).await? {
+///     outfile.write_all(&chunk).await?;
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timeouts {
+    /// Time allowed to establish a connection to the server.
+    pub connect: Option<Duration>,
+    /// Time allowed between sending a request and receiving the response
+    /// headers.
+    pub first_byte: Option<Duration>,
+    /// Time allowed between successive chunks of the body.
+    pub idle: Option<Duration>,
+    /// Minimum throughput of the body.
+    pub min_speed: Option<MinSpeed>,
+}
+
+/// Abort a download whose throughput stays below `bytes_per_sec` for a whole
+/// `window`, e.g. below 1KiB/s for 30 seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct MinSpeed {
+    /// Slowest acceptable throughput.
+    pub bytes_per_sec: u64,
+    /// How long the throughput may stay below `bytes_per_sec`.
+    pub window: Duration,
+}
+
+impl Timeouts {
+    /// Apply the connect timeout to a client under construction.
+    pub fn apply(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        match self.connect {
+            Some(connect) => builder.connect_timeout(connect),
+            None => builder,
+        }
+    }
+
+    /// Send `request`, waiting at most `first_byte` for the response headers.
+    pub async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, util::Error> {
+        let response = match self.first_byte {
+            Some(first_byte) => match rt::timeout(first_byte, request.send()).await {
+                Some(response) => response,
+                None => {
+                    return Err(timeout_error(format!(
+                        "No response from server within {} seconds.",
+                        first_byte.as_secs_f64()
+                    )))
+                }
+            },
+            None => request.send().await,
+        };
+        response.map_err(request_error)
+    }
+
+    /// Create a watchdog to enforce the idle timeout and minimum speed while
+    /// reading the body.
+    pub fn watchdog(&self) -> Watchdog {
+        Watchdog {
+            idle: self.idle,
+            min_speed: self.min_speed,
+            window_start: Instant::now(),
+            window_bytes: 0,
+            stalled: false,
+        }
+    }
+}
+
+/// Reads the body of a response chunk by chunk, failing with a timeout if the
+/// server stops sending or sends too slowly.
+#[derive(Debug)]
+pub struct Watchdog {
+    idle: Option<Duration>,
+    min_speed: Option<MinSpeed>,
+    /// Start of the current minimum speed window.
+    window_start: Instant,
+    /// Bytes received since `window_start`.
+    window_bytes: u64,
+    /// Have we told the caller the download is stalled?
+    stalled: bool,
+}
+impl Watchdog {
+    /// Wait for the next chunk of `response`, like `response.chunk()`.  Calls
+    /// `on_stall(true)` when no data has arrived for a few seconds and
+    /// `on_stall(false)` when data starts flowing again.
+    pub async fn chunk<F: FnMut(bool)>(
+        &mut self,
+        response: &mut reqwest::Response,
+        on_stall: F,
+    ) -> Result<Option<Bytes>, util::Error> {
+        let next = async { response.chunk().await.map_err(request_error) };
+        self.wait(next, on_stall).await
+    }
+
+    /// Wait for the next chunk of a `Fetcher`'s `body`, as for `chunk()`.
+    pub async fn body_chunk<F: FnMut(bool)>(
+        &mut self,
+        body: &mut Body,
+        on_stall: F,
+    ) -> Result<Option<Bytes>, util::Error> {
+        self.wait(body.chunk(), on_stall).await
+    }
+
+    /// Wait for `next` to yield the next chunk, enforcing our limits.
+    async fn wait<N, F>(&mut self, next: N, mut on_stall: F) -> Result<Option<Bytes>, util::Error>
+    where
+        N: Future<Output = Result<Option<Bytes>, util::Error>>,
+        F: FnMut(bool),
+    {
+        let waiting_since = Instant::now();
+        let stalled_after = match self.idle {
+            Some(idle) => STALLED_AFTER.min(idle / 2),
+            None => STALLED_AFTER,
+        };
+
+        // Wake up periodically while waiting so we can check our limits even
+        // if the server never sends another byte.
+        futures::pin_mut!(next);
+        let chunk = loop {
+            match rt::timeout(TICK, &mut next).await {
+                Some(chunk) => break chunk?,
+                None => {
+                    let waited = waiting_since.elapsed();
+                    if let Some(idle) = self.idle {
+                        if waited >= idle {
+                            return Err(timeout_error(format!(
+                                "No data received for {} seconds.",
+                                idle.as_secs_f64()
+                            )));
+                        }
+                    }
+                    if waited >= stalled_after && !self.stalled {
+                        self.stalled = true;
+                        on_stall(true);
+                    }
+                    self.check_speed()?;
+                }
+            }
+        };
+
+        if self.stalled {
+            self.stalled = false;
+            on_stall(false);
+        }
+        if let Some(chunk) = &chunk {
+            self.window_bytes += chunk.len() as u64;
+            self.check_speed()?;
+        }
+        Ok(chunk)
+    }
+
+    /// Fail if the throughput over a complete window is below the minimum,
+    /// otherwise start a new window once the current one is complete.
+    fn check_speed(&mut self) -> Result<(), util::Error> {
+        let min_speed = match self.min_speed {
+            Some(min_speed) => min_speed,
+            None => return Ok(()),
+        };
+        let elapsed = self.window_start.elapsed();
+        if elapsed < min_speed.window {
+            return Ok(());
+        }
+        let speed = self.window_bytes as f64 / elapsed.as_secs_f64();
+        if speed < min_speed.bytes_per_sec as f64 {
+            return Err(timeout_error(format!(
+                "Download slower than {} bytes/s for {} seconds.",
+                min_speed.bytes_per_sec,
+                min_speed.window.as_secs_f64()
+            )));
+        }
+        self.window_start = Instant::now();
+        self.window_bytes = 0;
+        Ok(())
+    }
+}
+
+/// Create a retryable timeout error.
+fn timeout_error(what: String) -> util::Error {
+    util::Error {
+        what,
+        source: None,
+        kind: util::ErrorKind::Timeout,
+    }
+}
+
+/// Wrap a `reqwest::Error`, classifying reqwest's own timeouts (such as the
+/// connect timeout) as timeouts, failed TLS handshakes as TLS errors, and
+/// failed or broken connections as network errors.
+pub(crate) fn request_error(error: reqwest::Error) -> util::Error {
+    if let Some(reason) = crate::tls::handshake_error(&error) {
+        let host = error.url().and_then(|url| url.host_str()).unwrap_or("the server");
+        return util::Error {
+            what: format!("Couldn't set up a secure connection to {}: {}.", host, reason),
+            source: Some(error.into()),
+            kind: util::ErrorKind::Tls,
+        };
+    }
+    let kind = if error.is_timeout() {
+        util::ErrorKind::Timeout
+    } else if error.is_connect() || error.is_request() || error.is_body() {
+        util::ErrorKind::Network
+    } else {
+        util::ErrorKind::Other
+    };
+    util::Error {
+        what: "Request failed.".to_string(),
+        source: Some(error.into()),
+        kind,
+    }
+}
@@ -0,0 +1,272 @@
+//! Content-addressable store that deduplicates downloads with identical
+//! content.
+//!
+//! Every finished download is filed in the store under its digest, e.g.
+//! `store/sha256/e3/b0c44298...`.  The first download with some content is
+//! linked into the store; later downloads with the same content are replaced
+//! by links to the stored copy, so the content takes up disk space only once.
+
+use std::path::{Path, PathBuf};
+
+use crate::{rt, Algorithm};
+
+/// How outputs are linked to the store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Link {
+    /// Hard links.  Outputs share their data with the store, so an output
+    /// must be replaced rather than modified in place.
+    Hard,
+    /// Copy-on-write clones where the file system supports them, such as
+    /// Btrfs and XFS on Linux, and hard links elsewhere.
+    Reflink,
+}
+
+/// Content-addressable store of downloaded files.  Example:
+///
+/// ```ignore
+/// let options = Options {
+///     store: Some(Arc::new(Store::open("store", Link::Reflink).await?)),
+///     ..Options::new(client)
+/// };
+/// ```
+#[derive(Debug)]
+pub struct Store {
+    dir: PathBuf,
+    link: Link,
+}
+impl Store {
+    /// Open the store in `dir`, creating it if need be.
+    pub async fn open(dir: impl Into<PathBuf>, link: Link) -> Result<Self, util::Error> {
+        let dir = dir.into();
+        rt::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| store_error(&dir, e))?;
+        Ok(Store { dir, link })
+    }
+
+    /// Directory holding the store.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// File the finished download at `path`, whose `algorithm` digest is
+    /// `digest`, in the store.  If the store already has the content, `path`
+    /// is replaced by a link to it.  Returns the number of bytes saved.
+    pub async fn add(
+        &self,
+        path: &Path,
+        algorithm: Algorithm,
+        digest: &[u8],
+    ) -> Result<u64, util::Error> {
+        let hex = hex::encode(digest);
+        let object = self
+            .dir
+            .join(algorithm.name())
+            .join(&hex[..2])
+            .join(&hex[2..]);
+        if let Some(parent) = object.parent() {
+            rt::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| store_error(&self.dir, e))?;
+        }
+
+        // New content is linked into the store.  If another download got
+        // there first, we share its copy instead.  Outputs that can't be
+        // linked, e.g. on another file system, are left out of the store.
+        match self.link(path, &object).await {
+            Ok(()) => return Ok(0),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) if unlinkable(&e) => return Ok(0),
+            Err(e) => return Err(store_error(&self.dir, e)),
+        }
+        let size = rt::fs::metadata(path)
+            .await
+            .map_err(|e| store_error(&self.dir, e))?
+            .len();
+        let stored = rt::fs::metadata(&object)
+            .await
+            .map_err(|e| store_error(&self.dir, e))?
+            .len();
+        if size != stored {
+            return Err(util::Error {
+                what: format!(
+                    "{} has the same {} digest as {} but a different size.",
+                    path.display(),
+                    algorithm.name(),
+                    object.display()
+                ),
+                source: None,
+                kind: util::ErrorKind::Integrity,
+            });
+        }
+        if same_file(path, &object).await {
+            return Ok(0);
+        }
+
+        // Link next to the output and rename over it, so the output is never
+        // missing.
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".dedup");
+        let temp = PathBuf::from(temp);
+        let _ = rt::fs::remove_file(&temp).await;
+        match self.link(&object, &temp).await {
+            Ok(()) => {}
+            Err(e) if unlinkable(&e) => return Ok(0),
+            Err(e) => return Err(store_error(&self.dir, e)),
+        }
+        rt::fs::rename(&temp, path)
+            .await
+            .map_err(|e| store_error(&self.dir, e))?;
+        Ok(size)
+    }
+
+    /// Make `to` a link to `from`.
+    async fn link(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        if self.link == Link::Reflink {
+            let (source, dest) = (from.to_owned(), to.to_owned());
+            match rt::fs::blocking(move || reflink(&source, &dest)).await {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Err(e),
+                Err(_) => {}
+            }
+        }
+        rt::fs::hard_link(from, to).await
+    }
+}
+
+/// Does `error` mean the file system can't link these files, so they are
+/// better left as they are?  Links can't cross file systems, some file
+/// systems don't support them at all, and files have a maximum number of
+/// links.  Permission problems are not among them: they mean the store is set
+/// up wrong, which the user should hear about.
+fn unlinkable(error: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        error.kind(),
+        ErrorKind::CrossesDevices
+            | ErrorKind::TooManyLinks
+            | ErrorKind::Unsupported
+    )
+}
+
+/// Are `a` and `b` hard links to the same file?
+#[cfg(unix)]
+async fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (rt::fs::metadata(a).await, rt::fs::metadata(b).await) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Are `a` and `b` hard links to the same file?  We can't tell here, so we
+/// link them again to be sure.
+#[cfg(not(unix))]
+async fn same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Create `to` as a copy-on-write clone of `from`.
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let source = std::fs::File::open(from)?;
+    let dest = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)?;
+    // Safety: both descriptors are open for the duration of the call.
+    if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == -1 {
+        let error = std::io::Error::last_os_error();
+        drop(dest);
+        let _ = std::fs::remove_file(to);
+        return Err(error);
+    }
+    Ok(())
+}
+
+/// Copy-on-write clones are only implemented on Linux.
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "copy-on-write clones are not supported on this platform",
+    ))
+}
+
+/// Error for a problem with the store in `dir`.
+fn store_error(dir: &Path, error: std::io::Error) -> util::Error {
+    util::Error {
+        what: format!("Couldn't update store {}.", dir.display()),
+        source: Some(error.into()),
+        kind: util::ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory for the test called `name`.
+    fn scratch(parent: &Path, name: &str) -> PathBuf {
+        let dir = parent.join(format!("download-store-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn replaces_identical_files_with_links() {
+        let dir = scratch(&std::env::temp_dir(), "dedup");
+        let (a, b) = (dir.join("a.bin"), dir.join("b.bin"));
+        std::fs::write(&a, b"same content").unwrap();
+        std::fs::write(&b, b"same content").unwrap();
+        let digest = [0x5a; 32];
+        let saved = rt::block_on(async {
+            let store = Store::open(dir.join("store"), Link::Hard).await.unwrap();
+            assert_eq!(store.add(&a, Algorithm::Sha256, &digest).await.unwrap(), 0);
+            store.add(&b, Algorithm::Sha256, &digest).await.unwrap()
+        })
+        .unwrap();
+        assert_eq!(saved, 12);
+        assert!(rt::block_on(same_file(&a, &b)).unwrap());
+        assert_eq!(std::fs::read(&b).unwrap(), b"same content");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn leaves_files_on_another_file_system_alone() {
+        // Needs a store on a different file system than the output.
+        use std::os::unix::fs::MetadataExt;
+        let shm = Path::new("/dev/shm");
+        match (std::fs::metadata(shm), std::fs::metadata(std::env::temp_dir())) {
+            (Ok(shm), Ok(temp)) if shm.dev() != temp.dev() => {}
+            _ => return,
+        }
+        let dir = scratch(&std::env::temp_dir(), "cross-device");
+        let store_dir = scratch(shm, "cross-device-store");
+        let output = dir.join("a.bin");
+        std::fs::write(&output, b"content").unwrap();
+        for link in [Link::Hard, Link::Reflink] {
+            let saved = rt::block_on(async {
+                let store = Store::open(&store_dir, link).await.unwrap();
+                store.add(&output, Algorithm::Sha256, &[0xa5; 32]).await
+            })
+            .unwrap();
+            assert_eq!(saved.unwrap(), 0);
+        }
+        assert_eq!(std::fs::read(&output).unwrap(), b"content");
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&store_dir).unwrap();
+    }
+
+    #[test]
+    fn reports_permission_problems() {
+        use std::io::{Error, ErrorKind};
+        assert!(unlinkable(&Error::from(ErrorKind::CrossesDevices)));
+        assert!(unlinkable(&Error::from(ErrorKind::Unsupported)));
+        assert!(!unlinkable(&Error::from(ErrorKind::PermissionDenied)));
+    }
+}
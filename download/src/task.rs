@@ -0,0 +1,1324 @@
+//! Downloading a `Request`, failing over between mirrors.
+
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::Url;
+
+use crate::auth::Credentials;
+use crate::cache::{Cache, Hit};
+use crate::cookies::CookieJar;
+use crate::extract::{self, Format};
+use crate::fetch::{self, Fetcher, Metadata, Range};
+use crate::journal::{Journal, Record, Status};
+use crate::proxy::Proxies;
+use crate::rt;
+use crate::sink::Sink;
+use crate::store::Store;
+use crate::{Algorithm, Compression, Decoder, Hasher, Pieces, Progress, Request, Timeouts};
+use crate::{TokenBucket, Watchdog, WriteStats, Writer};
+
+/// How many times to re-download corrupt pieces before giving up.
+const MAX_REPAIRS: usize = 3;
+
+/// Settings shared by every download in a batch.  Example:
+///
+/// ```ignore
+/// let options = Options {
+///     segments: 4,
+///     ..Options::new(timeouts.apply(Client::builder()).build()?)
+/// };
+/// let outcome = download::download(&request, &options, &progress_bar).await?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Client used for every HTTP request.  Sharing one client between
+    /// downloads lets them share connections.
+    pub client: reqwest::Client,
+    /// Timeouts for every request.  The minimum speed doesn't apply to
+    /// downloads that `limits` or `limit_each` keep below it.
+    pub timeouts: Timeouts,
+    /// Credentials for servers that require authentication.
+    pub credentials: Option<Arc<Credentials>>,
+    /// Cookies shared by every HTTP request.  The client must be set up with
+    /// `CookieJar::apply()`: a client that follows redirects itself drops the
+    /// cookies set on them, since the jar never sees those responses.
+    /// reqwest can't tell us how a client was set up, so this isn't checked.
+    pub cookies: Option<Arc<CookieJar>>,
+    /// Proxies the client was set up with, so outcomes can say which ones
+    /// were used.
+    pub proxies: Option<Arc<Proxies>>,
+    /// Fetchers for URL schemes, in addition to or in place of the built-in
+    /// ones for `http`, `https`, `file`, and `data`.
+    pub fetchers: HashMap<String, Arc<dyn Fetcher>>,
+    /// Bandwidth limits shared with other downloads.
+    pub limits: Vec<Arc<TokenBucket>>,
+    /// Bandwidth limit in bytes per second for each download on its own.
+    pub limit_each: Option<u64>,
+    /// Number of byte ranges to fetch from different mirrors at the same
+    /// time.  With 1, or if the mirrors disagree about the file, the download
+    /// is fetched from one mirror at a time.
+    pub segments: usize,
+    /// Journal recording the progress of every download, so a batch that is
+    /// run again skips finished files and resumes unfinished ones.
+    pub journal: Option<Arc<Journal>>,
+    /// Set the modification time of each finished file from the server's
+    /// `Last-Modified` header.
+    pub remote_time: bool,
+    /// Cache that fresh downloads are served from and finished downloads are
+    /// stored in.
+    pub cache: Option<Arc<Cache>>,
+    /// Only serve downloads from `cache`, fresh or not, and never use the
+    /// network.
+    pub offline: bool,
+    /// Content-addressable store that finished downloads are filed in, so
+    /// downloads with identical content share disk space.
+    pub store: Option<Arc<Store>>,
+    /// Whether to ask for compressed transfer, and whether to decode
+    /// compressed responses.
+    pub compression: Compression,
+    /// How often to try a mirror again after a timeout, a broken connection,
+    /// or a short body before moving on to the next mirror.
+    pub retries: Retries,
+}
+impl Options {
+    /// Download with `client` one mirror at a time, without limits or
+    /// timeouts.
+    pub fn new(client: reqwest::Client) -> Self {
+        Options {
+            client,
+            timeouts: Timeouts::default(),
+            credentials: None,
+            cookies: None,
+            proxies: None,
+            fetchers: HashMap::new(),
+            limits: Vec::new(),
+            limit_each: None,
+            segments: 1,
+            journal: None,
+            remote_time: false,
+            cache: None,
+            offline: false,
+            store: None,
+            compression: Compression::default(),
+            retries: Retries::default(),
+        }
+    }
+
+    /// Fetcher for `url`, chosen by its scheme.
+    pub fn fetcher(&self, url: &Url) -> Result<Arc<dyn Fetcher>, util::Error> {
+        let scheme = url.scheme();
+        match self.fetchers.get(scheme) {
+            Some(fetcher) => Ok(fetcher.clone()),
+            None => fetch::builtin(scheme, self).ok_or_else(|| util::Error {
+                what: format!("Can't download {}, there is no fetcher for {}: URLs.", url, scheme),
+                source: None,
+                kind: util::ErrorKind::Other,
+            }),
+        }
+    }
+}
+
+/// How often to try the same mirror again after an error that may go away,
+/// continuing from where it stopped.  The wait before each retry is twice the
+/// wait before the one before it.  Example:
+///
+/// ```ignore
+/// let options = Options {
+///     retries: Retries { attempts: 5, delay: Duration::from_millis(500) },
+///     ..Options::new(client)
+/// };
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Retries {
+    /// Attempts after the first one, for each mirror.
+    pub attempts: u32,
+    /// Wait before the first retry.
+    pub delay: Duration,
+}
+impl Retries {
+    /// Never try a mirror again, move on to the next one straight away.
+    pub fn none() -> Self {
+        Retries {
+            attempts: 0,
+            delay: Duration::from_secs(0),
+        }
+    }
+}
+impl Default for Retries {
+    /// Two retries, after one and two seconds.
+    fn default() -> Self {
+        Retries {
+            attempts: 2,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// What a successful download did.
+#[derive(Clone, Debug)]
+pub struct Outcome {
+    /// Size of the output file.
+    pub bytes: u64,
+    /// Bytes received from the network, which is fewer than `bytes` if the
+    /// server compressed the file.
+    pub received: u64,
+    /// How fast the bytes were written to disk.
+    pub disk: WriteStats,
+    /// Mirrors that served (part of) the file, in the order they were used.
+    pub mirrors: Vec<Url>,
+    /// The configured route to the mirrors: the proxies `options.proxies`
+    /// picks for them, without their credentials, in the order the mirrors
+    /// were first used.  Empty if every mirror is reached directly.  reqwest
+    /// doesn't say which proxy it actually used, so this is what it was told.
+    pub proxies: Vec<Url>,
+    /// Was the file from an earlier run still up to date, so nothing was
+    /// downloaded?
+    pub up_to_date: bool,
+    /// Was the file copied from the cache instead of downloaded?
+    pub cached: bool,
+    /// Bytes of disk space saved by linking the file to identical content in
+    /// the store.
+    pub saved: u64,
+    /// Was the file extracted?  A file extracted as it downloaded was never
+    /// written to disk itself.
+    pub extracted: bool,
+}
+
+/// Where a download goes instead of its output file.
+enum Stream {
+    /// Extracted as it arrives into a staging directory, which is moved into
+    /// place once the download has been verified.
+    Unpack(Format, PathBuf),
+    /// Into a sink.
+    Sink(Box<dyn Sink>),
+}
+
+/// State shared by every range of one download.
+struct Transfer<'a, P> {
+    request: &'a Request,
+    options: &'a Options,
+    progress: &'a P,
+    limits: Vec<Arc<TokenBucket>>,
+    filename: String,
+    /// Size of the whole file, once known.
+    size: Option<u64>,
+    /// Record the offset in the journal as bytes arrive?
+    checkpoint: bool,
+    /// `If-None-Match` and `If-Modified-Since` headers for probing a file we
+    /// already have.
+    conditional: HeaderMap,
+    /// Bytes received from the network so far.
+    received: AtomicU64,
+    /// What the mirror that answered first said about the file.  Other
+    /// mirrors must agree with it to continue a download from the middle.
+    probed: Option<Metadata>,
+    /// Mirrors known to serve the file that was probed.
+    verified: Mutex<Vec<Url>>,
+    /// The first mirror found serving a different file, and what it said
+    /// about it.
+    changed: Mutex<Option<(Url, Metadata)>>,
+    /// Headers of the last response that sent part of the body, which decide
+    /// whether and how long the file is cached.
+    response: Mutex<Option<HeaderMap>>,
+}
+
+/// Download `request` into its output file, reporting progress to `progress`.
+///
+/// The request's URL is tried first, then each of its mirrors.  If a mirror
+/// fails part way through, the download continues from the same byte offset
+/// on the next mirror that serves the same file, with the same size and
+/// `ETag`, or `Last-Modified` date if there is no `ETag`.  If the mirrors left
+/// serve a different file, the download starts over on one of them.  With
+/// `options.segments` greater than 1, different byte ranges are fetched from
+/// different mirrors at the same time, as long as the mirrors agree on the
+/// size and `ETag` of the file.  The size and checksum of the finished file
+/// are checked against the request, if given.  Pieces that don't match the
+/// request's piece hashes are downloaded again.
+///
+/// With `options.journal`, the download is recorded as it progresses.  A file
+/// the journal says is complete is only downloaded again if it changed on the
+/// server, or changed size if the server sends neither an `ETag` nor a
+/// `Last-Modified` date.  An unfinished one is resumed if the server still
+/// has the same file.
+///
+/// With `options.cache`, a fresh copy of the file in the cache is used instead
+/// of downloading it, and a downloaded file is stored in the cache if the
+/// server allows it.  With `options.offline`, the file comes from the cache or
+/// not at all.
+///
+/// With `options.store`, the finished file is filed in the store by its
+/// digest, and replaced by a link if the store already has the same content.
+///
+/// A response compressed with a `Content-Encoding` is decoded as it arrives,
+/// unless `options.compression` says to keep it as it is.  The progress
+/// reported is then the compressed bytes received.
+///
+/// With `request.extract`, the file is extracted into that directory.  Tar
+/// archives are extracted as they download, without writing the archive,
+/// unless it has pieces to check or is needed for the cache or the store.
+/// They are unpacked into a staging directory next to `request.extract` and
+/// only moved into place once their size and checksum have been verified.
+pub async fn download<P>(
+    request: &Request,
+    options: &Options,
+    progress: &P,
+) -> Result<Outcome, util::Error>
+where
+    P: Progress + Sync,
+{
+    let result = match transfer(request, options, progress, None).await {
+        Ok(outcome) => extract_after(request, progress, outcome).await,
+        Err(e) => Err(e),
+    };
+    if let Err(error) = &result {
+        // Whatever was unpacked from an archive that failed can't be trusted.
+        if let Some(staging) = request.extract.as_deref().and_then(extract::staging) {
+            let _ = rt::fs::remove_dir_all(staging).await;
+        }
+        // The download's own error says more than a failure to record it.
+        if let Some(journal) = &options.journal {
+            let _ = journal.fail(&request.output, error).await;
+        }
+    }
+    result
+}
+
+/// Download `request` into `sink` instead of its output file, reporting
+/// progress to `progress`.  The download is fetched in order, failing over
+/// between mirrors as for `download()`, and its size and checksum are checked
+/// once the sink has received it.  It is not resumed, cached, filed in the
+/// store, extracted, or recorded in the journal.  Example:
+///
+/// ```ignore
+/// let memory = sink::Memory::new(64 * 1024);
+/// download::download_to(&request, &options, &progress_bar, memory.clone()).await?;
+/// let body = memory.contents();
+/// ```
+pub async fn download_to<P, S>(
+    request: &Request,
+    options: &Options,
+    progress: &P,
+    sink: S,
+) -> Result<Outcome, util::Error>
+where
+    P: Progress + Sync,
+    S: Sink + 'static,
+{
+    transfer(request, options, progress, Some(Box::new(sink))).await
+}
+
+/// Download `request` as described for `download()`, or into `sink` as
+/// described for `download_to()`.
+async fn transfer<P>(
+    request: &Request,
+    options: &Options,
+    progress: &P,
+    sink: Option<Box<dyn Sink>>,
+) -> Result<Outcome, util::Error>
+where
+    P: Progress + Sync,
+{
+    let mut transfer = Transfer {
+        request,
+        options,
+        progress,
+        // Each download gets its own bandwidth limit in addition to the
+        // limits shared by all downloads.
+        limits: options
+            .limits
+            .iter()
+            .cloned()
+            .chain(options.limit_each.map(|rate| Arc::new(TokenBucket::new(rate))))
+            .collect(),
+        filename: request.output.display().to_string(),
+        size: None,
+        checkpoint: false,
+        conditional: HeaderMap::new(),
+        received: AtomicU64::new(0),
+        probed: None,
+        verified: Mutex::new(Vec::new()),
+        changed: Mutex::new(None),
+        response: Mutex::new(None),
+    };
+    progress.set_status(&transfer.filename);
+
+    // Tar archives are extracted as they arrive, so the archive is never
+    // written, unless the finished archive is needed for something else.
+    let stream = match (sink, &request.extract) {
+        (Some(sink), _) => Some(Stream::Sink(sink)),
+        (None, Some(dest)) => {
+            let format = archive_format(request)?;
+            let needed = request.pieces.is_some() || options.cache.is_some() || options.store.is_some();
+            match extract::staging(dest) {
+                Some(staging) if format.streams() && !needed => Some(Stream::Unpack(format, staging)),
+                _ => None,
+            }
+        }
+        (None, None) => None,
+    };
+    let to_file = stream.is_none();
+    let to_sink = matches!(stream, Some(Stream::Sink(_)));
+    let staging = match &stream {
+        Some(Stream::Unpack(_, staging)) => Some(staging.clone()),
+        _ => None,
+    };
+    let extracted = staging.is_some();
+
+    // Only files are cached and filed in the store.
+    let cache = options.cache.as_ref().filter(|_| to_file);
+    let store = options.store.as_ref().filter(|_| to_file);
+
+    // Use the cached copy if it is fresh, or whatever copy there is offline.
+    match cache {
+        Some(cache) => match cache.lookup(&request.url, &transfer.headers(false)).await? {
+            Some(hit) if hit.fresh || options.offline => {
+                return from_cache(request, progress, &hit).await;
+            }
+            _ if options.offline => {
+                return Err(offline_error(&format!("{} is not in the cache", request.url)));
+            }
+            _ => {}
+        },
+        None if options.offline => {
+            let problem = format!("there is no cache to serve {} from", request.url);
+            return Err(offline_error(&problem));
+        }
+        None => {}
+    }
+
+    // A file an earlier run finished, and that is still there, is only
+    // downloaded again if it changed on the server since.
+    let journal = options.journal.as_deref().filter(|_| !to_sink);
+    let previous = match journal {
+        Some(journal) => journal.get(&request.output).await,
+        None => None,
+    };
+    let on_disk = rt::fs::metadata(&request.output).await.ok().map(|meta| meta.len());
+    // An archive extracted as it arrived was never written, so the directory
+    // it was extracted into stands in for it.
+    let extracted_before = match (&request.extract, extracted) {
+        (Some(dest), true) => rt::fs::metadata(dest).await.is_ok(),
+        _ => false,
+    };
+    let finished = previous.as_ref().filter(|previous| {
+        previous.status == Status::Complete
+            && previous.url == request.url
+            && (on_disk == Some(previous.offset) || extracted_before)
+    });
+    if let Some(previous) = finished {
+        if let Some(etag) = previous.etag.as_ref().and_then(|etag| etag.parse().ok()) {
+            transfer.conditional.insert(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(date) = previous.last_modified.as_ref().and_then(|date| date.parse().ok()) {
+            transfer.conditional.insert(header::IF_MODIFIED_SINCE, date);
+        }
+    }
+
+    // We need to determine the file size before we download, so we can report
+    // the total.  Mirrors that don't answer are skipped.
+    let urls: Vec<&Url> = std::iter::once(&request.url).chain(&request.mirrors).collect();
+    let (first, mut probe) = transfer.probe_any(&urls).await?;
+    if let Some(previous) = finished {
+        // Without validators we couldn't ask, so the file counts as unchanged
+        // if it is still the same size.
+        let unchanged = if transfer.conditional.is_empty() {
+            probe.size.is_some() && probe.size == previous.size
+        } else {
+            probe.not_modified
+        };
+        if unchanged {
+            return Ok(up_to_date(progress, previous.offset));
+        }
+    }
+    transfer.conditional.clear();
+    transfer.probed = Some(probe.clone());
+    transfer.verified.lock().unwrap().push(urls[first].clone());
+
+    // The Content-Length of a compressed file we decode is the size on the
+    // wire, which is what the progress bar counts, not the size of the
+    // output.  Ranges of it are no use to us, so it can't be resumed or
+    // split.
+    let decoding = probe.encoding.is_some() && options.compression != Compression::Preserve;
+    let size = if decoding { request.size } else { probe.size.or(request.size) };
+    transfer.size = size;
+    if let Some(total) = probe.size.or(size) {
+        progress.set_total(total);
+    }
+
+    // Resume an unfinished download if the server still has the same file.
+    // Bytes the journal counted may not have reached the disk, so we go by
+    // whichever is shorter.  An archive extracted as it arrives starts over.
+    let resume = match (&previous, on_disk) {
+        (Some(previous), Some(on_disk))
+            if to_file
+                && !decoding
+                && previous.status != Status::Complete
+                && previous.url == request.url
+                && previous.size == size
+                && same_file(previous, &probe)
+                && probe.ranges =>
+        {
+            previous.offset.min(on_disk)
+        }
+        _ => 0,
+    };
+    // With nothing left to fetch there would be no range to ask for.
+    let mut resume = if Some(resume) == size { 0 } else { resume };
+    if let Some(journal) = journal {
+        let record = Record {
+            url: request.url.clone(),
+            status: Status::Partial,
+            etag: probe.etag.clone(),
+            last_modified: probe.last_modified.clone(),
+            size,
+            offset: resume,
+            error: None,
+        };
+        journal.record(&request.output, record).await?;
+    }
+
+    // Prefer the mirror that answered first, then the others in order.
+    let mut urls = urls;
+    urls.rotate_left(first);
+
+    // We need a digest to check the checksum or to file the download in the
+    // store.  The store uses the checksum's algorithm if there is one.
+    let algorithm = match (&request.checksum, store) {
+        (Some(checksum), _) => Some(checksum.algorithm),
+        (None, Some(_)) => Some(Algorithm::Sha256),
+        (None, None) => None,
+    };
+
+    let (disk, mut mirrors, digest) = if let Some(stream) = stream {
+        // Nothing is written to the output file, so there is nothing to
+        // resume and the download must arrive in order.
+        let mut hasher = algorithm.map(|algorithm| algorithm.hasher());
+        let mut writer = match stream {
+            Stream::Unpack(format, staging) => {
+                // Leftovers of an earlier attempt would end up in the output.
+                let _ = rt::fs::remove_dir_all(&staging).await;
+                extract::unpacker(format, staging)
+            }
+            Stream::Sink(mut sink) => {
+                sink.expect(size);
+                Writer::for_sink(sink)
+            }
+        };
+        let mirrors = transfer.fetch_range(&urls, 0, size, &mut writer, hasher.as_mut()).await?;
+        (writer.finish().await?, mirrors, hasher.map(Hasher::finish))
+    } else {
+        // Create the output file and any missing directories, or cut a
+        // partial file back to the part we are keeping.
+        if let Some(parent) = request.output.parent() {
+            rt::fs::create_dir_all(parent).await.map_err(|e| file_error(&request.output, e))?;
+        }
+        let mut file = if resume > 0 {
+            let mut file = transfer.open_at(resume).await?;
+            file.set_len(resume).await.map_err(|e| file_error(&request.output, e))?;
+            progress.advance(resume);
+            file
+        } else {
+            remove_output(&request.output).await?;
+            rt::File::create(&request.output)
+                .await
+                .map_err(|e| file_error(&request.output, e))?
+        };
+
+        // Find mirrors that agree with the first one, if we are going to
+        // split the download between them.
+        let agreeing = match size {
+            Some(size)
+                if options.segments > 1
+                    && urls.len() > 1
+                    && probe.ranges
+                    && resume == 0
+                    && !decoding =>
+            {
+                transfer.agreeing(&urls, size, &probe).await
+            }
+            _ => Vec::new(),
+        };
+
+        if agreeing.len() > 1 {
+            let size = size.unwrap_or(0);
+            file.set_len(size).await.map_err(|e| file_error(&request.output, e))?;
+            drop(file);
+            let (disk, mirrors) = transfer.segmented(&agreeing, size).await?;
+            // The ranges arrived out of order, so the finished file is hashed
+            // below instead.
+            (disk, mirrors, None)
+        } else {
+            let mut file = file;
+            let mut restarts = 0;
+            loop {
+                // Hash the download as it arrives if we need the digest and
+                // are starting from the beginning.  Only a download written
+                // in order can be resumed, so only it records its progress in
+                // the journal.
+                let mut hasher = match algorithm {
+                    Some(algorithm) if resume == 0 => Some(algorithm.hasher()),
+                    _ => None,
+                };
+                let mut writer = Writer::spawn(file);
+                transfer.checkpoint = true;
+                let result =
+                    transfer.fetch_range(&urls, resume, transfer.size, &mut writer, hasher.as_mut()).await;
+                transfer.checkpoint = false;
+                let changed = transfer.changed.lock().unwrap().take();
+                let (url, changed) = match (result, changed) {
+                    (Ok(mirrors), _) => break (writer.finish().await?, mirrors, hasher.map(Hasher::finish)),
+                    (Err(_), Some(changed)) if !writer.has_failed() && restarts < urls.len() => changed,
+                    (Err(e), _) => return Err(e),
+                };
+
+                // The mirrors that are left serve a different file, e.g. a
+                // newer version, so what we have is no use to them.  We start
+                // over on the first of them.
+                writer.finish().await?;
+                restarts += 1;
+                file = rt::File::create(&request.output)
+                    .await
+                    .map_err(|e| file_error(&request.output, e))?;
+                let i = urls.iter().position(|u| **u == url).unwrap_or(0);
+                urls.rotate_left(i);
+                transfer.size = if decoding { request.size } else { changed.size.or(request.size) };
+                progress.restart(changed.size.or(transfer.size).unwrap_or(0));
+                transfer.probed = Some(changed.clone());
+                *transfer.verified.lock().unwrap() = vec![url.clone()];
+                probe = changed;
+                resume = 0;
+                if let Some(journal) = journal {
+                    let record = Record {
+                        url: request.url.clone(),
+                        status: Status::Partial,
+                        etag: probe.etag.clone(),
+                        last_modified: probe.last_modified.clone(),
+                        size: transfer.size,
+                        offset: 0,
+                        error: None,
+                    };
+                    journal.record(&request.output, record).await?;
+                }
+            }
+        }
+    };
+    let bytes = resume + disk.bytes;
+
+    // Re-download any pieces that don't match their hashes.  The file changed,
+    // so a digest computed while downloading no longer counts.
+    let mut digest = digest;
+    if let (Some(pieces), true) = (&request.pieces, to_file) {
+        if !pieces.fits(bytes) {
+            return Err(util::Error {
+                what: format!(
+                    "{} has {} bytes, which don't fit its {} piece hashes of {} bytes.",
+                    transfer.filename,
+                    bytes,
+                    pieces.digests.len(),
+                    pieces.length
+                ),
+                source: None,
+                kind: util::ErrorKind::Integrity,
+            });
+        }
+        let repaired = transfer.repair(&urls, pieces, bytes).await?;
+        if !repaired.is_empty() {
+            digest = None;
+        }
+        for url in repaired {
+            if !mirrors.contains(&url) {
+                mirrors.push(url);
+            }
+        }
+    }
+    let digest = match (algorithm, digest) {
+        (Some(_), Some(digest)) => Some(digest),
+        (Some(algorithm), None) => Some(hash_file(&request.output, algorithm.hasher()).await?),
+        (None, _) => None,
+    };
+    if let (Some(checksum), Some(digest)) = (&request.checksum, &digest) {
+        checksum.verify(digest)?;
+    }
+
+    // Make sure we got the file the request promised.
+    if let Some(size) = request.size {
+        if bytes != size {
+            return Err(util::Error {
+                what: format!(
+                    "Expected {} bytes for {} but got {}.",
+                    size, transfer.filename, bytes
+                ),
+                source: None,
+                kind: util::ErrorKind::Integrity,
+            });
+        }
+    }
+    // The archive checks out, so what was extracted from it can be used.
+    if let (Some(staging), Some(dest)) = (&staging, &request.extract) {
+        extract::move_into(staging, dest).await?;
+    }
+    if let Some(cache) = cache {
+        // Go by the response that sent the body, or by the probe for
+        // transports without response headers.
+        let headers = transfer.headers(false);
+        let response = transfer.response.lock().unwrap().take().unwrap_or_else(|| probe.headers.clone());
+        cache.store(&request.url, &headers, &response, &request.output).await?;
+    }
+    let saved = match (store, algorithm, &digest) {
+        (Some(store), Some(algorithm), Some(digest)) => {
+            store.add(&request.output, algorithm, digest).await?
+        }
+        _ => 0,
+    };
+    if options.remote_time && to_file {
+        if let Some(date) = &probe.last_modified {
+            set_modified(&request.output, date)?;
+        }
+    }
+    if let Some(journal) = journal {
+        let record = Record {
+            url: request.url.clone(),
+            status: Status::Complete,
+            etag: probe.etag,
+            last_modified: probe.last_modified,
+            size: Some(bytes),
+            offset: bytes,
+            error: None,
+        };
+        journal.record(&request.output, record).await?;
+    }
+    progress.set_status(&transfer.filename);
+
+    // The configured route, which is what reqwest was told to use.
+    let mut proxies = Vec::new();
+    for proxy in mirrors.iter().filter_map(|url| options.proxies.as_ref()?.proxy_for(url)) {
+        if !proxies.contains(&proxy) {
+            proxies.push(proxy);
+        }
+    }
+    Ok(Outcome {
+        bytes,
+        received: transfer.received.load(Ordering::Relaxed),
+        disk,
+        mirrors,
+        proxies,
+        up_to_date: false,
+        cached: false,
+        saved,
+        extracted,
+    })
+}
+
+/// Copy the cached `hit` for `request` into its output file and check it like
+/// a download.
+async fn from_cache<P: Progress>(
+    request: &Request,
+    progress: &P,
+    hit: &Hit,
+) -> Result<Outcome, util::Error> {
+    progress.set_total(hit.size);
+    if let Some(parent) = request.output.parent() {
+        rt::fs::create_dir_all(parent).await.map_err(|e| file_error(&request.output, e))?;
+    }
+    let started = std::time::Instant::now();
+    remove_output(&request.output).await?;
+    let bytes = rt::fs::copy(&hit.path, &request.output)
+        .await
+        .map_err(|e| file_error(&request.output, e))?;
+    progress.advance(bytes);
+    let disk = WriteStats {
+        bytes,
+        elapsed: started.elapsed(),
+    };
+    if let Some(checksum) = &request.checksum {
+        checksum.verify(&hash_file(&request.output, checksum.hasher()).await?)?;
+    }
+    if let Some(size) = request.size {
+        if bytes != size {
+            return Err(util::Error {
+                what: format!(
+                    "Expected {} bytes for {} but the cache has {}.",
+                    size,
+                    request.output.display(),
+                    bytes
+                ),
+                source: None,
+                kind: util::ErrorKind::Integrity,
+            });
+        }
+    }
+    Ok(Outcome {
+        bytes,
+        received: 0,
+        disk,
+        mirrors: Vec::new(),
+        proxies: Vec::new(),
+        up_to_date: false,
+        cached: true,
+        saved: 0,
+        extracted: false,
+    })
+}
+
+/// Extract the finished download of `request` into its `extract` directory,
+/// unless it was extracted as it downloaded.  A file that was up to date was
+/// extracted by the run that downloaded it, unless the directory is gone.
+async fn extract_after<P: Progress>(
+    request: &Request,
+    progress: &P,
+    mut outcome: Outcome,
+) -> Result<Outcome, util::Error> {
+    let dest = match &request.extract {
+        Some(dest) => dest,
+        None => return Ok(outcome),
+    };
+    if outcome.extracted || (outcome.up_to_date && dest.exists()) {
+        return Ok(outcome);
+    }
+    let filename = request.output.display().to_string();
+    progress.set_status(&format!("{} (extracting)", filename));
+    extract::extract_file(&request.output, archive_format(request)?, dest, progress).await?;
+    progress.set_status(&filename);
+    outcome.extracted = true;
+    Ok(outcome)
+}
+
+/// Archive format of the output file of `request`.
+fn archive_format(request: &Request) -> Result<Format, util::Error> {
+    Format::from_path(&request.output).ok_or_else(|| util::Error {
+        what: format!(
+            "Can't extract {}, expected a .tar.gz, .tgz, .tar.zst, .tzst, or .zip extension.",
+            request.output.display()
+        ),
+        source: None,
+        kind: util::ErrorKind::Other,
+    })
+}
+
+/// Outcome for a file of `bytes` bytes that was already up to date.
+fn up_to_date<P: Progress>(progress: &P, bytes: u64) -> Outcome {
+    progress.set_total(bytes);
+    progress.advance(bytes);
+    Outcome {
+        bytes,
+        received: 0,
+        disk: WriteStats {
+            bytes: 0,
+            elapsed: Default::default(),
+        },
+        mirrors: Vec::new(),
+        proxies: Vec::new(),
+        up_to_date: true,
+        cached: false,
+        saved: 0,
+        extracted: false,
+    }
+}
+
+/// Does `probe` describe the same file as `previous`?  The `ETag` decides if
+/// both have one, otherwise the `Last-Modified` date.
+fn same_file(previous: &Record, probe: &Metadata) -> bool {
+    match (&previous.etag, &probe.etag) {
+        (Some(a), Some(b)) => a == b,
+        (None, None) => {
+            previous.last_modified.is_some() && previous.last_modified == probe.last_modified
+        }
+        _ => false,
+    }
+}
+
+impl<'a, P: Progress + Sync> Transfer<'a, P> {
+    /// Check each of `pieces` in the `size` bytes of the output file and
+    /// re-download those that don't match, trying a different mirror first in
+    /// each round.  Returns the mirrors used for the repair.
+    async fn repair(&self, urls: &[&Url], pieces: &Pieces, size: u64) -> Result<Vec<Url>, util::Error> {
+        let mut used = Vec::new();
+        let mut total = size;
+        for round in 0..=MAX_REPAIRS {
+            let corrupt = corrupt_pieces(&self.request.output, pieces, size).await?;
+            if corrupt.is_empty() {
+                return Ok(used);
+            }
+            if round == MAX_REPAIRS {
+                return Err(util::Error {
+                    what: format!(
+                        "{} of {} pieces of {} are still corrupt after {} repairs.",
+                        corrupt.len(),
+                        pieces.digests.len(),
+                        self.filename,
+                        MAX_REPAIRS
+                    ),
+                    source: None,
+                    kind: util::ErrorKind::Integrity,
+                });
+            }
+
+            // Show the repair as extra bytes to download.
+            let bytes: u64 = corrupt
+                .iter()
+                .map(|&i| {
+                    let (start, end) = pieces.range(i, size);
+                    end - start
+                })
+                .sum();
+            total += bytes;
+            self.progress.set_total(total);
+
+            let mut urls = urls.to_vec();
+            let first = (round + 1) % urls.len();
+            urls.rotate_left(first);
+            for i in corrupt {
+                let (start, end) = pieces.range(i, size);
+                let mut writer = Writer::spawn(self.open_at(start).await?);
+                for url in self.fetch_range(&urls, start, Some(end), &mut writer, None).await? {
+                    if !used.contains(&url) {
+                        used.push(url);
+                    }
+                }
+                writer.finish().await?;
+            }
+        }
+        Ok(used)
+    }
+
+    /// Probe each of `urls` in turn and return the index and metadata of the
+    /// first that answers.
+    async fn probe_any(&self, urls: &[&Url]) -> Result<(usize, Metadata), util::Error> {
+        let mut error = None;
+        for (i, url) in urls.iter().enumerate() {
+            match self.probe(url).await {
+                Ok(probe) => return Ok((i, probe)),
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(all_failed(&self.filename, error))
+    }
+
+    /// Headers for requesting the file, or a byte range of it if `ranged`.
+    fn headers(&self, ranged: bool) -> HeaderMap {
+        let mut headers = self.request.headers.clone();
+        // The request may ask for an encoding of its own.
+        if !headers.contains_key(header::ACCEPT_ENCODING) {
+            let accept = self.options.compression.accept_encoding(ranged);
+            headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(accept));
+        }
+        headers
+    }
+
+    /// Wait until we may receive `n` more bytes and count them.  Progress
+    /// only moves after we wait, so its speed reflects the throttled rate.
+    async fn pace(&self, n: u64) {
+        for limit in &self.limits {
+            limit.acquire(n).await;
+        }
+        self.received.fetch_add(n, Ordering::Relaxed);
+        self.progress.advance(n);
+    }
+
+    /// Watchdog for a body.  A download our bandwidth limits keep below the
+    /// minimum speed isn't held to it.
+    fn watchdog(&self) -> Watchdog {
+        let mut timeouts = self.options.timeouts;
+        let slowest = self.limits.iter().map(|limit| limit.rate()).filter(|&rate| rate > 0).min();
+        if let (Some(min_speed), Some(slowest)) = (timeouts.min_speed, slowest) {
+            if slowest < min_speed.bytes_per_sec {
+                timeouts.min_speed = None;
+            }
+        }
+        timeouts.watchdog()
+    }
+
+    /// Ask the fetcher for `url` about the file, conditionally if we already
+    /// have it.
+    async fn probe(&self, url: &Url) -> Result<Metadata, util::Error> {
+        let mut headers = self.headers(false);
+        for (name, value) in &self.conditional {
+            headers.insert(name, value.clone());
+        }
+        self.options.fetcher(url)?.probe(url, &headers).await
+    }
+
+    /// Probe the rest of `urls` and return those that serve the same file as
+    /// the first, i.e. with the same size and, if both have one, `ETag`.
+    async fn agreeing<'u>(&self, urls: &[&'u Url], size: u64, first: &Metadata) -> Vec<&'u Url> {
+        let probes = futures::future::join_all(urls[1..].iter().map(|url| self.probe(url))).await;
+        let mut agreeing = vec![urls[0]];
+        for (url, probe) in urls[1..].iter().zip(probes) {
+            if let Ok(probe) = probe {
+                let etags_agree = match (&first.etag, &probe.etag) {
+                    (Some(a), Some(b)) => a == b,
+                    _ => true,
+                };
+                if probe.size == Some(size) && probe.ranges && etags_agree {
+                    agreeing.push(*url);
+                }
+            }
+        }
+        agreeing
+    }
+
+    /// Split `size` bytes into one range per mirror in `urls` and fetch them
+    /// all at the same time.  A range whose mirror fails continues on the
+    /// other mirrors.
+    async fn segmented(&self, urls: &[&Url], size: u64) -> Result<(WriteStats, Vec<Url>), util::Error> {
+        let segments = self.options.segments.min(urls.len()) as u64;
+        let ranges = (0..segments).map(|i| (size * i / segments, size * (i + 1) / segments));
+        let fetches = ranges.enumerate().map(|(i, (start, end))| async move {
+            // Each range writes through its own handle, positioned at the
+            // start of the range.
+            let mut writer = Writer::spawn(self.open_at(start).await?);
+
+            // Range i starts on mirror i, then fails over to the others.
+            let mut urls = urls.to_vec();
+            urls.rotate_left(i);
+            let mirrors = self.fetch_range(&urls, start, Some(end), &mut writer, None).await?;
+            Ok::<_, util::Error>((writer.finish().await?, mirrors))
+        });
+        let results = futures::future::try_join_all(fetches).await?;
+
+        // Combine what each range did.
+        let mut disk = WriteStats {
+            bytes: 0,
+            elapsed: Default::default(),
+        };
+        let mut mirrors = Vec::new();
+        for (stats, used) in results {
+            disk.bytes += stats.bytes;
+            disk.elapsed = disk.elapsed.max(stats.elapsed);
+            for url in used {
+                if !mirrors.contains(&url) {
+                    mirrors.push(url);
+                }
+            }
+        }
+        Ok((disk, mirrors))
+    }
+
+    /// Open the existing output file for writing at `offset`.
+    async fn open_at(&self, offset: u64) -> Result<rt::File, util::Error> {
+        let path = &self.request.output;
+        let mut file = rt::File::open_with(path, std::fs::OpenOptions::new().write(true))
+            .await
+            .map_err(|e| file_error(path, e))?;
+        file.seek(SeekFrom::Start(offset)).await.map_err(|e| file_error(path, e))?;
+        Ok(file)
+    }
+
+    /// Fetch bytes `start..end` (or to the end of the file if `end` is `None`)
+    /// into `writer`, trying each of `urls` in turn.  A mirror that fails
+    /// with a retryable error is tried again, as `options.retries` says,
+    /// before moving on.  Returns the mirrors used.
+    async fn fetch_range(
+        &self,
+        urls: &[&Url],
+        start: u64,
+        end: Option<u64>,
+        writer: &mut Writer,
+        mut hasher: Option<&mut Hasher>,
+    ) -> Result<Vec<Url>, util::Error> {
+        let retries = self.options.retries;
+        let mut offset = start;
+        let mut used = Vec::new();
+        let mut error = None;
+        for url in urls {
+            let mut delay = retries.delay;
+            for attempt in 0..=retries.attempts {
+                if attempt > 0 {
+                    rt::sleep(delay).await;
+                    delay = delay.checked_mul(2).unwrap_or(delay);
+                }
+                // A mirror can only continue from the middle of the file if
+                // it serves the same file.
+                if offset > 0 {
+                    if let Err(e) = self.verify(url).await {
+                        error = Some(e);
+                        break;
+                    }
+                }
+                if attempt == 0 {
+                    used.push((*url).clone());
+                }
+                let result = self
+                    .fetch_from(url, &mut offset, end, writer, hasher.as_deref_mut())
+                    .await;
+                match result {
+                    Ok(()) => return Ok(used),
+                    // The writer failing is not the mirror's fault.
+                    Err(e) if writer.has_failed() => return Err(e),
+                    Err(e) => {
+                        let retryable = e.is_retryable();
+                        error = Some(e);
+                        if !retryable {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Err(all_failed(&self.filename, error))
+    }
+
+    /// Make sure `url` serves the file that was probed: the same size, and
+    /// the same `ETag`, or `Last-Modified` date if it has no `ETag`.  A
+    /// mirror that serves a different file is remembered in `changed`.
+    async fn verify(&self, url: &Url) -> Result<(), util::Error> {
+        if self.verified.lock().unwrap().contains(url) {
+            return Ok(());
+        }
+        let probe = self.probe(url).await?;
+        let same = match &self.probed {
+            Some(first) => {
+                let validator = match (&first.etag, &first.last_modified) {
+                    (Some(etag), _) => probe.etag.as_ref() == Some(etag),
+                    (None, Some(date)) => probe.last_modified.as_ref() == Some(date),
+                    (None, None) => true,
+                };
+                first.size.is_some() && probe.size == first.size && validator
+            }
+            None => false,
+        };
+        if same {
+            self.verified.lock().unwrap().push(url.clone());
+            return Ok(());
+        }
+        self.changed.lock().unwrap().get_or_insert_with(|| (url.clone(), probe));
+        Err(util::Error {
+            what: format!("{} serves a different file, so the download can't continue there.", url),
+            source: None,
+            kind: util::ErrorKind::Integrity,
+        })
+    }
+
+    /// Fetch bytes `offset..end` from `url` into `writer`, advancing `offset`
+    /// past every byte written.
+    async fn fetch_from(
+        &self,
+        url: &Url,
+        offset: &mut u64,
+        end: Option<u64>,
+        writer: &mut Writer,
+        mut hasher: Option<&mut Hasher>,
+    ) -> Result<(), util::Error> {
+        // Show which mirror we are downloading from.
+        let source = url.host_str().filter(|host| !host.is_empty()).unwrap_or(url.scheme());
+        let status = format!("{} ({})", self.filename, source);
+        self.progress.set_status(&status);
+
+        // Ask for the rest of the range.  Without a range we get the whole
+        // file.
+        let ranged = *offset > 0 || end != self.size;
+        let range = if ranged {
+            Some(Range { start: *offset, end })
+        } else {
+            None
+        };
+        let fetcher = self.options.fetcher(url)?;
+        let mut download = fetcher.fetch(url, &self.headers(ranged), range).await?;
+        if !download.headers.is_empty() {
+            *self.response.lock().unwrap() = Some(download.headers.clone());
+        }
+
+        // A server that ignores the range sends the whole file, so we skip the
+        // part we already have.
+        let mut position = if download.partial { *offset } else { 0 };
+
+        // Decode a compressed body unless we are keeping it as it is.  A
+        // compressed range can't be decoded without what comes before it.
+        let encoding = download.encoding.as_deref().unwrap_or("");
+        let mut decoder = match self.options.compression {
+            Compression::Preserve => None,
+            _ => Decoder::new(encoding)?,
+        };
+        if decoder.is_some() && download.partial {
+            return Err(util::Error {
+                what: format!("{} sent a compressed range of the file.", url),
+                source: None,
+                kind: util::ErrorKind::Other,
+            });
+        }
+        let decoding = decoder.is_some();
+        // When the probe didn't say the file was compressed, the size we
+        // expect is the size on the wire, which the decoded file outgrows.
+        // The decoder tells us where the file ends instead.
+        let end = if decoding && end == self.size { None } else { end };
+
+        // The watchdog fails the download if the server stops sending.
+        let mut watchdog = self.watchdog();
+        let show_stalled = |stalled| {
+            if stalled {
+                self.progress.set_status(&format!("{} stalled", status));
+            } else {
+                self.progress.set_status(&status);
+            }
+        };
+
+        // Do an asynchronous, buffered copy of the download to the output file.
+        let mut ended = false;
+        while !ended {
+            let received = watchdog.body_chunk(&mut download, show_stalled).await?;
+            let mut chunk = match (received, &mut decoder) {
+                (Some(wire), Some(decoder)) => {
+                    // The bar's total is the compressed size, so we count
+                    // the compressed bytes.
+                    self.pace(wire.len() as u64).await;
+                    decoder.decode(&wire)?
+                }
+                (Some(chunk), None) => chunk,
+                (None, _) => {
+                    // The decoder may hold the end of the file.
+                    ended = true;
+                    match decoder.take() {
+                        Some(decoder) => decoder.finish()?,
+                        None => break,
+                    }
+                }
+            };
+
+            // Drop anything before the offset or after the end of the range.
+            let chunk_start = position;
+            position += chunk.len() as u64;
+            if position <= *offset {
+                continue;
+            }
+            if chunk_start < *offset {
+                chunk = chunk.split_off((*offset - chunk_start) as usize);
+            }
+            if let Some(end) = end {
+                if *offset + chunk.len() as u64 > end {
+                    chunk.truncate((end - *offset) as usize);
+                }
+            }
+
+            // Wait until we are allowed to write this chunk.
+            if !decoding {
+                self.pace(chunk.len() as u64).await;
+            }
+            if let Some(hasher) = hasher.as_deref_mut() {
+                hasher.update(&chunk);
+            }
+            *offset += chunk.len() as u64;
+            writer.write(chunk).await?;
+            if self.checkpoint {
+                if let Some(journal) = &self.options.journal {
+                    journal.checkpoint(&self.request.output, *offset).await?;
+                }
+            }
+
+            if Some(*offset) == end {
+                return Ok(());
+            }
+        }
+
+        // The server may close the connection cleanly before sending the
+        // whole range.
+        match end {
+            Some(end) if *offset < end => Err(util::Error {
+                what: format!(
+                    "{} ended after {} of {} bytes.",
+                    url, *offset, end
+                ),
+                source: None,
+                kind: util::ErrorKind::Integrity,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Hash the file at `path`.
+async fn hash_file(path: &Path, mut hasher: Hasher) -> Result<Vec<u8>, util::Error> {
+    use futures::io::AsyncReadExt;
+    let mut file = rt::File::open(path).await.map_err(|e| file_error(path, e))?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await.map_err(|e| file_error(path, e))?;
+        if n == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Indices of the pieces of the `size` bytes at `path` that don't match
+/// their expected digests, including pieces missing from the end.
+async fn corrupt_pieces(path: &Path, pieces: &Pieces, size: u64) -> Result<Vec<usize>, util::Error> {
+    use futures::io::AsyncReadExt;
+    let mut file = rt::File::open(path).await.map_err(|e| file_error(path, e))?;
+    let mut buf = vec![0u8; pieces.length.min(1024 * 1024) as usize];
+    let mut corrupt = Vec::new();
+    for (i, expected) in pieces.digests.iter().enumerate() {
+        let (start, end) = pieces.range(i, size);
+        let mut hasher = pieces.algorithm.hasher();
+        let mut remaining = end - start;
+        while remaining > 0 {
+            let want = remaining.min(buf.len() as u64) as usize;
+            let n = file.read(&mut buf[..want]).await.map_err(|e| file_error(path, e))?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            remaining -= n as u64;
+        }
+        if remaining > 0 || start == end || hasher.finish() != *expected {
+            corrupt.push(i);
+        }
+    }
+    Ok(corrupt)
+}
+
+/// Set the modification time of the file at `path` to the HTTP `date`.
+/// Dates we can't parse are ignored.
+fn set_modified(path: &Path, date: &str) -> Result<(), util::Error> {
+    if let Ok(time) = httpdate::parse_http_date(date) {
+        filetime::set_file_mtime(path, filetime::FileTime::from_system_time(time))
+            .map_err(|e| file_error(path, e))?;
+    }
+    Ok(())
+}
+
+/// Error for a download that failed on every mirror, wrapping the last error.
+fn all_failed(filename: &str, error: Option<util::Error>) -> util::Error {
+    let kind = match &error {
+        Some(error) => error.kind,
+        None => util::ErrorKind::Other,
+    };
+    util::Error {
+        what: format!("Couldn't download {} from any mirror.", filename),
+        source: error.map(|e| e.into()),
+        kind,
+    }
+}
+
+/// Remove the output file at `path`, if it exists, before writing a new one.
+/// The old file may be a link shared with the store, so it must not be
+/// overwritten in place.
+async fn remove_output(path: &Path) -> Result<(), util::Error> {
+    match rt::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(file_error(path, e)),
+        _ => Ok(()),
+    }
+}
+
+/// Error for a download that can't be served offline because of `problem`.
+fn offline_error(problem: &str) -> util::Error {
+    util::Error {
+        what: format!("Can't download offline: {}.", problem),
+        source: None,
+        kind: util::ErrorKind::Other,
+    }
+}
+
+/// Error for a problem with the output file at `path`.
+fn file_error(path: &Path, error: std::io::Error) -> util::Error {
+    util::Error {
+        what: format!("Couldn't write {}.", path.display()),
+        source: Some(error.into()),
+        kind: util::ErrorKind::Other,
+    }
+}
@@ -0,0 +1,327 @@
+//! Bandwidth limiting with a token bucket shared between tasks.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::channel::oneshot;
+
+use crate::rt;
+
+/// Longest we sleep before looking at the bucket again.  Keeping this short
+/// means a call to `set_rate()` takes effect promptly even for tasks that are
+/// already waiting.
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// Async token bucket that limits throughput to a number of bytes per second.
+/// The bucket is meant to be shared between tasks in an `Arc`.  Tasks call
+/// `acquire()` before writing each chunk and are served in first-come,
+/// first-served order, so concurrent downloads share the bandwidth fairly.
+/// Example:
+///
+/// ```ignore
+/// let limit = Arc::new(TokenBucket::new(2 * 1024 * 1024));
+/// while let Some(chunk) = download.chunk().await? {
+///     limit.acquire(chunk.len() as u64).await;
+///     outfile.write_all(&chunk).await?;
+/// }
+/// ```
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Tokens and the rate at which they accumulate.
+    state: Mutex<State>,
+    /// Tasks wait their turn in this queue, so they acquire tokens in the
+    /// order they asked for them.
+    queue: Queue,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Bytes per second, or 0 for unlimited.
+    rate: u64,
+    /// Tokens currently available, at most one second's worth.
+    tokens: f64,
+    /// When tokens were last added to the bucket.
+    refilled: Instant,
+}
+impl State {
+    /// Add tokens for the time elapsed since the last refill.
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.refilled = now;
+    }
+}
+
+/// First-come, first-served queue of tasks.  The mutex in `futures` makes no
+/// promise about the order in which waiting tasks get the lock, so we keep
+/// the waiting tasks in order ourselves.
+#[derive(Debug, Default)]
+struct Queue {
+    waiting: Mutex<Waiting>,
+}
+
+#[derive(Debug, Default)]
+struct Waiting {
+    /// Does some task have its turn?
+    busy: bool,
+    /// Tasks waiting for their turn, oldest first.
+    tasks: VecDeque<oneshot::Sender<()>>,
+}
+
+impl Queue {
+    /// Wait until it is our turn.  The turn ends when the returned `Turn` is
+    /// dropped.
+    async fn turn(&self) -> Turn<'_> {
+        let receiver = {
+            let mut waiting = self.waiting.lock().unwrap();
+            if !waiting.busy {
+                waiting.busy = true;
+                return Turn { queue: self };
+            }
+            let (sender, receiver) = oneshot::channel();
+            waiting.tasks.push_back(sender);
+            receiver
+        };
+        let mut wait = Wait {
+            queue: self,
+            receiver,
+            done: false,
+        };
+        // The sender is only dropped after being handed the turn, so the
+        // result doesn't matter.
+        let _ = (&mut wait.receiver).await;
+        wait.done = true;
+        Turn { queue: self }
+    }
+
+    /// Hand the turn to the next task still waiting, if any.
+    fn next(&self) {
+        let mut waiting = self.waiting.lock().unwrap();
+        while let Some(task) = waiting.tasks.pop_front() {
+            if task.send(()).is_ok() {
+                return;
+            }
+        }
+        waiting.busy = false;
+    }
+}
+
+/// A task's turn in a `Queue`.
+struct Turn<'a> {
+    queue: &'a Queue,
+}
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.queue.next();
+    }
+}
+
+/// A task waiting in a `Queue`.  If the task gives up after it was handed its
+/// turn, the turn goes to the next task.
+struct Wait<'a> {
+    queue: &'a Queue,
+    receiver: oneshot::Receiver<()>,
+    /// Has the task got its turn?
+    done: bool,
+}
+impl Drop for Wait<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        self.receiver.close();
+        if let Ok(Some(())) = self.receiver.try_recv() {
+            self.queue.next();
+        }
+    }
+}
+
+impl TokenBucket {
+    /// Create a full bucket that allows `rate` bytes per second.  A rate of 0
+    /// means unlimited.
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            state: Mutex::new(State {
+                rate,
+                tokens: rate as f64,
+                refilled: Instant::now(),
+            }),
+            queue: Queue::default(),
+        }
+    }
+
+    /// Current limit in bytes per second, or 0 for unlimited.
+    pub fn rate(&self) -> u64 {
+        self.state.lock().unwrap().rate
+    }
+
+    /// Change the limit to `rate` bytes per second, or 0 for unlimited.  Takes
+    /// effect immediately, including for tasks already waiting in `acquire()`.
+    pub fn set_rate(&self, rate: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.refill();
+        state.rate = rate;
+        state.tokens = state.tokens.min(rate as f64);
+    }
+
+    /// Wait until `n` bytes may be transferred without exceeding the limit.
+    pub async fn acquire(&self, n: u64) {
+        // Wait for the tasks that asked before us.
+        let _turn = self.queue.turn().await;
+
+        // Requests larger than the bucket are served one bucketful at a time.
+        let mut remaining = n as f64;
+        while remaining > 0.0 {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                if state.rate == 0 {
+                    return;
+                }
+                state.refill();
+                let want = remaining.min(state.rate as f64);
+                if state.tokens >= want {
+                    state.tokens -= want;
+                    remaining -= want;
+                    continue;
+                }
+                Duration::from_secs_f64((want - state.tokens) / state.rate as f64)
+            };
+            rt::sleep(wait.min(MAX_WAIT)).await;
+        }
+    }
+}
+
+/// Parse a rate such as `2M` into bytes per second.  The optional suffixes
+/// `K`, `M`, and `G` (case insensitive), or `Ki`, `Mi`, and `Gi`, multiply by
+/// powers of 1024, and a trailing `B` or `/s` is ignored, so `500k`, `1.5M`,
+/// `2MB/s`, and `2MiB/s` are all valid.
+pub fn parse_rate(rate: &str) -> Result<u64, util::Error> {
+    let number = rate.trim();
+    parse_bytes(number.strip_suffix("/s").unwrap_or(number)).ok_or_else(|| util::Error {
+        what: format!("Invalid rate {:?}, expected a number such as 500K or 2M.", rate),
+        source: None,
+        kind: util::ErrorKind::Other,
+    })
+}
+
+/// Parse a size such as `1G` into bytes, with the same suffixes as
+/// `parse_rate()`.
+pub fn parse_size(size: &str) -> Result<u64, util::Error> {
+    parse_bytes(size.trim()).ok_or_else(|| util::Error {
+        what: format!("Invalid size {:?}, expected a number such as 500M or 2G.", size),
+        source: None,
+        kind: util::ErrorKind::Other,
+    })
+}
+
+/// Parse a number of bytes with an optional `K`, `M`, or `G` suffix, which
+/// may be followed by `i`, and an optional trailing `B`.
+fn parse_bytes(number: &str) -> Option<u64> {
+    let number = number
+        .strip_suffix('B')
+        .or_else(|| number.strip_suffix('b'))
+        .unwrap_or(number);
+    let number = match number.strip_suffix('i') {
+        Some(prefixed) if prefixed.ends_with(|c: char| "KkMmGg".contains(c)) => prefixed,
+        _ => number,
+    };
+    let (number, multiplier) = match number.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&number[..number.len() - 1], 1024.0),
+        Some('M') => (&number[..number.len() - 1], 1024.0 * 1024.0),
+        Some('G') => (&number[..number.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (number, 1.0),
+    };
+    let number: f64 = number.parse().ok()?;
+    if !number.is_finite() || number < 0.0 {
+        return None;
+    }
+    Some((number * multiplier) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn parses_rates_and_sizes() {
+        let cases: &[(&str, u64)] = &[
+            ("0", 0),
+            ("1000", 1000),
+            ("500k", 500 * 1024),
+            ("500K", 500 * 1024),
+            ("1.5M", 1536 * 1024),
+            ("2m", 2 * 1024 * 1024),
+            ("1G", 1024 * 1024 * 1024),
+            ("64Ki", 64 * 1024),
+            ("2Mi", 2 * 1024 * 1024),
+            ("2MiB", 2 * 1024 * 1024),
+            ("2MB", 2 * 1024 * 1024),
+            ("100b", 100),
+            (" 3K ", 3 * 1024),
+        ];
+        for &(text, bytes) in cases {
+            assert_eq!(parse_size(text).unwrap(), bytes, "{}", text);
+            assert_eq!(parse_rate(text).unwrap(), bytes, "{}", text);
+        }
+        assert_eq!(parse_rate("2MB/s").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_rate("2MiB/s").unwrap(), 2 * 1024 * 1024);
+    }
+
+    #[test]
+    fn rejects_invalid_rates_and_sizes() {
+        for text in &["", "K", "-1", "1T", "1i", "fast", "1.5.5M", "NaN", "inf", "1/s/s"] {
+            assert!(parse_rate(text).is_err(), "{}", text);
+            assert!(parse_size(text).is_err(), "{}", text);
+        }
+        // Only rates are per second.
+        assert!(parse_size("2M/s").is_err());
+    }
+
+    #[test]
+    fn waits_for_the_bucket_to_refill() {
+        let bucket = TokenBucket::new(10_000);
+        let waited = rt::block_on(async {
+            // The bucket starts full.
+            bucket.acquire(10_000).await;
+            let started = Instant::now();
+            bucket.acquire(5_000).await;
+            started.elapsed()
+        })
+        .unwrap();
+        assert!(waited >= Duration::from_millis(400), "{:?}", waited);
+        assert!(waited < Duration::from_secs(2), "{:?}", waited);
+    }
+
+    #[test]
+    fn serves_tasks_in_the_order_they_asked() {
+        let bucket = Arc::new(TokenBucket::new(1_000));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        rt::block_on(async {
+            bucket.acquire(1_000).await;
+            // The second task asks for less, but it must still wait for the
+            // first.
+            let task = |name: &'static str, n: u64| {
+                let (bucket, order) = (bucket.clone(), order.clone());
+                async move {
+                    bucket.acquire(n).await;
+                    order.lock().unwrap().push(name);
+                }
+            };
+            futures::join!(task("first", 300), task("second", 10));
+        })
+        .unwrap();
+        assert_eq!(*order.lock().unwrap(), ["first", "second"]);
+    }
+
+    #[test]
+    fn never_waits_without_a_limit() {
+        let bucket = TokenBucket::new(0);
+        let started = Instant::now();
+        rt::block_on(bucket.acquire(u64::MAX)).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,370 @@
+//! Buffered writer stage that decouples the network from the disk.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::io::AsyncWrite;
+use futures::{SinkExt, StreamExt};
+
+use crate::rt::{self, JoinHandle};
+use crate::sink::{Output, Sink};
+
+/// Default size of the write buffer.  Small chunks from the network are
+/// coalesced into writes of about this size.
+pub(crate) const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Default number of chunks that may be queued for the writer before the
+/// network side has to wait.
+const QUEUE_LENGTH: usize = 16;
+
+/// Numbers the temporary files of `write_atomic()`, so concurrent writes of
+/// the same file don't write the same temporary file.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Writes chunks to an output on a separate task.  Chunks travel to the task
+/// through a bounded channel, so a slow disk applies backpressure to the
+/// network instead of letting memory grow without bound.  The task uses
+/// `write_all()` on a `BufWriter`, so short writes are retried and small
+/// chunks are coalesced into large writes.  Any other `Sink` can take the
+/// place of the output.  Example:
+///
+/// ```ignore
+/// let mut writer = Writer::spawn(download::rt::File::create(&filename).await?);
+/// while let Some(chunk) = download.chunk().await? {
+///     writer.write(chunk).await?;
+/// }
+/// let stats = writer.finish().await?;
+/// ```
+#[derive(Debug)]
+pub struct Writer {
+    sender: mpsc::Sender<Bytes>,
+    /// The writer task, or `None` once we have collected its error.
+    task: Option<JoinHandle<Result<(), util::Error>>>,
+    written: Arc<AtomicU64>,
+    started: Instant,
+}
+
+/// What the writer did, returned by `Writer::finish()`.
+#[derive(Clone, Copy, Debug)]
+pub struct WriteStats {
+    /// Bytes written to the output.
+    pub bytes: u64,
+    /// Time between spawning the writer and the final flush.
+    pub elapsed: Duration,
+}
+impl WriteStats {
+    /// Average disk throughput in bytes per second.
+    pub fn bytes_per_sec(&self) -> u64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            (self.bytes as f64 / secs) as u64
+        } else {
+            self.bytes
+        }
+    }
+}
+
+impl Writer {
+    /// Spawn a writer for `output` with the default buffer size and queue
+    /// length.
+    pub fn spawn<W>(output: W) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::with_capacity(output, BUFFER_SIZE, QUEUE_LENGTH)
+    }
+
+    /// Spawn a writer for `output` that buffers up to `buffer_size` bytes
+    /// and queues up to `queue_length` chunks.
+    pub fn with_capacity<W>(output: W, buffer_size: usize, queue_length: usize) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::spawn_sink(Output::with_capacity(output, buffer_size), queue_length)
+    }
+
+    /// Spawn a writer for `sink` with the default queue length.
+    pub fn for_sink<S: Sink + 'static>(sink: S) -> Self {
+        Self::spawn_sink(sink, QUEUE_LENGTH)
+    }
+
+    /// Spawn a writer for `sink` that queues up to `queue_length` chunks.
+    fn spawn_sink<S: Sink + 'static>(mut sink: S, queue_length: usize) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<Bytes>(queue_length.max(1));
+        let written = Arc::new(AtomicU64::new(0));
+        let task = {
+            let written = written.clone();
+            rt::spawn(async move {
+                while let Some(chunk) = receiver.next().await {
+                    let len = chunk.len() as u64;
+                    sink.write(chunk).await?;
+                    written.fetch_add(len, Ordering::Relaxed);
+                }
+                sink.finish().await
+            })
+        };
+        Writer {
+            sender,
+            task: Some(task),
+            written,
+            started: Instant::now(),
+        }
+    }
+
+    /// Spawn a writer that hands the chunks to `consume` on a thread that may
+    /// block, e.g. to feed a synchronous decoder.  `consume` reads the chunks
+    /// through a `ChunkReader`; whatever it leaves unread is read and dropped
+    /// after it returns.  Example:
+    ///
+    /// ```ignore
+    /// let writer = Writer::spawn_blocking(move |reader| {
+    ///     tar::Archive::new(reader).unpack(&dest).map_err(extract_error)
+    /// });
+    /// ```
+    pub fn spawn_blocking<F>(consume: F) -> Self
+    where
+        F: FnOnce(&mut ChunkReader) -> Result<(), util::Error> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<Bytes>(QUEUE_LENGTH);
+        let written = Arc::new(AtomicU64::new(0));
+        let mut reader = ChunkReader {
+            receiver,
+            chunk: Bytes::new(),
+            read: written.clone(),
+        };
+        let task = rt::spawn_blocking(move || {
+            consume(&mut reader)?;
+            // The sender would wait forever for room in a full queue.
+            std::io::copy(&mut reader, &mut std::io::sink()).map_err(write_error)?;
+            Ok(())
+        });
+        Writer {
+            sender,
+            task: Some(task),
+            written,
+            started: Instant::now(),
+        }
+    }
+
+    /// Queue `chunk` to be written, waiting if the queue is full.
+    pub async fn write(&mut self, chunk: Bytes) -> Result<(), util::Error> {
+        if self.sender.send(chunk).await.is_ok() {
+            return Ok(());
+        }
+
+        // The writer task only hangs up early when it fails, so wait for it
+        // to find out why.
+        match self.task.take() {
+            Some(task) => match task.await {
+                Ok(Err(e)) | Err(e) => Err(e),
+                Ok(Ok(())) => Err(write_error(std::io::ErrorKind::BrokenPipe.into())),
+            },
+            None => Err(write_error(std::io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    /// Has writing to the output failed?
+    pub fn has_failed(&self) -> bool {
+        self.task.is_none()
+    }
+
+    /// Bytes written to the output, or read by a blocking consumer, so far.
+    /// This lags behind the bytes received from the network by however much
+    /// is queued or buffered.
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// Write everything still queued, flush the output, and report what was
+    /// written.
+    pub async fn finish(self) -> Result<WriteStats, util::Error> {
+        // Closing the channel tells the task there are no more chunks.
+        drop(self.sender);
+        match self.task {
+            Some(task) => task.await??,
+            None => return Err(write_error(std::io::ErrorKind::BrokenPipe.into())),
+        }
+        Ok(WriteStats {
+            bytes: self.written.load(Ordering::Relaxed),
+            elapsed: self.started.elapsed(),
+        })
+    }
+}
+
+/// Reads the chunks queued for a writer created with
+/// `Writer::spawn_blocking()` as a `std::io::Read`, blocking until each chunk
+/// arrives.
+#[derive(Debug)]
+pub struct ChunkReader {
+    receiver: mpsc::Receiver<Bytes>,
+    /// What is left of the current chunk.
+    chunk: Bytes,
+    read: Arc<AtomicU64>,
+}
+impl std::io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            // We run on a thread where blocking is allowed.
+            match futures::executor::block_on(self.receiver.next()) {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        self.read.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Wrap an error writing the output.
+pub(crate) fn write_error(error: std::io::Error) -> util::Error {
+    util::Error {
+        what: "Failed to write output.".to_string(),
+        source: Some(error.into()),
+        kind: util::ErrorKind::Other,
+    }
+}
+
+/// Replace the file at `path` with `contents` by writing a temporary file next
+/// to it and renaming it into place, so a crash never leaves a half-written
+/// file behind.  Every write has a temporary file of its own, so concurrent
+/// writes of the same file don't clobber each other's.
+pub(crate) async fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    if let Err(e) = rt::fs::write(&temp, contents).await {
+        let _ = rt::fs::remove_file(&temp).await;
+        return Err(e);
+    }
+    rt::fs::rename(&temp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::BoxFuture;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Mutex;
+
+    /// Sink that holds every chunk until `open` is set, or fails if `fail`
+    /// is set.
+    #[derive(Clone, Default)]
+    struct Gated {
+        open: Arc<AtomicBool>,
+        fail: bool,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+    impl Sink for Gated {
+        fn write(&mut self, chunk: Bytes) -> BoxFuture<'_, Result<(), util::Error>> {
+            Box::pin(async move {
+                if self.fail {
+                    return Err(write_error(std::io::Error::other("disk full")));
+                }
+                while !self.open.load(Ordering::SeqCst) {
+                    rt::sleep(Duration::from_millis(10)).await;
+                }
+                self.written.lock().unwrap().extend_from_slice(&chunk);
+                Ok(())
+            })
+        }
+
+        fn finish(&mut self) -> BoxFuture<'_, Result<(), util::Error>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    /// Scratch file for the test called `name`.
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let dir = format!("download-writer-{}-{}", name, std::process::id());
+        let dir = std::env::temp_dir().join(dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("file")
+    }
+
+    #[test]
+    fn holds_up_the_network_while_the_output_is_slow() {
+        let sink = Gated::default();
+        let chunk = Bytes::from(vec![1u8; 1000]);
+        let (queued, stats) = rt::block_on(async {
+            let mut writer = Writer::spawn_sink(sink.clone(), 2);
+            // Only a few chunks fit in the queue while the sink is stuck.
+            let mut queued = 0;
+            while queued < 20 {
+                let write = writer.write(chunk.clone());
+                match rt::timeout(Duration::from_millis(100), write).await {
+                    Some(result) => result.unwrap(),
+                    None => break,
+                }
+                queued += 1;
+            }
+            sink.open.store(true, Ordering::SeqCst);
+            writer.write(chunk.clone()).await.unwrap();
+            (queued, writer.finish().await.unwrap())
+        })
+        .unwrap();
+        assert!(queued > 1 && queued < 10, "{} chunks queued", queued);
+        // Everything accepted was written once the sink got going.
+        assert!(stats.bytes > queued as u64 * 1000);
+        assert_eq!(sink.written.lock().unwrap().len() as u64, stats.bytes);
+    }
+
+    #[test]
+    fn reports_the_output_failing() {
+        let sink = Gated {
+            fail: true,
+            ..Default::default()
+        };
+        rt::block_on(async {
+            let mut writer = Writer::spawn_sink(sink, 2);
+            assert!(!writer.has_failed());
+            // The chunks that fit in the queue are accepted before the
+            // writer task gets to fail.
+            let error = loop {
+                if let Err(e) = writer.write(Bytes::from_static(b"chunk")).await {
+                    break e;
+                }
+            };
+            assert!(error.to_string().contains("disk full"));
+            assert!(writer.has_failed());
+            assert!(writer.write(Bytes::from_static(b"more")).await.is_err());
+            assert!(writer.finish().await.is_err());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn writes_files_atomically() {
+        let path = scratch("atomic");
+        std::fs::write(&path, b"old").unwrap();
+        rt::block_on(async {
+            // Concurrent writes have temporary files of their own.
+            let (first, second) = futures::join!(
+                write_atomic(&path, b"first"),
+                write_atomic(&path, b"second")
+            );
+            first.unwrap();
+            second.unwrap();
+        })
+        .unwrap();
+        let content = std::fs::read(&path).unwrap();
+        assert!(content == b"first" || content == b"second");
+
+        // Only the file itself is left.
+        let dir = path.parent().unwrap();
+        let names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["file"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
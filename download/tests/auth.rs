@@ -0,0 +1,152 @@
+//! Credential helpers asked by concurrent downloads, servers that refuse
+//! access, and credentials kept out of `Debug` output.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use download::auth::{Credential, Credentials};
+use download::{rt, Options, Request};
+use reqwest::Url;
+
+/// Look up `url` in `credentials`, returning the credential and how long it
+/// took from `start`.
+async fn lookup(
+    credentials: &Credentials,
+    url: &str,
+    start: Instant,
+) -> (Option<Credential>, Duration) {
+    let credential = credentials.lookup(&Url::parse(url).unwrap()).await.unwrap();
+    (credential, start.elapsed())
+}
+
+#[test]
+fn a_slow_helper_does_not_hold_up_other_hosts() {
+    let mut credentials = Credentials::new();
+    credentials
+        .add_helper("slow.example=sleep 2; echo username=slow; echo password=a; true")
+        .unwrap();
+    credentials
+        .add_helper("fast.example=echo username=fast; echo password=b; true")
+        .unwrap();
+
+    let start = Instant::now();
+    let ((slow, _), (fast, fast_time)) = rt::block_on(futures::future::join(
+        lookup(&credentials, "https://slow.example/file", start),
+        lookup(&credentials, "https://fast.example/file", start),
+    ))
+    .unwrap();
+    assert!(matches!(slow, Some(Credential::Basic { username, .. }) if username == "slow"));
+    assert!(matches!(fast, Some(Credential::Basic { username, .. }) if username == "fast"));
+    assert!(fast_time < Duration::from_secs(1), "{:?}", fast_time);
+}
+
+#[test]
+fn asks_a_helper_once_for_concurrent_lookups() {
+    let count = std::env::temp_dir().join(format!("download-auth-{}", std::process::id()));
+    let _ = std::fs::remove_file(&count);
+    let mut credentials = Credentials::new();
+    credentials
+        .add_helper(&format!(
+            "host.example=echo >> {}; sleep 1; echo username=a; echo password=b; true",
+            count.display()
+        ))
+        .unwrap();
+
+    let start = Instant::now();
+    rt::block_on(futures::future::join(
+        lookup(&credentials, "https://host.example/a", start),
+        lookup(&credentials, "https://host.example/b", start),
+    ))
+    .unwrap();
+    assert_eq!(std::fs::read(&count).unwrap(), b"\n");
+    std::fs::remove_file(&count).unwrap();
+}
+
+/// Answer every request on a loopback port with `status` and return the
+/// port.
+fn refuse(status: &'static str) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                line.clear();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nWWW-Authenticate: Basic realm=\"files\"\r\n\
+                 Content-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+        }
+    });
+    port
+}
+
+/// Download from a server that answers with `status`, sending `credentials`.
+fn download_refused(status: &'static str, credentials: Credentials) -> util::Error {
+    let url = Url::parse(&format!("http://127.0.0.1:{}/file", refuse(status))).unwrap();
+    let options = Options {
+        credentials: Some(Arc::new(credentials)),
+        ..Options::new(reqwest::Client::new())
+    };
+    let mut request = Request::new(url);
+    request.output = std::env::temp_dir().join(format!(
+        "download-auth-{}-{}",
+        &status[..3],
+        std::process::id()
+    ));
+    let result = rt::block_on(download::download(&request, &options, &|_| {})).unwrap();
+    let _ = std::fs::remove_file(&request.output);
+    result.unwrap_err()
+}
+
+#[test]
+fn refused_access_is_an_auth_error() {
+    let error = download_refused("401 Unauthorized", Credentials::new());
+    assert_eq!(error.kind, util::ErrorKind::Auth);
+    assert!(error.to_string().contains("needs credentials"), "{}", error);
+
+    let mut credentials = Credentials::new();
+    credentials.add_basic("127.0.0.1=builder:s3cret").unwrap();
+    let error = download_refused("403 Forbidden", credentials.clone());
+    assert_eq!(error.kind, util::ErrorKind::Auth);
+    assert!(error.to_string().contains("were refused"), "{}", error);
+    assert!(!error.to_string().contains("s3cret"));
+
+    // Without a helper to ask again, a refused credential stays refused.
+    let error = download_refused("401 Unauthorized", credentials);
+    assert_eq!(error.kind, util::ErrorKind::Auth);
+    assert!(error.to_string().contains("were refused"), "{}", error);
+}
+
+#[test]
+fn debug_output_hides_secrets() {
+    let basic = Credential::Basic {
+        username: "builder".to_string(),
+        password: "s3cret".to_string(),
+    };
+    let debug = format!("{:?}", basic);
+    assert!(debug.contains("builder"));
+    assert!(!debug.contains("s3cret"));
+    assert!(!format!("{:?}", Credential::Bearer("t0ken".to_string())).contains("t0ken"));
+
+    let mut credentials = Credentials::new();
+    credentials
+        .add_basic("host.example=builder:s3cret")
+        .unwrap();
+    credentials.add_bearer("api.example=t0ken").unwrap();
+    let debug = format!("{:?}", credentials);
+    assert!(
+        !debug.contains("s3cret") && !debug.contains("t0ken"),
+        "{}",
+        debug
+    );
+}
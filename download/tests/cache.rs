@@ -0,0 +1,159 @@
+//! Downloads through the cache, with files served by the `Mock` fetcher.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use download::cache::Cache;
+use download::fetch::Mock;
+use download::{rt, Options, Outcome, Request};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::Url;
+
+/// Size of the files served.
+const SIZE: usize = 100_000;
+
+/// Scratch directory for the test called `name`, empty.
+fn scratch(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("download-cache-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Mock serving `SIZE` bytes of `seed` at `mock://a/<name>` for each of
+/// `names`, cacheable for an hour and with `extra` headers.
+fn mock(names: &[&str], extra: &[(header::HeaderName, &'static str)]) -> Arc<Mock> {
+    let mock = Arc::new(Mock::new());
+    for (seed, name) in names.iter().enumerate() {
+        let url = url(name);
+        mock.insert(url.clone(), vec![seed as u8; SIZE]);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("max-age=3600"),
+        );
+        for (name, value) in extra {
+            headers.insert(name.clone(), HeaderValue::from_static(value));
+        }
+        mock.respond_with(&url, headers);
+    }
+    mock
+}
+
+fn url(name: &str) -> Url {
+    Url::parse(&format!("mock://a/{}", name)).unwrap()
+}
+
+/// Options that fetch from `mock` through a cache in `dir` of `budget` bytes.
+fn options(mock: &Arc<Mock>, dir: &std::path::Path, budget: u64) -> Options {
+    let mut options = Options::new(reqwest::Client::new());
+    options.fetchers.insert("mock".to_string(), mock.clone());
+    let cache = rt::block_on(Cache::open(dir.join("cache"), budget))
+        .unwrap()
+        .unwrap();
+    options.cache = Some(Arc::new(cache));
+    options
+}
+
+/// Download `mock://a/<name>` into `dir` with `headers`.
+fn download(
+    options: &Options,
+    dir: &std::path::Path,
+    name: &str,
+    headers: &[(header::HeaderName, &'static str)],
+) -> Result<Outcome, util::Error> {
+    let mut request = Request::new(url(name));
+    request.output = dir.join(name);
+    for (name, value) in headers {
+        request
+            .headers
+            .insert(name.clone(), HeaderValue::from_static(value));
+    }
+    rt::block_on(download::download(&request, options, &|_| {})).unwrap()
+}
+
+#[test]
+fn serves_a_fresh_hit_without_fetching() {
+    let dir = scratch("hit");
+    let mock = mock(&["a"], &[]);
+    let options = options(&mock, &dir, 10 * SIZE as u64);
+
+    assert!(!download(&options, &dir, "a", &[]).unwrap().cached);
+    std::fs::remove_file(dir.join("a")).unwrap();
+    let outcome = download(&options, &dir, "a", &[]).unwrap();
+    assert!(outcome.cached);
+    assert_eq!(outcome.bytes, SIZE as u64);
+    assert_eq!(std::fs::read(dir.join("a")).unwrap(), vec![0; SIZE]);
+    assert_eq!(mock.fetches().len(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fetches_again_when_a_varying_header_differs() {
+    let dir = scratch("vary");
+    let mock = mock(&["a"], &[(header::VARY, "Accept-Language")]);
+    let options = options(&mock, &dir, 10 * SIZE as u64);
+    let english = [(header::ACCEPT_LANGUAGE, "en")];
+    let german = [(header::ACCEPT_LANGUAGE, "de")];
+
+    assert!(!download(&options, &dir, "a", &english).unwrap().cached);
+    assert!(!download(&options, &dir, "a", &german).unwrap().cached);
+    assert_eq!(mock.fetches().len(), 2);
+    // Both are cached now, each for its own language.
+    assert!(download(&options, &dir, "a", &english).unwrap().cached);
+    assert!(download(&options, &dir, "a", &german).unwrap().cached);
+    assert_eq!(mock.fetches().len(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn evicts_the_least_recently_used_bodies_over_budget() {
+    let dir = scratch("evict");
+    let mock = mock(&["a", "b", "c"], &[]);
+    // Room for two bodies.
+    let mut options = options(&mock, &dir, 2 * SIZE as u64 + SIZE as u64 / 2);
+
+    // Using a again makes b the least recently used when c arrives.  Times
+    // are in milliseconds, so the steps are spaced out to keep them apart.
+    for name in &["a", "b", "a", "c"] {
+        download(&options, &dir, name, &[]).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(mock.fetches().len(), 3);
+
+    options.offline = true;
+    assert!(download(&options, &dir, "a", &[]).unwrap().cached);
+    assert!(download(&options, &dir, "c", &[]).unwrap().cached);
+    assert!(download(&options, &dir, "b", &[]).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fails_offline_without_a_cached_copy() {
+    let dir = scratch("offline");
+    let mock = mock(&["a"], &[]);
+    let mut options = options(&mock, &dir, 10 * SIZE as u64);
+    options.offline = true;
+
+    let error = download(&options, &dir, "a", &[]).unwrap_err();
+    assert_eq!(
+        error.what,
+        "Can't download offline: mock://a/a is not in the cache."
+    );
+    assert!(mock.fetches().is_empty());
+
+    // Offline, even a stale copy will do.
+    let mock = {
+        let mock = Arc::new(Mock::new());
+        mock.insert(url("a"), vec![9; SIZE]);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        mock.respond_with(&url("a"), headers);
+        mock
+    };
+    let mut options = self::options(&mock, &dir, 10 * SIZE as u64);
+    download(&options, &dir, "a", &[]).unwrap();
+    options.offline = true;
+    assert!(download(&options, &dir, "a", &[]).unwrap().cached);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
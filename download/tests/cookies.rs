@@ -0,0 +1,159 @@
+//! Cookies that last longer than `SystemTime` can count, and cookies set on
+//! a redirect by a portal on a loopback port.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use download::cookies::CookieJar;
+use download::{rt, Options, Request};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::Url;
+
+const CONTENT: &[u8] = b"members only\n";
+
+/// 9999-12-31T23:59:59Z, when cookies meant to last forever expire.
+fn never() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(253_402_300_799)
+}
+
+#[test]
+fn caps_a_max_age_too_long_to_represent() {
+    let jar = CookieJar::new();
+    let url = Url::parse("https://portal.example.com/").unwrap();
+    let mut headers = HeaderMap::new();
+    for set_cookie in &[
+        "forever=1; Max-Age=9223372036854775807",
+        "hour=2; Max-Age=3600",
+    ] {
+        headers.append(header::SET_COOKIE, HeaderValue::from_static(set_cookie));
+    }
+    jar.store(&url, &headers);
+
+    let mut cookies = jar.cookies();
+    cookies.sort_by(|a, b| a.name.cmp(&b.name));
+    assert_eq!(cookies[0].name, "forever");
+    assert_eq!(cookies[0].expires, Some(never()));
+    assert_eq!(cookies[1].name, "hour");
+    let hour = cookies[1].expires.unwrap();
+    assert!(hour > SystemTime::now() && hour < never());
+    assert_eq!(
+        jar.header(&url).unwrap(),
+        HeaderValue::from_static("forever=1; hour=2")
+    );
+}
+
+#[test]
+fn loads_an_expiry_too_late_to_represent() {
+    let path = std::env::temp_dir().join(format!("download-cookies-{}", std::process::id()));
+    std::fs::write(
+        &path,
+        "portal.example.com\tFALSE\t/\tTRUE\t18446744073709551615\tforever\t1\n",
+    )
+    .unwrap();
+    let jar = rt::block_on(CookieJar::load(&path)).unwrap().unwrap();
+    let cookies = jar.cookies();
+    assert_eq!(cookies.len(), 1);
+    assert_eq!(cookies[0].expires, Some(never()));
+
+    // It is saved with the date it got, which loads again.
+    rt::block_on(jar.save(&path)).unwrap().unwrap();
+    let jar = rt::block_on(CookieJar::load(&path)).unwrap().unwrap();
+    assert_eq!(jar.cookies()[0].expires, Some(never()));
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Run a portal on a loopback port and return its port.  `/start` sets a
+/// session cookie and redirects to `/file`, which is only served to requests
+/// that send the cookie back.
+fn portal() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let mut session = false;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                let lower = line.to_lowercase();
+                session |= lower.starts_with("cookie:") && lower.contains("session=s3cret");
+                line.clear();
+            }
+            let head = request.starts_with("HEAD ");
+            if request.contains(" /start ") {
+                write!(
+                    stream,
+                    "HTTP/1.1 302 Found\r\nLocation: /file\r\n\
+                     Set-Cookie: session=s3cret; Path=/; HttpOnly\r\n\
+                     Content-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+            } else if session {
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    CONTENT.len()
+                )
+                .unwrap();
+                if !head {
+                    stream.write_all(CONTENT).unwrap();
+                }
+            } else {
+                write!(
+                    stream,
+                    "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+            }
+        }
+    });
+    port
+}
+
+/// Download `/start` from a new portal with `client` and `jar`, into the
+/// scratch file `name`.
+fn download_from_portal(
+    client: reqwest::Client,
+    jar: &Arc<CookieJar>,
+    name: &str,
+) -> Result<Vec<u8>, util::Error> {
+    let url = Url::parse(&format!("http://127.0.0.1:{}/start", portal())).unwrap();
+    let options = Options {
+        cookies: Some(jar.clone()),
+        ..Options::new(client)
+    };
+    let mut request = Request::new(url);
+    request.output =
+        std::env::temp_dir().join(format!("download-cookies-{}-{}", name, std::process::id()));
+    let result = rt::block_on(download::download(&request, &options, &|_| {})).unwrap();
+    let content = result.map(|_| std::fs::read(&request.output).unwrap());
+    let _ = std::fs::remove_file(&request.output);
+    content
+}
+
+#[test]
+fn sends_the_cookie_a_redirect_set() {
+    let jar = Arc::new(CookieJar::new());
+    let client = jar.apply(reqwest::Client::builder()).build().unwrap();
+    let content = download_from_portal(client, &jar, "redirect").unwrap();
+    assert_eq!(content, CONTENT);
+    let cookies = jar.cookies();
+    assert_eq!(cookies.len(), 1);
+    assert_eq!(cookies[0].name, "session");
+    assert!(cookies[0].host_only && cookies[0].http_only);
+}
+
+#[test]
+fn loses_the_cookie_when_the_client_follows_redirects() {
+    // Without `CookieJar::apply()`, reqwest follows the redirect itself and
+    // the jar never sees the response that set the cookie.
+    let jar = Arc::new(CookieJar::new());
+    let error = download_from_portal(reqwest::Client::new(), &jar, "lost").unwrap_err();
+    assert!(error.to_string().contains("403 Forbidden"), "{}", error);
+    assert!(jar.cookies().is_empty());
+}
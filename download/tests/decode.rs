@@ -0,0 +1,77 @@
+//! Compressed bodies from a server that doesn't say so when probed.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use download::fetch::{Body, Fetcher, Metadata, Range};
+use download::{rt, Options, Request};
+use futures::future::BoxFuture;
+use futures::stream::{self, StreamExt};
+use reqwest::header::HeaderMap;
+use reqwest::Url;
+
+/// Serves `body` with `Content-Encoding: zstd`, but probes report only its
+/// size on the wire, like a `HEAD` response without `Content-Encoding`.
+#[derive(Debug)]
+struct Zstd {
+    body: Bytes,
+}
+impl Fetcher for Zstd {
+    fn probe<'a>(
+        &'a self,
+        _url: &'a Url,
+        _headers: &'a HeaderMap,
+    ) -> BoxFuture<'a, Result<Metadata, util::Error>> {
+        Box::pin(async move {
+            Ok(Metadata {
+                size: Some(self.body.len() as u64),
+                ..Metadata::default()
+            })
+        })
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        _url: &'a Url,
+        _headers: &'a HeaderMap,
+        _range: Option<Range>,
+    ) -> BoxFuture<'a, Result<Body, util::Error>> {
+        Box::pin(async move {
+            let chunks = stream::iter(vec![Ok(self.body.clone())]).boxed();
+            Ok(Body::new(false, Some("zstd".to_string()), chunks))
+        })
+    }
+}
+
+/// Download `body` from the `Zstd` fetcher into a scratch file called
+/// `name` and return what was written.
+fn download(name: &str, body: Vec<u8>) -> Result<Vec<u8>, util::Error> {
+    let output =
+        std::env::temp_dir().join(format!("download-decode-{}-{}", name, std::process::id()));
+    let mut options = Options::new(reqwest::Client::new());
+    options
+        .fetchers
+        .insert("zstd".to_string(), Arc::new(Zstd { body: body.into() }));
+    let mut request = Request::new(Url::parse("zstd://host/file.txt").unwrap());
+    request.output = output.clone();
+    let result = rt::block_on(download::download(&request, &options, &|_| {})).unwrap();
+    let content = std::fs::read(&output);
+    let _ = std::fs::remove_file(&output);
+    result.map(|_| content.unwrap())
+}
+
+#[test]
+fn decodes_past_the_size_on_the_wire() {
+    let content = b"hello, world\n".repeat(1000);
+    let body = zstd::encode_all(&content[..], 3).unwrap();
+    assert!(body.len() < content.len());
+    assert_eq!(download("whole", body).unwrap(), content);
+}
+
+#[test]
+fn fails_a_truncated_frame() {
+    let content = b"hello, world\n".repeat(1000);
+    let body = zstd::encode_all(&content[..], 3).unwrap();
+    let error = download("truncated", body[..body.len() - 4].to_vec()).unwrap_err();
+    assert_eq!(error.kind, util::ErrorKind::Integrity);
+}
@@ -0,0 +1,101 @@
+//! Archives extracted as they download, served by the `Mock` fetcher.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use download::fetch::Mock;
+use download::journal::Journal;
+use download::{rt, Algorithm, Checksum, Options, Request};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+
+/// Empty directory for the test called `name`.
+fn scratch(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("download-extract-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A `.tar.gz` archive holding `hello.txt`.
+fn archive() -> Vec<u8> {
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        Vec::new(),
+        flate2::Compression::default(),
+    ));
+    let content = b"hello, world\n";
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "hello.txt", &content[..])
+        .unwrap();
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+/// Request for the archive at `url`, extracted into `dir/out`.
+fn request(url: &Url, dir: &std::path::Path, digest: Vec<u8>) -> Request {
+    let mut request = Request::new(url.clone());
+    request.output = dir.join("out.tar.gz");
+    request.extract = Some(dir.join("out"));
+    request.checksum = Some(Checksum {
+        algorithm: Algorithm::Sha256,
+        digest,
+    });
+    request
+}
+
+/// Options serving `mock://` URLs from `mock`.
+fn options(mock: &Arc<Mock>) -> Options {
+    let mut options = Options::new(reqwest::Client::new());
+    options.fetchers.insert("mock".to_string(), mock.clone());
+    options
+}
+
+#[test]
+fn extracts_nothing_when_the_checksum_fails() {
+    let dir = scratch("checksum");
+    let url = Url::parse("mock://host/out.tar.gz").unwrap();
+    let mock = Arc::new(Mock::new());
+    mock.insert(url.clone(), archive());
+
+    let request = request(&url, &dir, vec![0; 32]);
+    let result = rt::block_on(download::download(&request, &options(&mock), &|_| {})).unwrap();
+    assert!(result.is_err());
+    assert!(!dir.join("out").exists());
+    assert!(!dir.join("out.extracting").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn extracts_a_verified_archive_once() {
+    let dir = scratch("verified");
+    let url = Url::parse("mock://host/out.tar.gz").unwrap();
+    let mock = Arc::new(Mock::new());
+    let archive = archive();
+    mock.insert(url.clone(), archive.clone());
+
+    let request = request(&url, &dir, Sha256::digest(&archive).to_vec());
+    let journal = dir.join("journal.json");
+    for _ in 0..2 {
+        rt::block_on(async {
+            let mut options = options(&mock);
+            options.journal = Some(Arc::new(Journal::open(&journal).await.unwrap()));
+            download::download(&request, &options, &|_| {})
+                .await
+                .unwrap()
+        })
+        .unwrap();
+    }
+    assert_eq!(
+        std::fs::read(dir.join("out").join("hello.txt")).unwrap(),
+        b"hello, world\n"
+    );
+    assert!(!dir.join("out.extracting").exists());
+    // The archive wasn't written, but the second run knew it was done.
+    assert!(!dir.join("out.tar.gz").exists());
+    assert_eq!(mock.fetches().len(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
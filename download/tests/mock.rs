@@ -0,0 +1,483 @@
+//! Downloads and fetchers driven through the `Mock`, `File`, and `Data`
+//! fetchers, without a server.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use download::fetch::{Body, Data, Fetcher, File, Metadata, Mock, Range};
+use download::journal::Journal;
+use download::{rt, Algorithm, Checksum, Options, Outcome, Pieces, Request, Retries};
+use futures::future::BoxFuture;
+use reqwest::header::{self, HeaderMap};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+
+/// Size of the files served, a few chunks long.
+const SIZE: usize = 200_000;
+
+/// Length of the pieces of a file with piece hashes, so it has four.
+const PIECE: u64 = 50_000;
+
+/// File content that differs at every offset, so a splice shows.
+fn content(seed: u8) -> Vec<u8> {
+    (0..SIZE).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// Scratch output file for the test called `name`.
+fn output(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("download-mock-{}-{}", name, std::process::id()))
+}
+
+/// Download `url`, then `mirrors`, from `mock` into the scratch file `name`,
+/// moving on to the next mirror as soon as one fails.  Returns the outcome
+/// and what was written.
+fn download(
+    mock: &Arc<Mock>,
+    name: &str,
+    url: &Url,
+    mirrors: &[&Url],
+    checksum: Option<&[u8]>,
+) -> (Result<Outcome, util::Error>, Vec<u8>) {
+    download_with(Retries::none(), mock, name, url, mirrors, checksum)
+}
+
+/// Download as `download()` does, but trying each mirror again as `retries`
+/// says.
+fn download_with(
+    retries: Retries,
+    mock: &Arc<Mock>,
+    name: &str,
+    url: &Url,
+    mirrors: &[&Url],
+    checksum: Option<&[u8]>,
+) -> (Result<Outcome, util::Error>, Vec<u8>) {
+    let mut options = Options::new(reqwest::Client::new());
+    options.retries = retries;
+    options.fetchers.insert("mock".to_string(), mock.clone());
+    let mut request = Request::new(url.clone());
+    request.output = output(name);
+    request.mirrors = mirrors.iter().map(|&url| url.clone()).collect();
+    request.checksum = checksum.map(|content| Checksum {
+        algorithm: Algorithm::Sha256,
+        digest: Sha256::digest(content).to_vec(),
+    });
+    let result = rt::block_on(download::download(&request, &options, &|_| {})).unwrap();
+    let written = std::fs::read(&request.output).unwrap_or_default();
+    let _ = std::fs::remove_file(&request.output);
+    (result, written)
+}
+
+/// Download `url`, then `mirrors`, from `mock` into the scratch file `name`,
+/// checking it against the SHA-256 digests of `PIECE`-byte pieces of
+/// `content`.  Returns the outcome and what was written.
+fn download_pieces(
+    mock: &Arc<Mock>,
+    name: &str,
+    url: &Url,
+    mirrors: &[&Url],
+    content: &[u8],
+) -> (Result<Outcome, util::Error>, Vec<u8>) {
+    let mut options = Options::new(reqwest::Client::new());
+    options.fetchers.insert("mock".to_string(), mock.clone());
+    let mut request = Request::new(url.clone());
+    request.output = output(name);
+    request.mirrors = mirrors.iter().map(|&url| url.clone()).collect();
+    request.pieces = Some(Pieces {
+        length: PIECE,
+        algorithm: Algorithm::Sha256,
+        digests: content
+            .chunks(PIECE as usize)
+            .map(|piece| Sha256::digest(piece).to_vec())
+            .collect(),
+    });
+    let result = rt::block_on(download::download(&request, &options, &|_| {})).unwrap();
+    let written = std::fs::read(&request.output).unwrap_or_default();
+    let _ = std::fs::remove_file(&request.output);
+    (result, written)
+}
+
+/// Everything left in `body`.
+fn read(mut body: Body) -> Vec<u8> {
+    rt::block_on(async move {
+        let mut content = Vec::new();
+        while let Some(chunk) = body.chunk().await.unwrap() {
+            content.extend_from_slice(&chunk);
+        }
+        content
+    })
+    .unwrap()
+}
+
+/// Fetch `range` of `url` from `fetcher`.
+fn fetch(fetcher: &dyn Fetcher, url: &Url, range: Option<Range>) -> Result<Body, util::Error> {
+    rt::block_on(fetcher.fetch(url, &HeaderMap::new(), range)).unwrap()
+}
+
+fn url(s: &str) -> Url {
+    Url::parse(s).unwrap()
+}
+
+#[test]
+fn serves_a_range() {
+    let mock = Mock::new();
+    let file = url("mock://a/file");
+    mock.insert(file.clone(), content(0));
+    let range = Range {
+        start: 1000,
+        end: Some(2000),
+    };
+    let body = fetch(&mock, &file, Some(range)).unwrap();
+    assert!(body.partial);
+    assert_eq!(read(body), &content(0)[1000..2000]);
+    assert_eq!(mock.fetches(), vec![(file, Some(range))]);
+}
+
+#[test]
+fn serves_the_whole_file_when_ignoring_the_range() {
+    let mock = Mock::new();
+    let file = url("mock://a/file");
+    mock.insert(file.clone(), content(0));
+    mock.ignore_ranges(&file);
+    let range = Range {
+        start: 1000,
+        end: None,
+    };
+    let body = fetch(&mock, &file, Some(range)).unwrap();
+    assert!(!body.partial);
+    assert_eq!(read(body), content(0));
+}
+
+#[test]
+fn downloads_a_file() {
+    let mock = Arc::new(Mock::new());
+    let file = url("mock://a/file");
+    mock.insert(file.clone(), content(0));
+    let (result, written) = download(&mock, "plain", &file, &[], Some(&content(0)));
+    assert_eq!(result.unwrap().bytes, SIZE as u64);
+    assert_eq!(written, content(0));
+    assert_eq!(mock.fetches(), vec![(file, None)]);
+}
+
+#[test]
+fn continues_on_a_mirror_after_a_reset() {
+    let mock = Arc::new(Mock::new());
+    let (main, mirror) = (url("mock://a/file"), url("mock://b/file"));
+    mock.insert(main.clone(), content(0));
+    mock.insert(mirror.clone(), content(0));
+    mock.fail_after(&main, 100_000);
+
+    let (result, written) = download(&mock, "reset", &main, &[&mirror], Some(&content(0)));
+    assert_eq!(result.unwrap().mirrors, vec![main.clone(), mirror.clone()]);
+    assert_eq!(written, content(0));
+    let range = Range {
+        start: 100_000,
+        end: Some(SIZE as u64),
+    };
+    assert_eq!(mock.fetches(), vec![(main, None), (mirror, Some(range))]);
+}
+
+#[test]
+fn retries_the_same_mirror_after_a_reset() {
+    let mock = Arc::new(Mock::new());
+    let (main, mirror) = (url("mock://a/file"), url("mock://b/file"));
+    mock.insert(main.clone(), content(0));
+    mock.insert(mirror.clone(), content(0));
+    mock.fail_after(&main, 100_000);
+
+    let retries = Retries {
+        attempts: 1,
+        delay: Duration::from_millis(10),
+    };
+    let (result, written) = download_with(
+        retries,
+        &mock,
+        "retried",
+        &main,
+        &[&mirror],
+        Some(&content(0)),
+    );
+    assert_eq!(result.unwrap().mirrors, vec![main.clone()]);
+    assert_eq!(written, content(0));
+    let range = Range {
+        start: 100_000,
+        end: Some(SIZE as u64),
+    };
+    assert_eq!(
+        mock.fetches(),
+        vec![(main.clone(), None), (main, Some(range))]
+    );
+}
+
+#[test]
+fn moves_on_when_the_retries_run_out() {
+    let mock = Arc::new(Mock::new());
+    let (main, mirror) = (url("mock://a/file"), url("mock://b/file"));
+    mock.insert(main.clone(), content(0));
+    mock.insert(mirror.clone(), content(0));
+    mock.fail_after(&main, 50_000);
+
+    let retries = Retries {
+        attempts: 1,
+        delay: Duration::from_millis(10),
+    };
+    let (result, written) = download_with(
+        retries,
+        &mock,
+        "retries-out",
+        &main,
+        &[&mirror],
+        Some(&content(0)),
+    );
+    assert_eq!(result.unwrap().mirrors, vec![main.clone(), mirror.clone()]);
+    assert_eq!(written, content(0));
+    let range = |start| Range {
+        start,
+        end: Some(SIZE as u64),
+    };
+    assert_eq!(
+        mock.fetches(),
+        vec![
+            (main.clone(), None),
+            (main, Some(range(50_000))),
+            (mirror, Some(range(100_000))),
+        ]
+    );
+}
+
+#[test]
+fn skips_what_it_has_when_the_mirror_ignores_the_range() {
+    let mock = Arc::new(Mock::new());
+    let (main, mirror) = (url("mock://a/file"), url("mock://b/file"));
+    mock.insert(main.clone(), content(0));
+    mock.insert(mirror.clone(), content(0));
+    mock.fail_after(&main, 100_000);
+    mock.ignore_ranges(&mirror);
+
+    let (result, written) = download(&mock, "ignored", &main, &[&mirror], Some(&content(0)));
+    result.unwrap();
+    assert_eq!(written, content(0));
+}
+
+#[test]
+fn starts_over_on_a_mirror_with_a_different_file() {
+    // The mirror has another version of the file, the same size.  Continuing
+    // there would splice the two versions together.
+    let mock = Arc::new(Mock::new());
+    let (main, mirror) = (url("mock://a/file"), url("mock://b/file"));
+    mock.insert(main.clone(), content(0));
+    mock.insert(mirror.clone(), content(1));
+    mock.fail_after(&main, 100_000);
+
+    let (result, written) = download(&mock, "changed", &main, &[&mirror], None);
+    result.unwrap();
+    assert_eq!(written, content(1));
+    assert_eq!(mock.fetches(), vec![(main, None), (mirror, None)]);
+}
+
+#[test]
+fn fails_when_every_mirror_resets() {
+    let mock = Arc::new(Mock::new());
+    let (main, mirror) = (url("mock://a/file"), url("mock://b/file"));
+    mock.insert(main.clone(), content(0));
+    mock.insert(mirror.clone(), content(0));
+    mock.fail_after(&main, 100_000);
+    mock.fail_after(&mirror, 50_000);
+
+    let (result, _) = download(&mock, "failed", &main, &[&mirror], Some(&content(0)));
+    assert!(result.is_err());
+}
+
+#[test]
+fn refetches_only_the_corrupt_piece() {
+    let mock = Arc::new(Mock::new());
+    let (main, mirror) = (url("mock://a/file"), url("mock://b/file"));
+    mock.insert(main.clone(), content(0));
+    mock.insert(mirror.clone(), content(0));
+    // Damage the third piece on the way from the main URL.
+    mock.corrupt(&main, 2 * PIECE + 1234);
+
+    let (result, written) = download_pieces(&mock, "repaired", &main, &[&mirror], &content(0));
+    assert_eq!(result.unwrap().mirrors, vec![main.clone(), mirror.clone()]);
+    assert_eq!(written, content(0));
+    let range = Range {
+        start: 2 * PIECE,
+        end: Some(3 * PIECE),
+    };
+    assert_eq!(mock.fetches(), vec![(main, None), (mirror, Some(range))]);
+}
+
+#[test]
+fn gives_up_on_pieces_that_stay_corrupt() {
+    let mock = Arc::new(Mock::new());
+    let file = url("mock://a/file");
+    mock.insert(file.clone(), content(0));
+    mock.corrupt(&file, PIECE + 1);
+
+    let (result, _) = download_pieces(&mock, "corrupt", &file, &[], &content(0));
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, util::ErrorKind::Integrity);
+    assert!(error.to_string().contains("still corrupt after 3 repairs"));
+    // The whole file once, then the piece in every repair.
+    let range = Range {
+        start: PIECE,
+        end: Some(2 * PIECE),
+    };
+    let mut fetches = vec![(file.clone(), None)];
+    fetches.extend(vec![(file, Some(range)); 3]);
+    assert_eq!(mock.fetches(), fetches);
+}
+
+/// Fetcher for a server that sends neither an `ETag` nor a `Last-Modified`
+/// date, serving the files of a `Mock`.
+#[derive(Debug)]
+struct Unvalidated(Arc<Mock>);
+impl Fetcher for Unvalidated {
+    fn probe<'a>(
+        &'a self,
+        url: &'a Url,
+        headers: &'a HeaderMap,
+    ) -> BoxFuture<'a, Result<Metadata, util::Error>> {
+        Box::pin(async move {
+            let probe = self.0.probe(url, headers).await?;
+            Ok(Metadata {
+                etag: None,
+                last_modified: None,
+                not_modified: false,
+                ..probe
+            })
+        })
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        url: &'a Url,
+        headers: &'a HeaderMap,
+        range: Option<Range>,
+    ) -> BoxFuture<'a, Result<Body, util::Error>> {
+        self.0.fetch(url, headers, range)
+    }
+}
+
+#[test]
+fn downloads_again_when_a_file_without_validators_changes_size() {
+    let mock = Arc::new(Mock::new());
+    let file = url("mock://a/file");
+    mock.insert(file.clone(), content(0));
+    let journal = output("unvalidated.json");
+    let _ = std::fs::remove_file(&journal);
+    let mut options = Options::new(reqwest::Client::new());
+    options
+        .fetchers
+        .insert("mock".to_string(), Arc::new(Unvalidated(mock.clone())));
+    options.journal = Some(Arc::new(
+        rt::block_on(Journal::open(&journal)).unwrap().unwrap(),
+    ));
+    let mut request = Request::new(file.clone());
+    request.output = output("unvalidated");
+    let download = || rt::block_on(download::download(&request, &options, &|_| {})).unwrap();
+
+    assert!(!download().unwrap().up_to_date);
+    // The server can only be asked for the size, which is the same.
+    assert!(download().unwrap().up_to_date);
+    assert_eq!(mock.fetches().len(), 1);
+
+    // A file of another size is downloaded again.
+    let changed = content(1)[..SIZE - 1000].to_vec();
+    mock.insert(file, changed.clone());
+    let outcome = download().unwrap();
+    assert!(!outcome.up_to_date);
+    assert_eq!(outcome.bytes, changed.len() as u64);
+    assert_eq!(std::fs::read(&request.output).unwrap(), changed);
+    assert_eq!(mock.fetches().len(), 2);
+    std::fs::remove_file(&request.output).unwrap();
+    std::fs::remove_file(&journal).unwrap();
+}
+
+#[test]
+fn parses_data_urls() {
+    let cases: &[(&str, &str, &[u8])] = &[
+        (
+            "data:,Hello%2C%20World!",
+            "text/plain;charset=US-ASCII",
+            b"Hello, World!",
+        ),
+        ("data:text/plain;base64,SGVsbG8=", "text/plain", b"Hello"),
+        (
+            "data:application/octet-stream;BASE64,AAEC",
+            "application/octet-stream",
+            b"\0\x01\x02",
+        ),
+    ];
+    for &(data, media_type, expected) in cases {
+        let data = url(data);
+        let probe = rt::block_on(Data.probe(&data, &HeaderMap::new()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(probe.size, Some(expected.len() as u64), "{}", data);
+        assert_eq!(probe.headers[header::CONTENT_TYPE], media_type, "{}", data);
+        assert_eq!(
+            read(fetch(&Data, &data, None).unwrap()),
+            expected,
+            "{}",
+            data
+        );
+    }
+
+    let range = Range {
+        start: 1,
+        end: Some(3),
+    };
+    let body = fetch(&Data, &url("data:,Hello"), Some(range)).unwrap();
+    assert_eq!(read(body), b"el");
+    assert!(fetch(&Data, &url("data:text/plain;base64,!!!"), None).is_err());
+    assert!(fetch(&Data, &url("data:no-comma"), None).is_err());
+}
+
+#[test]
+fn reads_file_ranges_up_to_the_end() {
+    let path = output("file-range");
+    std::fs::write(&path, b"0123456789").unwrap();
+    let file = Url::from_file_path(&path).unwrap();
+
+    let read_range = |start, end| read(fetch(&File, &file, Some(Range { start, end })).unwrap());
+    assert_eq!(read_range(2, Some(5)), b"234");
+    assert_eq!(read_range(5, Some(100)), b"56789");
+    assert_eq!(read_range(8, None), b"89");
+    assert_eq!(read_range(10, None), b"");
+    assert_eq!(read_range(20, Some(30)), b"");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn downloads_a_batch_by_priority() {
+    let mock = Arc::new(Mock::new());
+    let mut options = Options::new(reqwest::Client::new());
+    options.fetchers.insert("mock".to_string(), mock.clone());
+    let mut requests = Vec::new();
+    for (i, priority) in [0, 2, 1].iter().enumerate() {
+        let file = url(&format!("mock://a/{}", i));
+        mock.insert(file.clone(), content(i as u8));
+        let mut request = Request::new(file);
+        request.output = output(&format!("batch-{}", i));
+        request.priority = *priority;
+        requests.push(request);
+    }
+
+    let done = std::sync::Mutex::new(vec![0; requests.len()]);
+    let results = download::download_batch_blocking(&requests, &options, 1, |i, bytes, total| {
+        assert_eq!(total, Some(SIZE as u64));
+        done.lock().unwrap()[i] = bytes;
+    })
+    .unwrap();
+    let fetched: Vec<Url> = mock.fetches().into_iter().map(|(url, _)| url).collect();
+    assert_eq!(
+        fetched,
+        vec![url("mock://a/1"), url("mock://a/2"), url("mock://a/0")]
+    );
+    assert_eq!(done.into_inner().unwrap(), vec![SIZE as u64; 3]);
+    for (i, (request, result)) in requests.iter().zip(results).enumerate() {
+        assert_eq!(result.unwrap().bytes, SIZE as u64);
+        assert_eq!(std::fs::read(&request.output).unwrap(), content(i as u8));
+        std::fs::remove_file(&request.output).unwrap();
+    }
+}
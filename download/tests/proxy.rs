@@ -0,0 +1,135 @@
+//! Downloads through a SOCKS5 proxy that wants a password.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use download::proxy::Proxies;
+use download::{rt, Options, Request};
+use reqwest::Url;
+
+const CONTENT: &[u8] = b"hello through the proxy\n";
+
+/// Serve `CONTENT` to every request on a loopback port and return the port.
+fn serve() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let head = line.starts_with("HEAD ");
+            while line != "\r\n" {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                CONTENT.len()
+            )
+            .unwrap();
+            if !head {
+                stream.write_all(CONTENT).unwrap();
+            }
+        }
+    });
+    port
+}
+
+/// What the SOCKS5 proxy was asked: the username, password, and host.
+type Seen = Arc<Mutex<Vec<(String, String, String)>>>;
+
+/// Run a SOCKS5 proxy that wants a password on a loopback port and connects
+/// every host to `127.0.0.1`, and return its port.
+fn socks5(seen: Seen) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let seen = seen.clone();
+            thread::spawn(move || relay(stream.unwrap(), &seen));
+        }
+    });
+    port
+}
+
+/// Serve a client of the SOCKS5 proxy.
+fn relay(mut client: TcpStream, seen: &Seen) {
+    let read = |client: &mut TcpStream, n: usize| {
+        let mut buf = vec![0; n];
+        client.read_exact(&mut buf).unwrap();
+        buf
+    };
+    // Methods: only username and password, 2.
+    let methods = read(&mut client, 2)[1] as usize;
+    assert!(read(&mut client, methods).contains(&2));
+    client.write_all(&[5, 2]).unwrap();
+
+    let len = read(&mut client, 2)[1] as usize;
+    let username = String::from_utf8(read(&mut client, len)).unwrap();
+    let len = read(&mut client, 1)[0] as usize;
+    let password = String::from_utf8(read(&mut client, len)).unwrap();
+    client.write_all(&[1, 0]).unwrap();
+
+    // CONNECT to a domain name, 3, which the proxy resolves.
+    assert_eq!(read(&mut client, 4), [5, 1, 0, 3]);
+    let len = read(&mut client, 1)[0] as usize;
+    let host = String::from_utf8(read(&mut client, len)).unwrap();
+    let port = read(&mut client, 2);
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    seen.lock().unwrap().push((username, password, host));
+
+    let mut server = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+    let (mut from_client, mut to_server) =
+        (client.try_clone().unwrap(), server.try_clone().unwrap());
+    thread::spawn(move || std::io::copy(&mut from_client, &mut to_server));
+    let _ = std::io::copy(&mut server, &mut client);
+}
+
+#[test]
+fn downloads_through_a_socks5_proxy() {
+    let seen = Seen::default();
+    let proxy = socks5(seen.clone());
+    let server = serve();
+
+    let mut proxies = Proxies::new();
+    proxies
+        .set_all(&format!("socks5h://127.0.0.1:{}", proxy))
+        .unwrap();
+    proxies.set_credentials("build", "s3cret");
+    let client = proxies
+        .apply(reqwest::Client::builder())
+        .unwrap()
+        .build()
+        .unwrap();
+    let options = Options {
+        proxies: Some(Arc::new(proxies)),
+        ..Options::new(client)
+    };
+
+    // Only the proxy knows the host.
+    let url = Url::parse(&format!("http://files.invalid:{}/file.txt", server)).unwrap();
+    let mut request = Request::new(url);
+    request.output = std::env::temp_dir().join(format!("download-proxy-{}", std::process::id()));
+    let outcome = rt::block_on(download::download(&request, &options, &|_| {}))
+        .unwrap()
+        .unwrap();
+    assert_eq!(std::fs::read(&request.output).unwrap(), CONTENT);
+    std::fs::remove_file(&request.output).unwrap();
+
+    let proxy_url = Url::parse(&format!("socks5h://127.0.0.1:{}", proxy)).unwrap();
+    assert_eq!(outcome.proxies, vec![proxy_url]);
+    let seen = seen.lock().unwrap();
+    assert!(!seen.is_empty());
+    for (username, password, host) in seen.iter() {
+        assert_eq!(
+            (username.as_str(), password.as_str(), host.as_str()),
+            ("build", "s3cret", "files.invalid")
+        );
+    }
+}
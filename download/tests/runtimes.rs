@@ -0,0 +1,121 @@
+//! The download core on each runtime it supports: tokio with the `rt-tokio`
+//! feature and async-std with the `rt-async-std` feature.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use download::fetch::Mock;
+use download::{rt, Options, Request};
+use reqwest::Url;
+
+/// Content of the files downloaded, a few chunks long.
+fn content() -> Vec<u8> {
+    (0..200_000u32).map(|i| (i % 251) as u8).collect()
+}
+
+/// Scratch output file for the test called `name` on `runtime`.
+fn output(runtime: &str, name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!(
+        "download-{}-{}-{}",
+        runtime,
+        name,
+        std::process::id()
+    ))
+}
+
+/// Download from memory, failing over to a mirror half way through.
+async fn downloads_from_memory(runtime: &str) {
+    let mock = Arc::new(Mock::new());
+    let (main, mirror) = (
+        Url::parse("mock://a/file").unwrap(),
+        Url::parse("mock://b/file").unwrap(),
+    );
+    mock.insert(main.clone(), content());
+    mock.insert(mirror.clone(), content());
+    mock.fail_after(&main, 100_000);
+
+    let mut options = Options::new(reqwest::Client::new());
+    options.fetchers.insert("mock".to_string(), mock.clone());
+    let mut request = Request::new(main);
+    request.mirrors = vec![mirror];
+    request.output = output(runtime, "memory");
+    let outcome = download::download(&request, &options, &|_| {})
+        .await
+        .unwrap();
+    assert_eq!(outcome.bytes, 200_000);
+    assert_eq!(rt::fs::read(&request.output).await.unwrap(), content());
+    rt::fs::remove_file(&request.output).await.unwrap();
+}
+
+/// Download a local file through the built-in `file:` fetcher.
+async fn downloads_a_local_file(runtime: &str) {
+    let source = output(runtime, "source");
+    rt::fs::write(&source, content()).await.unwrap();
+    let mut request = Request::new(Url::from_file_path(&source).unwrap());
+    request.output = output(runtime, "copy");
+    let options = Options::new(reqwest::Client::new());
+    let outcome = download::download(&request, &options, &|_| {})
+        .await
+        .unwrap();
+    assert_eq!(outcome.bytes, 200_000);
+    assert_eq!(rt::fs::read(&request.output).await.unwrap(), content());
+    rt::fs::remove_file(&request.output).await.unwrap();
+    rt::fs::remove_file(&source).await.unwrap();
+}
+
+/// Timers, tasks, and blocking code.
+async fn runs_timers_and_tasks() {
+    let started = Instant::now();
+    rt::sleep(Duration::from_millis(20)).await;
+    assert!(started.elapsed() >= Duration::from_millis(20));
+
+    let started = Instant::now();
+    let slow = rt::timeout(
+        Duration::from_millis(20),
+        rt::sleep(Duration::from_secs(10)),
+    )
+    .await;
+    assert_eq!(slow, None);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let fast = rt::timeout(Duration::from_secs(10), async { 42 }).await;
+    assert_eq!(fast, Some(42));
+
+    assert_eq!(rt::spawn(async { 1 + 1 }).await.unwrap(), 2);
+    assert_eq!(rt::spawn_blocking(|| 2 + 2).await.unwrap(), 4);
+}
+
+#[cfg(feature = "rt-tokio")]
+mod on_tokio {
+    #[tokio::test]
+    async fn downloads_from_memory() {
+        super::downloads_from_memory("tokio").await
+    }
+
+    #[tokio::test]
+    async fn downloads_a_local_file() {
+        super::downloads_a_local_file("tokio").await
+    }
+
+    #[tokio::test]
+    async fn runs_timers_and_tasks() {
+        super::runs_timers_and_tasks().await
+    }
+}
+
+#[cfg(feature = "rt-async-std")]
+mod on_async_std {
+    #[async_std::test]
+    async fn downloads_from_memory() {
+        super::downloads_from_memory("async-std").await
+    }
+
+    #[async_std::test]
+    async fn downloads_a_local_file() {
+        super::downloads_a_local_file("async-std").await
+    }
+
+    #[async_std::test]
+    async fn runs_timers_and_tasks() {
+        super::runs_timers_and_tasks().await
+    }
+}
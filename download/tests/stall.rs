@@ -0,0 +1,172 @@
+//! Timeouts and stall detection, with the `Mock` fetcher pausing bodies and
+//! a loopback server that never answers.
+
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use download::fetch::Mock;
+use download::{rt, MinSpeed, Options, Outcome, Request, Retries, Timeouts};
+use reqwest::Url;
+
+/// Size of the files served, a few chunks long.
+const SIZE: usize = 200_000;
+
+/// Progress that remembers every status it was shown.
+#[derive(Default)]
+struct Statuses(Mutex<Vec<String>>);
+impl download::Progress for Statuses {
+    fn advance(&self, _n: u64) {}
+
+    fn set_status(&self, status: &str) {
+        self.0.lock().unwrap().push(status.to_string());
+    }
+}
+
+/// Download `url` with `options` into the scratch file `name`, without
+/// retries, reporting to `progress`.
+fn download(
+    options: Options,
+    name: &str,
+    url: &Url,
+    progress: &Statuses,
+) -> Result<Outcome, util::Error> {
+    let options = Options {
+        retries: Retries::none(),
+        ..options
+    };
+    let mut request = Request::new(url.clone());
+    request.output =
+        std::env::temp_dir().join(format!("download-stall-{}-{}", name, std::process::id()));
+    let result = rt::block_on(download::download(&request, &options, progress)).unwrap();
+    let _ = std::fs::remove_file(&request.output);
+    result
+}
+
+/// Options that fetch `mock://` URLs from `mock`, with `timeouts`.
+fn mock_options(mock: &Arc<Mock>, timeouts: Timeouts) -> Options {
+    let mut options = Options::new(reqwest::Client::new());
+    options.fetchers.insert("mock".to_string(), mock.clone());
+    options.timeouts = timeouts;
+    options
+}
+
+/// Mock serving a file at `mock://a/file` whose body stops for `duration`
+/// after `bytes` bytes.
+fn pausing(bytes: u64, duration: Duration) -> (Arc<Mock>, Url) {
+    let mock = Arc::new(Mock::new());
+    let url = Url::parse("mock://a/file").unwrap();
+    mock.insert(url.clone(), vec![7u8; SIZE]);
+    mock.pause(&url, bytes, duration);
+    (mock, url)
+}
+
+#[test]
+fn times_out_waiting_for_the_first_byte() {
+    // The operating system accepts the connection, but nobody answers.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/file", listener.local_addr().unwrap())).unwrap();
+    let timeouts = Timeouts {
+        first_byte: Some(Duration::from_millis(300)),
+        ..Default::default()
+    };
+    let options = Options {
+        timeouts,
+        ..Options::new(reqwest::Client::new())
+    };
+
+    let started = Instant::now();
+    let error = download(options, "first-byte", &url, &Statuses::default()).unwrap_err();
+    assert_eq!(error.kind, util::ErrorKind::Timeout);
+    assert!(error
+        .to_string()
+        .contains("No response from server within 0.3 seconds."));
+    assert!(started.elapsed() < Duration::from_secs(5));
+    drop(listener);
+}
+
+#[test]
+fn times_out_when_the_body_stops() {
+    let (mock, url) = pausing(100_000, Duration::from_secs(10));
+    let timeouts = Timeouts {
+        idle: Some(Duration::from_millis(500)),
+        ..Default::default()
+    };
+
+    let started = Instant::now();
+    let options = mock_options(&mock, timeouts);
+    let error = download(options, "idle", &url, &Statuses::default()).unwrap_err();
+    assert_eq!(error.kind, util::ErrorKind::Timeout);
+    assert!(error
+        .to_string()
+        .contains("No data received for 0.5 seconds."));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn waits_out_a_pause_shorter_than_the_idle_timeout() {
+    let (mock, url) = pausing(100_000, Duration::from_millis(300));
+    let timeouts = Timeouts {
+        idle: Some(Duration::from_secs(2)),
+        ..Default::default()
+    };
+
+    let options = mock_options(&mock, timeouts);
+    let outcome = download(options, "short-pause", &url, &Statuses::default()).unwrap();
+    assert_eq!(outcome.bytes, SIZE as u64);
+}
+
+#[test]
+fn times_out_below_the_minimum_speed() {
+    let (mock, url) = pausing(100_000, Duration::from_secs(10));
+    let timeouts = Timeouts {
+        min_speed: Some(MinSpeed {
+            bytes_per_sec: 1_000_000,
+            window: Duration::from_millis(500),
+        }),
+        ..Default::default()
+    };
+
+    let started = Instant::now();
+    let options = mock_options(&mock, timeouts);
+    let error = download(options, "min-speed", &url, &Statuses::default()).unwrap_err();
+    assert_eq!(error.kind, util::ErrorKind::Timeout);
+    let message = "Download slower than 1000000 bytes/s for 0.5 seconds.";
+    assert!(error.to_string().contains(message));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn keeps_going_above_the_minimum_speed() {
+    let (mock, url) = pausing(100_000, Duration::from_millis(100));
+    let timeouts = Timeouts {
+        min_speed: Some(MinSpeed {
+            bytes_per_sec: 1_000,
+            window: Duration::from_millis(500),
+        }),
+        ..Default::default()
+    };
+
+    let options = mock_options(&mock, timeouts);
+    let outcome = download(options, "fast-enough", &url, &Statuses::default()).unwrap();
+    assert_eq!(outcome.bytes, SIZE as u64);
+}
+
+#[test]
+fn shows_a_stalled_download() {
+    // With a 2 second idle timeout, the download counts as stalled after 1.
+    let (mock, url) = pausing(100_000, Duration::from_millis(1500));
+    let timeouts = Timeouts {
+        idle: Some(Duration::from_secs(2)),
+        ..Default::default()
+    };
+
+    let progress = Statuses::default();
+    let options = mock_options(&mock, timeouts);
+    download(options, "stalled", &url, &progress).unwrap();
+    // The mirror's status, then the same marked stalled, then back again.
+    let statuses = progress.0.lock().unwrap();
+    assert!(statuses.windows(3).any(|w| {
+        w[0].ends_with(" (a)") && w[1] == format!("{} stalled", w[0]) && w[2] == w[0]
+    }));
+}
@@ -0,0 +1,286 @@
+//! Local TCP proxy that injects scripted faults into HTTP responses.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{AbortHandle, Abortable, Either};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{ReadHalf, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Faults to inject into the response on a single connection.  Every field
+/// defaults to `None`, meaning the response is forwarded untouched.  Example:
+///
+/// ```ignore
+/// let fault = Fault {
+///     bandwidth: Some(64 * 1024),
+///     reset_after: Some(100_000),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Fault {
+    /// Delay before the first byte of the response is forwarded.
+    pub latency: Option<Duration>,
+    /// Maximum number of response bytes forwarded per second.
+    pub bandwidth: Option<u64>,
+    /// Reset the connection after forwarding this many bytes of the body.
+    pub reset_after: Option<u64>,
+    /// Close the connection cleanly after forwarding this many bytes of the
+    /// body.
+    pub truncate_after: Option<u64>,
+    /// Replace the value of the `Content-Length` header, or add one if the
+    /// server did not send it.
+    pub content_length: Option<u64>,
+}
+
+/// Sequence of faults applied to successive connections.
+#[derive(Clone, Debug, Default)]
+pub struct Script {
+    /// Faults for the first, second, third... connection.
+    pub sequence: Vec<Fault>,
+    /// Fault for every connection after the sequence is exhausted.
+    pub then: Fault,
+}
+impl Script {
+    /// Apply the faults in `sequence` to the first connections, then forward
+    /// all remaining connections untouched.
+    pub fn new(sequence: Vec<Fault>) -> Self {
+        Script { sequence, then: Fault::default() }
+    }
+
+    /// Apply the same `fault` to every connection.
+    pub fn always(fault: Fault) -> Self {
+        Script { sequence: Vec::new(), then: fault }
+    }
+
+    /// Fault to apply to the connection with zero-based index `n`.
+    pub fn fault(&self, n: usize) -> &Fault {
+        self.sequence.get(n).unwrap_or(&self.then)
+    }
+}
+
+/// Handle to a running proxy.  The proxy stops accepting connections when the
+/// handle is dropped.
+#[derive(Debug)]
+pub struct FaultProxy {
+    addr: SocketAddr,
+    connections: Arc<AtomicUsize>,
+    abort: AbortHandle,
+}
+impl FaultProxy {
+    /// Start a proxy on an ephemeral port of the loopback interface that
+    /// forwards connections to `upstream` according to `script`.
+    pub async fn start(upstream: &str, script: Script) -> Result<Self, util::BoxError> {
+        Self::start_on("127.0.0.1:0", upstream, script).await
+    }
+
+    /// Start a proxy listening on `listen` that forwards connections to
+    /// `upstream` according to `script`.
+    pub async fn start_on(
+        listen: &str,
+        upstream: &str,
+        script: Script,
+    ) -> Result<Self, util::BoxError> {
+        let mut listener = TcpListener::bind(listen).await?;
+        let addr = listener.local_addr()?;
+        let connections = Arc::new(AtomicUsize::new(0));
+        let upstream = upstream.to_string();
+
+        // Accept connections until the handle is dropped.
+        let accept = {
+            let connections = connections.clone();
+            async move {
+                loop {
+                    let (client, _) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(_) => continue,
+                    };
+                    let n = connections.fetch_add(1, Ordering::SeqCst);
+                    let fault = script.fault(n).clone();
+                    let upstream = upstream.clone();
+
+                    // Errors on one connection are the client's problem, not
+                    // the proxy's, so they are deliberately ignored.
+                    tokio::spawn(async move {
+                        let _ = proxy_connection(client, &upstream, fault).await;
+                    });
+                }
+            }
+        };
+        let (abort, registration) = AbortHandle::new_pair();
+        tokio::spawn(Abortable::new(accept, registration));
+
+        Ok(FaultProxy { addr, connections, abort })
+    }
+
+    /// Address on which the proxy is listening.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// URL for `path` (which should begin with `/`) on the proxy.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Number of connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+impl Drop for FaultProxy {
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
+
+/// Forward one client connection to `upstream`, injecting `fault` into the
+/// response.
+async fn proxy_connection(
+    mut client: TcpStream,
+    upstream: &str,
+    fault: Fault,
+) -> std::io::Result<()> {
+    let mut server = TcpStream::connect(upstream).await?;
+
+    // Borrow rather than move the halves of each stream.  Owned halves shut
+    // down the socket when dropped, which would send a FIN before we get a
+    // chance to send a reset.
+    let reset = {
+        let (mut client_read, mut client_write) = client.split();
+        let (server_read, mut server_write) = server.split();
+
+        // Requests are copied to the server untouched.
+        let requests = async {
+            tokio::io::copy(&mut client_read, &mut server_write).await?;
+            server_write.shutdown().await
+        };
+        let responses = forward_response(server_read, &mut client_write, &fault);
+        futures::pin_mut!(requests, responses);
+
+        // Once the response is finished we stop copying requests too, so that
+        // the connection is actually closed.
+        match futures::future::select(requests, responses).await {
+            Either::Left((_, responses)) => responses.await?,
+            Either::Right((result, _)) => result?,
+        }
+    };
+
+    // With a linger time of zero the operating system sends a RST as soon as
+    // the socket is closed.
+    if reset {
+        client.set_linger(Some(Duration::from_secs(0)))?;
+    }
+    Ok(())
+}
+
+/// Copy the response from `server` to `client`, injecting `fault`.  Returns
+/// `true` if the connection should be reset rather than closed.
+async fn forward_response(
+    mut server: ReadHalf<'_>,
+    client: &mut WriteHalf<'_>,
+    fault: &Fault,
+) -> std::io::Result<bool> {
+    let mut buf = vec![0u8; 8192];
+
+    // Wait before forwarding anything.
+    if let Some(latency) = fault.latency {
+        tokio::time::delay_for(latency).await;
+    }
+
+    // Read until we have the complete response header.
+    let mut head = Vec::new();
+    let header_end = loop {
+        let n = server.read(&mut buf).await?;
+        if n == 0 {
+            // The server hung up without a complete header, pass on what we
+            // have and give up.
+            throttled_write(client, &head, fault.bandwidth).await?;
+            return Ok(false);
+        }
+        head.extend_from_slice(&buf[..n]);
+        if let Some(i) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+    };
+    let mut pending = head.split_off(header_end);
+    let head = match fault.content_length {
+        Some(len) => rewrite_content_length(&head, len),
+        None => head,
+    };
+    throttled_write(client, &head, fault.bandwidth).await?;
+
+    // Forward the body until it ends or a fault cuts it short.
+    let cutoff = match (fault.reset_after, fault.truncate_after) {
+        (Some(r), Some(t)) => Some(r.min(t)),
+        (r, t) => r.or(t),
+    };
+    let mut sent: u64 = 0;
+    loop {
+        if pending.is_empty() {
+            let n = server.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            pending.extend_from_slice(&buf[..n]);
+        }
+        let len = match cutoff {
+            Some(cutoff) => (pending.len() as u64).min(cutoff - sent) as usize,
+            None => pending.len(),
+        };
+        throttled_write(client, &pending[..len], fault.bandwidth).await?;
+        pending.drain(..len);
+        sent += len as u64;
+
+        if Some(sent) == cutoff {
+            if fault.reset_after == cutoff {
+                return Ok(true);
+            }
+            break;
+        }
+    }
+    client.shutdown().await?;
+    Ok(false)
+}
+
+/// Write all of `data` to `client`, sleeping as needed to stay within
+/// `bandwidth` bytes per second.
+async fn throttled_write(
+    client: &mut WriteHalf<'_>,
+    data: &[u8],
+    bandwidth: Option<u64>,
+) -> std::io::Result<()> {
+    let bandwidth = match bandwidth {
+        Some(bandwidth) if bandwidth > 0 => bandwidth,
+        _ => return client.write_all(data).await,
+    };
+
+    // Write in slices of about a tenth of a second so the transfer is smooth
+    // rather than bursty.
+    let slice = ((bandwidth / 10).max(1) as usize).min(data.len().max(1));
+    for piece in data.chunks(slice) {
+        client.write_all(piece).await?;
+        let secs = piece.len() as f64 / bandwidth as f64;
+        tokio::time::delay_for(Duration::from_secs_f64(secs)).await;
+    }
+    Ok(())
+}
+
+/// Replace the `Content-Length` header in the response header `head` with
+/// `len`, adding the header if it is missing.
+fn rewrite_content_length(head: &[u8], len: u64) -> Vec<u8> {
+    let text = String::from_utf8_lossy(head);
+    let mut lines: Vec<String> = text
+        .trim_end_matches("\r\n")
+        .split("\r\n")
+        .filter(|line| !line.to_ascii_lowercase().starts_with("content-length:"))
+        .map(String::from)
+        .collect();
+    lines.push(format!("Content-Length: {}", len));
+    let mut head = lines.join("\r\n");
+    head.push_str("\r\n\r\n");
+    head.into_bytes()
+}
@@ -0,0 +1,62 @@
+// Runs a local proxy in front of a plain HTTP server that misbehaves on purpose.
+// Demonstrates injecting latency, bandwidth caps, resets, truncated bodies and
+// wrong Content-Length headers to test how a downloader copes with them.
+//
+// Usage:
+//   fault-proxy <upstream host:port> [--listen addr] [--latency ms]
+//               [--bandwidth bytes/s] [--reset-after bytes]
+//               [--truncate-after bytes] [--content-length bytes]
+
+use fault_proxy::{Fault, FaultProxy, Script};
+
+// tokio::main macro automatically sets up the tokio runtime.
+#[tokio::main]
+async fn main() -> Result<(), util::BoxError> {
+    let mut args = std::env::args().skip(1);
+    let mut upstream = None;
+    let mut listen = "127.0.0.1:8080".to_string();
+    let mut fault = Fault::default();
+
+    // Parse the command line.  Every option takes exactly one value.
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            upstream = Some(arg);
+            continue;
+        }
+        let value = args.next().ok_or_else(|| util::Error {
+            what: format!("Missing value for {}.", arg),
+            source: None,
+            kind: util::ErrorKind::Other,
+        })?;
+        match arg.as_str() {
+            "--listen" => listen = value,
+            "--latency" => fault.latency = Some(std::time::Duration::from_millis(value.parse()?)),
+            "--bandwidth" => fault.bandwidth = Some(value.parse()?),
+            "--reset-after" => fault.reset_after = Some(value.parse()?),
+            "--truncate-after" => fault.truncate_after = Some(value.parse()?),
+            "--content-length" => fault.content_length = Some(value.parse()?),
+            _ => {
+                return Err(util::Error {
+                    what: format!("Unknown option {}.", arg),
+                    source: None,
+                    kind: util::ErrorKind::Other,
+                }
+                .into())
+            }
+        }
+    }
+    let upstream = upstream.ok_or_else(|| util::Error {
+        what: "Usage: fault-proxy <upstream host:port> [options]".to_string(),
+        source: None,
+        kind: util::ErrorKind::Other,
+    })?;
+
+    // Apply the same fault to every connection.
+    let proxy = FaultProxy::start_on(&listen, &upstream, Script::always(fault)).await?;
+    println!("Proxying {} -> {}", proxy.addr(), upstream);
+
+    // The proxy runs on spawned tasks; keep the handle alive forever.
+    futures::future::pending::<()>().await;
+
+    Ok(())
+}
@@ -0,0 +1,319 @@
+//! Downloads through the fault proxy, checking that the download crate
+//! survives resets and truncated bodies and still gets the right file, and
+//! gives up on slow servers when its timeouts say so.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use download::{Algorithm, MinSpeed, Options, Request, Retries, Timeouts};
+use fault_proxy::{Fault, FaultProxy, Script};
+use reqwest::Url;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Size of the file the test server serves.
+const SIZE: usize = 64 * 1024;
+
+/// Progress that isn't shown anywhere.
+struct Quiet;
+impl download::Progress for Quiet {
+    fn advance(&self, _n: u64) {}
+}
+
+/// Content of the file the test server serves.
+fn content() -> Vec<u8> {
+    (0..SIZE).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+/// Start a plain HTTP server on an ephemeral port that serves `content()`
+/// at every path, with byte ranges, one request per connection.  Returns
+/// its address.
+async fn serve() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(_) => continue,
+            };
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => head.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let head = String::from_utf8_lossy(&head).to_lowercase();
+                let body = content();
+                let start = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .and_then(|range| range.split('-').next()?.trim().parse::<usize>().ok());
+                let response = match start {
+                    Some(start) => format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\
+                         Content-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                        SIZE - start,
+                        start,
+                        SIZE - 1,
+                        SIZE
+                    ),
+                    None => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\n\
+                         ETag: \"v1\"\r\nConnection: close\r\n\r\n",
+                        SIZE
+                    ),
+                };
+                let _ = socket.write_all(response.as_bytes()).await;
+                if !head.starts_with("head ") {
+                    let _ = socket.write_all(&body[start.unwrap_or(0)..]).await;
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Request for `url` with `mirrors`, into a fresh file named `name`, with
+/// the checksum of `content()`.
+fn request(name: &str, url: &str, mirrors: &[String]) -> Request {
+    let mut hasher = Algorithm::Sha256.hasher();
+    hasher.update(&content());
+    let digest: String = hasher.finish().iter().map(|b| format!("{:02x}", b)).collect();
+
+    let mut request = Request::new(Url::parse(url).unwrap());
+    request.mirrors = mirrors.iter().map(|url| Url::parse(url).unwrap()).collect();
+    request.output = output(name);
+    request.checksum = Some(format!("sha256:{}", digest).parse().unwrap());
+    request
+}
+
+/// Fresh output path for the test `name`.
+fn output(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fault-proxy-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[tokio::test]
+async fn resumes_on_the_next_mirror_after_a_reset_and_a_truncation() {
+    let upstream = serve().await;
+    let reset = Fault {
+        reset_after: Some(10_000),
+        ..Default::default()
+    };
+    let truncate = Fault {
+        truncate_after: Some(20_000),
+        ..Default::default()
+    };
+    let resetting = FaultProxy::start(&upstream, Script::always(reset)).await.unwrap();
+    let truncating = FaultProxy::start(&upstream, Script::always(truncate)).await.unwrap();
+    let clean = FaultProxy::start(&upstream, Script::default()).await.unwrap();
+
+    let request = request(
+        "resumed.bin",
+        &resetting.url("/file.bin"),
+        &[truncating.url("/file.bin"), clean.url("/file.bin")],
+    );
+    let options = Options {
+        retries: Retries::none(),
+        ..Options::new(reqwest::Client::new())
+    };
+    let outcome = download::download(&request, &options, &Quiet).await.unwrap();
+
+    assert_eq!(outcome.bytes, SIZE as u64);
+    assert_eq!(outcome.mirrors.len(), 3);
+    assert_eq!(std::fs::read(&request.output).unwrap(), content());
+    // The last mirror only sent what the others didn't.
+    assert!(outcome.received < 2 * SIZE as u64);
+}
+
+#[tokio::test]
+async fn fails_when_every_mirror_truncates() {
+    let upstream = serve().await;
+    let truncate = Fault {
+        truncate_after: Some(1_000),
+        ..Default::default()
+    };
+    let truncating = FaultProxy::start(&upstream, Script::always(truncate)).await.unwrap();
+
+    let request = request("truncated.bin", &truncating.url("/file.bin"), &[]);
+    let options = Options {
+        retries: Retries::none(),
+        ..Options::new(reqwest::Client::new())
+    };
+    assert!(download::download(&request, &options, &Quiet).await.is_err());
+}
+
+#[tokio::test]
+async fn fails_the_checksum_of_a_wrong_file() {
+    let upstream = serve().await;
+    let clean = FaultProxy::start(&upstream, Script::default()).await.unwrap();
+
+    let mut request = request("wrong.bin", &clean.url("/file.bin"), &[]);
+    request.checksum = Some(format!("sha256:{}", "0".repeat(64)).parse().unwrap());
+    let options = Options::new(reqwest::Client::new());
+    let error = download::download(&request, &options, &Quiet).await.unwrap_err();
+    assert_eq!(error.kind, util::ErrorKind::Integrity);
+}
+
+#[tokio::test]
+async fn retries_and_resumes_on_the_same_mirror_after_a_reset() {
+    let upstream = serve().await;
+    // The probe goes through untouched, the first download is reset, and
+    // the retry is served cleanly.
+    let reset = Fault {
+        reset_after: Some(10_000),
+        ..Default::default()
+    };
+    let script = Script::new(vec![Fault::default(), reset]);
+    let proxy = FaultProxy::start(&upstream, script).await.unwrap();
+
+    let request = request("retried.bin", &proxy.url("/file.bin"), &[]);
+    let options = Options {
+        retries: Retries {
+            attempts: 1,
+            delay: Duration::from_millis(10),
+        },
+        ..Options::new(reqwest::Client::new())
+    };
+    let outcome = download::download(&request, &options, &Quiet).await.unwrap();
+
+    assert_eq!(outcome.mirrors, vec![Url::parse(&proxy.url("/file.bin")).unwrap()]);
+    assert_eq!(std::fs::read(&request.output).unwrap(), content());
+    assert_eq!(proxy.connections(), 3);
+    // The retry only sent what the reset connection didn't.
+    assert!(outcome.received < SIZE as u64 + 10_000);
+}
+
+#[tokio::test]
+async fn times_out_on_latency_beyond_the_first_byte_timeout() {
+    let upstream = serve().await;
+    let slow = Fault {
+        latency: Some(Duration::from_secs(2)),
+        ..Default::default()
+    };
+    let proxy = FaultProxy::start(&upstream, Script::always(slow)).await.unwrap();
+
+    let request = request("latency.bin", &proxy.url("/file.bin"), &[]);
+    let options = Options {
+        timeouts: Timeouts {
+            first_byte: Some(Duration::from_millis(500)),
+            ..Default::default()
+        },
+        retries: Retries::none(),
+        ..Options::new(reqwest::Client::new())
+    };
+    let error = download::download(&request, &options, &Quiet).await.unwrap_err();
+    assert_eq!(error.kind, util::ErrorKind::Timeout);
+}
+
+#[tokio::test]
+async fn waits_out_latency_within_the_first_byte_timeout() {
+    let upstream = serve().await;
+    let slow = Fault {
+        latency: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let proxy = FaultProxy::start(&upstream, Script::always(slow)).await.unwrap();
+
+    let request = request("latency-ok.bin", &proxy.url("/file.bin"), &[]);
+    let options = Options {
+        timeouts: Timeouts {
+            first_byte: Some(Duration::from_secs(2)),
+            ..Default::default()
+        },
+        ..Options::new(reqwest::Client::new())
+    };
+    download::download(&request, &options, &Quiet).await.unwrap();
+    assert_eq!(std::fs::read(&request.output).unwrap(), content());
+}
+
+#[tokio::test]
+async fn times_out_on_bandwidth_below_the_minimum_speed() {
+    let upstream = serve().await;
+    let slow = Fault {
+        bandwidth: Some(4 * 1024),
+        ..Default::default()
+    };
+    let proxy = FaultProxy::start(&upstream, Script::always(slow)).await.unwrap();
+
+    let request = request("bandwidth.bin", &proxy.url("/file.bin"), &[]);
+    let options = Options {
+        timeouts: Timeouts {
+            min_speed: Some(MinSpeed {
+                bytes_per_sec: 32 * 1024,
+                window: Duration::from_millis(500),
+            }),
+            ..Default::default()
+        },
+        retries: Retries::none(),
+        ..Options::new(reqwest::Client::new())
+    };
+    let error = download::download(&request, &options, &Quiet).await.unwrap_err();
+    assert_eq!(error.kind, util::ErrorKind::Timeout);
+    assert!(error.to_string().contains("Download slower than 32768 bytes/s"));
+}
+
+#[tokio::test]
+async fn keeps_going_with_bandwidth_above_the_minimum_speed() {
+    let upstream = serve().await;
+    let throttled = Fault {
+        bandwidth: Some(256 * 1024),
+        ..Default::default()
+    };
+    let proxy = FaultProxy::start(&upstream, Script::always(throttled)).await.unwrap();
+
+    let request = request("bandwidth-ok.bin", &proxy.url("/file.bin"), &[]);
+    let options = Options {
+        timeouts: Timeouts {
+            idle: Some(Duration::from_secs(2)),
+            min_speed: Some(MinSpeed {
+                bytes_per_sec: 16 * 1024,
+                window: Duration::from_millis(100),
+            }),
+            ..Default::default()
+        },
+        ..Options::new(reqwest::Client::new())
+    };
+    download::download(&request, &options, &Quiet).await.unwrap();
+    assert_eq!(std::fs::read(&request.output).unwrap(), content());
+}
+
+#[tokio::test]
+async fn fails_on_a_body_shorter_than_its_content_length() {
+    let upstream = serve().await;
+    let lying = Fault {
+        content_length: Some(SIZE as u64 + 1_000),
+        ..Default::default()
+    };
+    let proxy = FaultProxy::start(&upstream, Script::always(lying)).await.unwrap();
+
+    let request = request("too-long.bin", &proxy.url("/file.bin"), &[]);
+    let options = Options {
+        retries: Retries::none(),
+        ..Options::new(reqwest::Client::new())
+    };
+    let error = download::download(&request, &options, &Quiet).await.unwrap_err();
+    assert!(error.is_retryable());
+}
+
+#[tokio::test]
+async fn fails_the_checksum_of_a_body_cut_short_by_its_content_length() {
+    let upstream = serve().await;
+    let lying = Fault {
+        content_length: Some(SIZE as u64 - 1_000),
+        ..Default::default()
+    };
+    let proxy = FaultProxy::start(&upstream, Script::always(lying)).await.unwrap();
+
+    let request = request("too-short.bin", &proxy.url("/file.bin"), &[]);
+    let options = Options::new(reqwest::Client::new());
+    let error = download::download(&request, &options, &Quiet).await.unwrap_err();
+    assert_eq!(error.kind, util::ErrorKind::Integrity);
+}
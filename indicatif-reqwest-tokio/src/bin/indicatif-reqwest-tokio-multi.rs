@@ -1,93 +1,347 @@
 // Downloads multiple files from https://file-examples.com.
 // Demonstrates basic use of reqwest for async http(s) requests and showing an indicatif status bar for the downloads.
 // This is a combination of reqwest-tokio and indicatif-tokio
+//
+// Usage:
+//   indicatif-reqwest-tokio-multi [--manifest downloads.toml |
+//       --metalink files.meta4 [--location de]]
+//       [--limit-rate 2M] [--limit-rate-each 500K]
+//       [--connect-timeout secs] [--first-byte-timeout secs]
+//       [--idle-timeout secs] [--min-speed 1K|0] [--min-speed-time secs]
+//       [--retries n]
+//       [--output-dir dir] [--journal file]
+//       [--segments n] [--remote-time]
+//       [--cache dir] [--cache-size 1G] [--offline]
+//       [--store dir] [--store-link reflink|hard] [--extract]
+//       [--compressed | --keep-encoding] [--tar downloads.tar]
+//       [--user host=user:password] [--bearer host=token] [--netrc file]
+//       [--credential-helper [host=]command]
+//       [--oauth2 host=token_url] [--oauth2-client-id id]
+//       [--oauth2-client-secret secret] [--oauth2-scope scope]
+//       [--aws-sigv4 host] [--aws-profile name] [--aws-region region]
+//       [--aws-service s3]
+//       [--cacert file] [--cert file] [--key file] [--cert-type pem|p12]
+//       [--pinnedpubkey host=sha256//hash]
+//       [--proxy url] [--noproxy hosts] [--proxy-user user:password]
+//       [--cookie file] [--cookie-jar file] [--verbose]
+//
+// With --tar, every download becomes an entry of one tar archive instead of
+// a file of its own.
+//
+// Downloads that fail are listed with their errors once every download has
+// finished, and the program then exits with a non-zero status.
+//
+// A mirror that times out or breaks off is tried again --retries times, 2 by
+// default, waiting a little longer each time, before moving on to the next.
+//
+// Servers that need authentication get credentials from --user and --bearer,
+// which may be given more than once, then the DOWNLOAD_USER and
+// DOWNLOAD_BEARER environment variables, then ~/.netrc or the --netrc file.
+// Hosts without any ask the --credential-helper program, like git does, or
+// the one in DOWNLOAD_CREDENTIAL_HELPER.  Hosts given with --oauth2 get an
+// access token from the OAuth2 token endpoint instead, for the client given
+// with --oauth2-client-id and --oauth2-client-secret, or the
+// DOWNLOAD_OAUTH2_CLIENT_SECRET environment variable, which keeps the secret
+// out of the process list.  Requests to hosts given with --aws-sigv4, S3 or
+// an S3-compatible store, are signed with AWS Signature Version 4, with keys
+// from the AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment variables
+// or the --aws-profile in ~/.aws/credentials.
+//
+// Servers are also trusted if their CA is in a --cacert file, which may be
+// given more than once.  Servers that want a client certificate get the
+// --cert file, with its key in the --key file or in the same file, or, with
+// --cert-type p12 or a .p12 or .pfx file, a PKCS#12 archive whose password
+// is in the DOWNLOAD_CERT_PASSWORD environment variable.  Hosts given with
+// --pinnedpubkey, which needs the tls-rustls feature, must have one of the
+// given keys.
+//
+// Requests go through the proxies in the http_proxy, https_proxy and
+// all_proxy environment variables, except to the hosts in no_proxy, like
+// curl.  --proxy sets one proxy for every URL instead, an HTTP proxy or a
+// SOCKS5 one, e.g. socks5h://proxy.example.com, and --noproxy replaces
+// no_proxy.  --proxy-user gives credentials to proxies without any in
+// their URL.  With --verbose, each finished download says which mirrors
+// and proxies it was configured to go through.
+//
+// With --cookie or --cookie-jar, the downloads share a cookie jar, so a
+// portal that sets a session cookie on a landing page serves its files.  The
+// jar starts with the cookies in the --cookie file, a cookies.txt file like
+// curl and browser extensions write, and is saved to the --cookie-jar file
+// when the batch is done.
 
-use std::sync::Arc;
-
-use futures::{stream, StreamExt};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use reqwest::{header, Client, Url};
-use tokio::{io::AsyncWriteExt};
-
-async fn download_task(
-    download_url: &str,
-    multibar: Arc<MultiProgress>,
-) -> Result<(), util::BoxError> {
-    // Parse URL into Url type
-    let url = Url::parse(download_url)?;
-
-    // Create a reqwest Client
-    let client = Client::new();
-
-    // We need to determine the file size before we download, so we can create a ProgressBar
-    // A Header request for the CONTENT_LENGTH header gets us the file size
-    let download_size = {
-        let resp = client.head(url.as_str()).send().await?;
-        if resp.status().is_success() {
-            resp.headers() // Gives us the HeaderMap
-                .get(header::CONTENT_LENGTH) // Gives us an Option containing the HeaderValue
-                .and_then(|ct_len| ct_len.to_str().ok()) // Unwraps the Option as &str
-                .and_then(|ct_len| ct_len.parse().ok()) // Parses the Option as u64
-                .unwrap_or(0) // Fallback to 0
-        } else {
-            // We return an Error if something goes wrong here
-            return Err(
-                format!("Couldn't download URL: {}. Error: {:?}", url, resp.status(),).into(),
-            );
-        }
-    };
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-    // Parse the filename from the given URL
-    let filename = url
-        .path_segments() // Splits into segments of the URL
-        .and_then(|segments| segments.last()) // Retrieves the last segment
-        .unwrap_or("file.download"); // Fallback to generic filename
+use download::auth::Credentials;
+use download::aws::SigV4;
+use download::cache::Cache;
+use download::cookies::CookieJar;
+use download::extract::Format;
+use download::journal::Journal;
+use download::oauth2::OAuth2;
+use download::proxy::Proxies;
+use download::sink::TarArchive;
+use download::store::{Link, Store};
+use download::tls::Tls;
+use download::{Compression, MinSpeed, Options, Request, Retries, Timeouts, TokenBucket};
+use futures::StreamExt;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use reqwest::{Client, Url};
 
-    // Here we build the actual Request with a RequestBuilder from the Client
-    let request = client.get(url.as_str());
+/// Tar archive that collects the downloads when --tar is given.
+type Archive = TarArchive<futures::io::BufWriter<download::rt::File>>;
 
-    // Create the ProgressBar with the aquired size from before
-    // and add it to the multibar
-    let progress_bar = multibar.add(ProgressBar::new(download_size));
+/// Create a ProgressBar for a download that is starting and add it to the
+/// multibar.  The download sets its length once it knows the file size, and
+/// reports the active mirror, and whether it has stalled, as its message.
+fn progress_bar(multibar: &MultiProgress) -> ProgressBar {
+    let progress_bar = multibar.add(ProgressBar::new(0));
 
     // Set Style to the ProgressBar
     progress_bar.set_style(
         ProgressStyle::default_bar()
-            .template("[{bar:40.cyan/blue}] {bytes}/{total_bytes} - {msg}")
+            .template("[{bar:40.cyan/blue}] {bytes}/{total_bytes} {bytes_per_sec} - {msg}")
             .progress_chars("#>-"),
     );
+    progress_bar
+}
 
-    // Set the filename as message part of the progress bar
-    progress_bar.set_message(&filename);
-
-    // Create the output file with tokio's async fs lib
-    let mut outfile = tokio::fs::File::create(&filename).await?;
-
-    // Do the actual request to download the file
-    let mut download = request.send().await?;
-
-    // Do an asynchronous, buffered copy of the download to the output file.
-    //
-    // We use the part from the reqwest-tokio example here on purpose
-    // This way, we are able to increase the ProgressBar with every downloaded chunk
-    while let Some(chunk) = download.chunk().await? {
-        progress_bar.inc(chunk.len() as u64); // Increase ProgressBar by chunk size
-        outfile.write(&chunk).await?; // Write chunk to output file
+/// Finish the ProgressBar of a finished download to prevent glitches, and
+/// return the disk space deduplication saved.  The bar's own speed is the
+/// network speed, so report the disk speed separately.
+fn finish(
+    progress_bar: &ProgressBar,
+    request: &Request,
+    result: &Result<download::Outcome, util::Error>,
+    verbose: bool,
+) -> u64 {
+    let filename = match result {
+        Ok(outcome) if verbose => format!("{}{}", request.output.display(), route(outcome)),
+        _ => request.output.display().to_string(),
+    };
+    match result {
+        Ok(outcome) if outcome.cached => {
+            progress_bar.finish_with_message(&format!("{} (from cache)", filename))
+        }
+        Ok(outcome) if outcome.up_to_date => {
+            progress_bar.finish_with_message(&format!("{} (up to date)", filename))
+        }
+        Ok(outcome) if outcome.extracted => progress_bar.finish_with_message(&format!(
+            "{} (disk {}/s, extracted)",
+            filename,
+            HumanBytes(outcome.disk.bytes_per_sec())
+        )),
+        Ok(outcome) if outcome.saved > 0 => progress_bar.finish_with_message(&format!(
+            "{} (disk {}/s, deduplicated)",
+            filename,
+            HumanBytes(outcome.disk.bytes_per_sec())
+        )),
+        Ok(outcome) if outcome.received > 0 && outcome.received < outcome.bytes => {
+            progress_bar.finish_with_message(&format!(
+                "{} (disk {}/s, {} compressed)",
+                filename,
+                HumanBytes(outcome.disk.bytes_per_sec()),
+                HumanBytes(outcome.received)
+            ))
+        }
+        Ok(outcome) => progress_bar.finish_with_message(&format!(
+            "{} (disk {}/s)",
+            filename,
+            HumanBytes(outcome.disk.bytes_per_sec())
+        )),
+        Err(e) => progress_bar.abandon_with_message(&format!("{} failed: {}", filename, e.what)),
     }
 
-    // Finish the progress bar to prevent glitches
-    progress_bar.finish();
-
-    // Must flush tokio::io::BufWriter manually.
-    // It will *not* flush itself automatically when dropped.
-    outfile.flush().await?;
+    // Report the disk space saved by deduplication.
+    result.as_ref().map(|outcome| outcome.saved).unwrap_or(0)
+}
 
-    Ok(())
+/// Where the download of `outcome` came from, for --verbose.
+fn route(outcome: &download::Outcome) -> String {
+    if outcome.mirrors.is_empty() {
+        return String::new();
+    }
+    let hosts: Vec<&str> = outcome.mirrors.iter().filter_map(Url::host_str).collect();
+    let via = if outcome.proxies.is_empty() {
+        "directly".to_string()
+    } else {
+        let proxies: Vec<&str> = outcome.proxies.iter().map(|proxy| proxy.as_str().trim_end_matches('/')).collect();
+        format!("via {}", proxies.join(", "))
+    };
+    format!(" [from {} {}]", hosts.join(", "), via)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), util::BoxError> {
+    // Parse optional bandwidth limits from the command line.
+    let mut global_limit = None;
+    let mut limit_each = None;
+    let mut manifest = None;
+    let mut metalink = None;
+    let mut location = None;
+    let mut segments = 1;
+    let mut output_dir = None;
+    let mut journal = None;
+    let mut remote_time = false;
+    let mut cache = None;
+    let mut cache_size = 1024 * 1024 * 1024;
+    let mut offline = false;
+    let mut store = None;
+    let mut store_link = Link::Reflink;
+    let mut extract = false;
+    let mut compression = Compression::Identity;
+    let mut tar = None;
+    let mut users = Vec::new();
+    let mut bearers = Vec::new();
+    let mut netrc = None;
+    let mut helpers = Vec::new();
+    let mut oauth2_hosts = Vec::new();
+    let mut oauth2_client_id = None;
+    let mut oauth2_client_secret = std::env::var("DOWNLOAD_OAUTH2_CLIENT_SECRET").ok();
+    let mut oauth2_scopes = Vec::new();
+    let mut aws_hosts = Vec::new();
+    let mut aws_profile = None;
+    let mut aws_region = None;
+    let mut aws_service = "s3".to_string();
+    let mut tls = Tls::new();
+    let mut cert = None;
+    let mut key = None;
+    let mut cert_type = None;
+    let mut proxies = Proxies::from_env()?;
+    let mut cookie = None;
+    let mut cookie_jar = None;
+    let mut verbose = false;
+
+    // Give up on servers that stop responding rather than hang forever.
+    let mut timeouts = Timeouts {
+        connect: Some(Duration::from_secs(10)),
+        first_byte: Some(Duration::from_secs(30)),
+        idle: Some(Duration::from_secs(60)),
+        min_speed: None,
+    };
+    // A minimum speed of 0 turns the check off.
+    let mut min_speed = MinSpeed {
+        bytes_per_sec: 1024,
+        window: Duration::from_secs(30),
+    };
+    let mut retries = Retries::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        // Flags without a value.
+        match arg.as_str() {
+            "--remote-time" => {
+                remote_time = true;
+                continue;
+            }
+            "--offline" => {
+                offline = true;
+                continue;
+            }
+            "--verbose" => {
+                verbose = true;
+                continue;
+            }
+            "--extract" => {
+                extract = true;
+                continue;
+            }
+            "--compressed" => {
+                compression = Compression::Decode;
+                continue;
+            }
+            "--keep-encoding" => {
+                compression = Compression::Preserve;
+                continue;
+            }
+            _ => {}
+        }
+        let value = args.next().ok_or_else(|| util::Error {
+            what: format!("Missing value for {}.", arg),
+            source: None,
+            kind: util::ErrorKind::Other,
+        })?;
+        match arg.as_str() {
+            "--manifest" => manifest = Some(std::path::PathBuf::from(value)),
+            "--metalink" => metalink = Some(std::path::PathBuf::from(value)),
+            "--location" => location = Some(value),
+            "--segments" => segments = value.parse()?,
+            "--output-dir" => output_dir = Some(std::path::PathBuf::from(value)),
+            "--journal" => journal = Some(std::path::PathBuf::from(value)),
+            "--cache" => cache = Some(std::path::PathBuf::from(value)),
+            "--cache-size" => cache_size = download::parse_size(&value)?,
+            "--store" => store = Some(std::path::PathBuf::from(value)),
+            "--tar" => tar = Some(std::path::PathBuf::from(value)),
+            "--user" => users.push(value),
+            "--bearer" => bearers.push(value),
+            "--netrc" => netrc = Some(std::path::PathBuf::from(value)),
+            "--credential-helper" => helpers.push(value),
+            "--oauth2" => {
+                let at = value.find('=').ok_or_else(|| util::Error {
+                    what: format!("Invalid --oauth2 {}, expected host=token_url.", value),
+                    source: None,
+                    kind: util::ErrorKind::Other,
+                })?;
+                oauth2_hosts.push((value[..at].to_string(), Url::parse(&value[at + 1..])?));
+            }
+            "--oauth2-client-id" => oauth2_client_id = Some(value),
+            "--oauth2-client-secret" => oauth2_client_secret = Some(value),
+            "--oauth2-scope" => oauth2_scopes.push(value),
+            "--aws-sigv4" => aws_hosts.push(value),
+            "--aws-profile" => aws_profile = Some(value),
+            "--aws-region" => aws_region = Some(value),
+            "--aws-service" => aws_service = value,
+            "--cacert" => tls.add_ca_bundle(value.as_ref()).await?,
+            "--cert" => cert = Some(std::path::PathBuf::from(value)),
+            "--key" => key = Some(std::path::PathBuf::from(value)),
+            "--cert-type" => cert_type = Some(value.to_lowercase()),
+            "--pinnedpubkey" => tls.add_pin(&value)?,
+            "--proxy" => proxies.set_all(&value)?,
+            "--noproxy" => proxies.set_no_proxy(&value),
+            "--cookie" => cookie = Some(std::path::PathBuf::from(value)),
+            "--cookie-jar" => cookie_jar = Some(std::path::PathBuf::from(value)),
+            "--proxy-user" => {
+                let colon = value.find(':').unwrap_or(value.len());
+                proxies.set_credentials(&value[..colon], value.get(colon + 1..).unwrap_or_default());
+            }
+            "--store-link" => {
+                store_link = match value.as_str() {
+                    "reflink" => Link::Reflink,
+                    "hard" => Link::Hard,
+                    _ => {
+                        return Err(util::Error {
+                            what: format!("Unknown link type {}, expected reflink or hard.", value),
+                            source: None,
+                            kind: util::ErrorKind::Other,
+                        }
+                        .into())
+                    }
+                }
+            }
+            "--limit-rate" => global_limit = Some(Arc::new(TokenBucket::new(download::parse_rate(&value)?))),
+            "--limit-rate-each" => limit_each = Some(download::parse_rate(&value)?),
+            "--connect-timeout" => timeouts.connect = Some(Duration::from_secs(value.parse()?)),
+            "--first-byte-timeout" => timeouts.first_byte = Some(Duration::from_secs(value.parse()?)),
+            "--idle-timeout" => timeouts.idle = Some(Duration::from_secs(value.parse()?)),
+            "--min-speed" => min_speed.bytes_per_sec = download::parse_rate(&value)?,
+            "--min-speed-time" => min_speed.window = Duration::from_secs(value.parse()?),
+            "--retries" => retries.attempts = value.parse()?,
+            _ => {
+                return Err(util::Error {
+                    what: format!("Unknown option {}.", arg),
+                    source: None,
+                    kind: util::ErrorKind::Other,
+                }
+                .into())
+            }
+        }
+    }
+    if min_speed.bytes_per_sec > 0 {
+        timeouts.min_speed = Some(min_speed);
+    }
+
     // A vector containing all the URLs to download
+    // Used when no manifest is given on the command line.
     let download_links = vec![
         "https://file-examples-com.github.io/uploads/2017/11/file_example_WAV_10MG.wav", // 10MB WAV audio file
         "https://file-examples-com.github.io/uploads/2017/11/file_example_OOG_2MG.ogg", // 2MB OGG audio file
@@ -95,16 +349,185 @@ async fn main() -> Result<(), util::BoxError> {
         "https://file-examples-com.github.io/uploads/2017/10/file_example_JPG_1MB.jpg", // 1MB JPG image
     ];
 
+    // Build the batch of download requests, highest priority first.
+    let mut requests = match (manifest, metalink) {
+        (Some(_), Some(_)) => {
+            return Err(util::Error {
+                what: "Give either --manifest or --metalink, not both.".to_string(),
+                source: None,
+                kind: util::ErrorKind::Other,
+            }
+            .into())
+        }
+        (Some(manifest), None) => download::manifest::load(&manifest).await?,
+        (None, Some(metalink)) => download::metalink::load(&metalink, location.as_deref()).await?,
+        (None, None) => download_links
+            .into_iter()
+            .map(|link| Ok(Request::new(Url::parse(link)?)))
+            .collect::<Result<Vec<_>, util::BoxError>>()?,
+    };
+
+    // Downloads go into the output directory, if there is one, and so do
+    // the directories they are extracted into.
+    let output_dir = output_dir.unwrap_or_default();
+    for request in &mut requests {
+        request.output = output_dir.join(&request.output);
+        request.extract = request.extract.as_ref().map(|extract| output_dir.join(extract));
+    }
+
+    // Record progress next to the downloads, so that running the same batch
+    // again skips finished files and resumes unfinished ones.
+    let journal = journal.unwrap_or_else(|| output_dir.join(".download-journal.json"));
+    if !output_dir.as_os_str().is_empty() {
+        download::rt::fs::create_dir_all(&output_dir).await?;
+    }
+
+    // Extract archives next to themselves, e.g. data.tar.gz into data/,
+    // unless the manifest says where.
+    if extract {
+        for request in &mut requests {
+            if request.extract.is_none() {
+                request.extract = Format::directory_for(&request.output);
+            }
+        }
+    }
+
+    // Credentials on the command line win over the environment, which wins
+    // over the netrc file.
+    let mut credentials = match netrc {
+        Some(path) => {
+            let mut credentials = Credentials::new();
+            credentials.add_netrc(&path).await?;
+            credentials.add_env()?;
+            credentials
+        }
+        None => Credentials::load().await?,
+    };
+    for user in &users {
+        credentials.add_basic(user)?;
+    }
+    for bearer in &bearers {
+        credentials.add_bearer(bearer)?;
+    }
+    for helper in &helpers {
+        credentials.add_helper(helper)?;
+    }
+
+    if let Some(cert) = cert {
+        let extension = cert.extension().and_then(|extension| extension.to_str()).map(str::to_lowercase);
+        let pkcs12 = match cert_type.as_deref() {
+            Some("p12") => true,
+            Some("pem") => false,
+            Some(other) => {
+                return Err(util::Error {
+                    what: format!("Unknown certificate type {}, expected pem or p12.", other),
+                    source: None,
+                    kind: util::ErrorKind::Other,
+                }
+                .into())
+            }
+            None => matches!(extension.as_deref(), Some("p12") | Some("pfx")),
+        };
+        if pkcs12 {
+            let password = std::env::var("DOWNLOAD_CERT_PASSWORD").unwrap_or_default();
+            tls.set_identity_pkcs12(&cert, &password).await?;
+        } else {
+            let key = key.unwrap_or_else(|| cert.clone());
+            tls.set_identity_pem(&cert, &key).await?;
+        }
+    }
+
+    let cookies = match (&cookie, &cookie_jar) {
+        (Some(path), _) => Some(Arc::new(CookieJar::load(path).await?)),
+        (None, Some(_)) => Some(Arc::new(CookieJar::new())),
+        (None, None) => None,
+    };
+
+    // Every download shares one client, so they can share connections, and the
+    // same limits, timeouts, TLS settings, and proxies.  Credentials don't
+    // follow redirects to another origin.  With a cookie jar, the downloads
+    // follow redirects themselves, keeping the cookies set along the way.
+    let mut builder = proxies
+        .apply(tls.apply(timeouts.apply(Client::builder()))?)?
+        .redirect(download::auth::redirect_policy());
+    if let Some(cookies) = &cookies {
+        builder = cookies.apply(builder);
+    }
+    let client = builder.build()?;
+
+    if !oauth2_hosts.is_empty() {
+        let (client_id, client_secret) = match (oauth2_client_id, oauth2_client_secret) {
+            (Some(client_id), Some(client_secret)) => (client_id, client_secret),
+            _ => {
+                return Err(util::Error {
+                    what: "--oauth2 needs --oauth2-client-id and --oauth2-client-secret.".to_string(),
+                    source: None,
+                    kind: util::ErrorKind::Other,
+                }
+                .into())
+            }
+        };
+        for (host, token_url) in oauth2_hosts {
+            let oauth2 = OAuth2 {
+                scopes: oauth2_scopes.clone(),
+                ..OAuth2::new(client.clone(), token_url, &client_id, &client_secret)
+            };
+            credentials.add_oauth2(&host, oauth2)?;
+        }
+    }
+
+    if !aws_hosts.is_empty() {
+        let signer = SigV4::load(aws_profile.as_deref(), aws_region.as_deref(), &aws_service).await?;
+        for host in &aws_hosts {
+            credentials.add_sigv4(host, signer.clone())?;
+        }
+    }
+
+    let options = Options {
+        client,
+        timeouts,
+        credentials: Some(Arc::new(credentials)),
+        cookies: cookies.clone(),
+        proxies: Some(Arc::new(proxies)),
+        // The built-in fetchers cover http(s), file and data URLs.
+        fetchers: Default::default(),
+        limits: global_limit.into_iter().collect(),
+        limit_each,
+        segments,
+        journal: Some(Arc::new(Journal::open(journal).await?)),
+        remote_time,
+        cache: match cache {
+            Some(dir) => Some(Arc::new(Cache::open(dir, cache_size).await?)),
+            None => None,
+        },
+        offline,
+        store: match store {
+            Some(dir) => Some(Arc::new(Store::open(dir, store_link).await?)),
+            None => None,
+        },
+        compression,
+        retries,
+    };
+
+    // Entries are appended to the archive as the downloads finish.
+    let archive: Option<Archive> = match tar {
+        Some(path) => {
+            let file = download::rt::File::create(path).await?;
+            Some(TarArchive::new(futures::io::BufWriter::new(file)))
+        }
+        None => None,
+    };
+
     // Set up a new multi-progress bar.
     // The bar is stored in an `Arc` to facilitate sharing between threads.
     let multibar = std::sync::Arc::new(indicatif::MultiProgress::new());
 
     // Add an overall progress indicator to the multibar.
-    // It has as many steps as the download_links Vector and will increment on completion of each task.
+    // It has as many steps as there are requests and will increment on completion of each task.
     let main_pb = std::sync::Arc::new(
         multibar
             .clone()
-            .add(indicatif::ProgressBar::new(download_links.len() as u64)),
+            .add(indicatif::ProgressBar::new(requests.len() as u64)),
     );
     main_pb.set_style(
         indicatif::ProgressStyle::default_bar().template("{msg} {bar:10} {pos}/{len}"),
@@ -115,26 +538,24 @@ async fn main() -> Result<(), util::BoxError> {
     // first task to finish.
     main_pb.tick();
 
-    // Convert download_links Vector into stream
-    // This is basically a async compatible iterator
-    let stream = stream::iter(download_links);
-
-    // Set up a future to iterate over tasks and run up to 2 at a time.
-    let tasks = stream
-        .enumerate()
-        .for_each_concurrent(Some(2), |(_i, download_link)| {
-            // Clone multibar and main_pb.  We will move the clones into each task.
-            let multibar = multibar.clone();
-            let main_pb = main_pb.clone();
-            async move {
-                // Spawn a new tokio task for the current download link
-                // We need to hand over the multibar, so the ProgressBar for the task can be added
-                let _task = tokio::task::spawn(download_task(download_link, multibar)).await;
-                
-                // Increase main ProgressBar by 1
-                main_pb.inc(1);
-            }
-        });
+    // Each download gets a ProgressBar of its own as it starts, kept here
+    // until it finishes.
+    let bars = Mutex::new(HashMap::new());
+    let start = |i| {
+        let progress_bar = progress_bar(&multibar);
+        bars.lock().unwrap().insert(i, progress_bar.clone());
+        progress_bar
+    };
+
+    // Download up to 2 files at a time, into the archive if there is one.
+    let mut batch = match &archive {
+        Some(archive) => {
+            let requests = &requests;
+            let entry = move |i: usize| archive.entry(requests[i].output.clone());
+            download::download_batch_to(requests, &options, 2, start, entry).boxed_local()
+        }
+        None => download::download_batch(&requests, &options, 2, start).boxed_local(),
+    };
 
     // Set up a future to manage rendering of the multiple progress bars.
     let multibar = {
@@ -147,11 +568,44 @@ async fn main() -> Result<(), util::BoxError> {
         tokio::task::spawn_blocking(move || multibar.join())
     };
 
-    // Wait for the tasks to finish.
-    tasks.await;
+    // Finish the ProgressBar of each download as it finishes, and increase
+    // the main ProgressBar by 1.  Total disk space saved by deduplication,
+    // and the downloads that failed with their errors.
+    let mut saved = 0;
+    let mut failed = Vec::new();
+    while let Some((i, result)) = batch.next().await {
+        if let Some(progress_bar) = bars.lock().unwrap().remove(&i) {
+            saved += finish(&progress_bar, &requests[i], &result, verbose);
+        }
+        if let Err(e) = result {
+            failed.push((i, e));
+        }
+        main_pb.inc(1);
+    }
+    // The batch borrows the archive, which is closed next.
+    drop(batch);
+
+    // Close the archive once every entry is in it.
+    if let Some(archive) = archive {
+        archive.finish().await?;
+    }
+
+    // Remember which cached files were used, for evicting the least recently
+    // used ones.
+    if let Some(cache) = &options.cache {
+        cache.flush().await?;
+    }
+
+    // Save the cookies for the next run.
+    if let (Some(cookies), Some(path)) = (&cookies, &cookie_jar) {
+        cookies.save(path).await?;
+    }
 
     // Change the message on the overall progress indicator. 
-    main_pb.finish_with_message("done");
+    match saved {
+        0 => main_pb.finish_with_message("done"),
+        saved => main_pb.finish_with_message(&format!("done, deduplication saved {}", HumanBytes(saved))),
+    }
 
     // Wait for the progress bars to finish rendering.
     // The first ? unwraps the outer join() in which we are waiting for the
@@ -159,5 +613,20 @@ async fn main() -> Result<(), util::BoxError> {
     // The second ? unwraps the inner multibar.join().  
     multibar.await??;
 
+    // The bars only have room for the gist, so give the whole errors now
+    // that the bars are out of the way.
+    if !failed.is_empty() {
+        failed.sort_by_key(|(i, _)| *i);
+        for (i, e) in &failed {
+            eprintln!("{}: {}", requests[*i].output.display(), e);
+        }
+        return Err(util::Error {
+            what: format!("{} of {} downloads failed.", failed.len(), requests.len()),
+            source: None,
+            kind: util::ErrorKind::Other,
+        }
+        .into());
+    }
+
     Ok(())
 }
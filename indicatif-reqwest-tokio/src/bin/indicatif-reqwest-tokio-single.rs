@@ -1,10 +1,24 @@
 // Downloads a 10MB video example file from https://file-examples.com.
 // Demonstrates basic use of reqwest for async http(s) requests and showing an indicatif status bar for the download.
+//
+// Usage:
+//   indicatif-reqwest-tokio-single [output.mp4 | -]
+//
+// Give - as the output to write the video to stdout.  The progress bar is
+// drawn on stderr, so it stays out of the way of a pipe.
+//
+// If the server needs authentication, the credentials come from the
+// DOWNLOAD_USER or DOWNLOAD_BEARER environment variables or ~/.netrc.
+// Requests go through the proxies in the http_proxy, https_proxy and
+// all_proxy environment variables, except to the hosts in no_proxy.
 
-// Needed to be able to call flush() on a tokio::io::AsyncWrite.
-use indicatif::{ProgressBar, ProgressStyle};
+use std::time::Duration;
+
+use download::auth::{self, Credentials};
+use download::proxy::Proxies;
+use download::{sink, MinSpeed, Timeouts, Writer};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use reqwest::{Client, Url, header};
-use tokio::io::AsyncWriteExt;
 
 // tokio::main macro automatically sets up the tokio runtime.
 #[tokio::main]
@@ -15,13 +29,31 @@ async fn main() -> Result<(), util::BoxError> {
     // Parse URL into Url type
     let url = Url::parse(download_url_str)?;
 
-    // Create a reqwest Client
-    let client = Client::new();
+    // Give up on servers that stop responding rather than hang forever.
+    let timeouts = Timeouts {
+        connect: Some(Duration::from_secs(10)),
+        first_byte: Some(Duration::from_secs(30)),
+        idle: Some(Duration::from_secs(60)),
+        min_speed: Some(MinSpeed {
+            bytes_per_sec: 1024,
+            window: Duration::from_secs(30),
+        }),
+    };
+
+    // Create a reqwest Client.  Credentials must not follow a redirect to
+    // another origin.
+    let client = Proxies::from_env()?
+        .apply(timeouts.apply(Client::builder()))?
+        .redirect(auth::redirect_policy())
+        .build()?;
+
+    // Look up credentials for servers that need them.
+    let credentials = Credentials::load().await?;
 
     // We need to determine the file size before we download so we can create a ProgressBar
     // A Header request for the CONTENT_LENGTH header gets us the file size
     let download_size = {
-        let resp = client.head(url.as_str()).send().await?;
+        let resp = timeouts.send(credentials.authorize(&url, client.head(url.as_str())).await?).await?;
         if resp.status().is_success() {
             resp.headers() // Gives is the HeaderMap
                 .get(header::CONTENT_LENGTH) // Gives us an Option containing the HeaderValue
@@ -42,11 +74,11 @@ async fn main() -> Result<(), util::BoxError> {
     // Parse the filename from the given URL
     let filename = url
            .path_segments() // Splits into segments of the URL
-           .and_then(|segments| segments.last()) // Retrieves the last segment
+           .and_then(|mut segments| segments.next_back()) // Retrieves the last segment
            .unwrap_or("video.mp4"); // Fallback to generic filename
 
     // Here we build the actual Request with a RequestBuilder from the Client
-    let request = client.get(url.as_str());
+    let request = credentials.authorize(&url, client.get(url.as_str())).await?;
 
     // Create the ProgressBar with the aquired size from before
     let progress_bar = ProgressBar::new(download_size);
@@ -59,29 +91,50 @@ async fn main() -> Result<(), util::BoxError> {
     );
 
     // Set the filename as message part of the progress bar
-    progress_bar.set_message(&filename);
+    progress_bar.set_message(filename);
 
-    // Create the output file
-    let mut outfile = tokio::fs::File::create(&filename).await?;
+    // Create the output, the file named on the command line, stdout, or a file
+    // named after the URL, and hand it to a writer task, which decouples the
+    // network from the disk.
+    let output = std::env::args().nth(1).unwrap_or_else(|| filename.to_string());
+    let mut writer = if output == "-" {
+        Writer::for_sink(sink::stdout())
+    } else {
+        Writer::for_sink(sink::file(output.as_ref()).await?)
+    };
 
     // Do the actual request to download the file
-    let mut download = request.send().await?;
+    let mut download = timeouts.send(request).await?;
+
+    // The watchdog fails the download if the server stops sending.
+    let mut watchdog = timeouts.watchdog();
+    let show_stalled = |stalled| {
+        if stalled {
+            progress_bar.set_message(&format!("{} (stalled)", filename));
+        } else {
+            progress_bar.set_message(filename);
+        }
+    };
 
     // Do an asynchronous, buffered copy of the download to the output file.
     // 
     // We use the part from the reqwest-tokio example here on purpose
     // This way, we are able to increase the ProgressBar with every downloaded chunk
-    while let Some(chunk) = download.chunk().await? {
-        progress_bar.inc(chunk.len() as u64);   // Increase ProgressBar by chunk size
-        outfile.write(&chunk).await?;   // Write chunk to output file
+    while let Some(chunk) = watchdog.chunk(&mut download, show_stalled).await? {
+        progress_bar.inc(chunk.len() as u64); // Increase ProgressBar by chunk size
+        writer.write(chunk).await?; // Queue chunk for the writer task
     }
 
-    // Finish the progress bar to prevent glitches
-    progress_bar.finish();
-    
-    // Must flush tokio::io::BufWriter manually.
-    // It will *not* flush itself automatically when dropped.
-    outfile.flush().await?;
+    // Wait for the writer to flush everything to disk.
+    let stats = writer.finish().await?;
+
+    // Finish the progress bar to prevent glitches.  The bar's own speed is the
+    // network speed, so report the disk speed separately.
+    progress_bar.finish_with_message(&format!(
+        "{} (disk {}/s)",
+        filename,
+        HumanBytes(stats.bytes_per_sec())
+    ));
     
     Ok(())
 }
\ No newline at end of file
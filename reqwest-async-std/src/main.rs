@@ -0,0 +1,49 @@
+// Downloads a picture of Ferris, the Rust mascot, from the Internet, on the
+// async-std runtime instead of tokio.
+// Demonstrates that the download crate runs on either runtime: it is built
+// with the rt-async-std feature here, so its tasks, timers and files all come
+// from async-std.
+//
+// Usage:
+//   reqwest-async-std [url [output]]
+//
+// The URL defaults to the picture of Ferris, which is handy for trying the
+// example against a local server.
+
+use download::{Options, Request};
+use indicatif::{ProgressBar, ProgressStyle};
+use reqwest::{Client, Url};
+
+// async_std::main macro automatically sets up the async-std runtime.
+#[async_std::main]
+async fn main() -> Result<(), util::BoxError> {
+    let mut args = std::env::args().skip(1);
+    let url = args
+        .next()
+        .unwrap_or_else(|| "https://rustacean.net/assets/rustacean-orig-noshadow.png".to_string());
+    let output = args.next().unwrap_or_else(|| "ferris.png".to_string());
+
+    // Describe the download.  The rest of the Request fields, such as mirrors
+    // and checksums, keep their defaults.
+    let mut request = Request::new(Url::parse(&url)?);
+    request.output = output.into();
+
+    // reqwest itself still needs tokio underneath, which async-std provides
+    // through its tokio02 feature.  Everything else runs on async-std.
+    let options = Options::new(Client::new());
+
+    // The download learns the size of the file before it starts, and tells
+    // the progress bar.
+    let progress_bar = ProgressBar::new(0);
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{bar:40.cyan/blue}] {bytes}/{total_bytes} - {msg}")
+            .progress_chars("#>-"),
+    );
+
+    let outcome = download::download(&request, &options, &progress_bar).await?;
+    progress_bar.finish_with_message(&format!("{}", request.output.display()));
+    println!("Downloaded {} bytes.", outcome.bytes);
+
+    Ok(())
+}
@@ -2,13 +2,8 @@
 // Demonstrates basic use of reqwest for async http(s) requests and downloading.
 // Demonstrates the futures <--> tokio compatibility layer for AsyncRead.
 
-// Compatibility trait lets us call `compat()` on a futures::io::AsyncRead
-// to convert it into a tokio::io::AsyncRead.
-use tokio_util::compat::FuturesAsyncReadCompatExt;
-
-// Lets us call into_async_read() to convert a futures::stream::Stream into a
-// futures::io::AsyncRead.
-use futures::stream::TryStreamExt;
+use download::ProgressReader;
+use indicatif::{ProgressBar, ProgressStyle};
 
 // tokio::main macro automatically sets up the tokio runtime.
 #[tokio::main]
@@ -18,17 +13,23 @@ async fn main() -> Result<(), util::BoxError> {
         .await? // await server response
         .error_for_status()?; // generate an error if server didn't respond OK
     
-    // Convert the body of the response into a futures::io::Stream.
-    let download = download.bytes_stream();
+    // Create a progress bar sized from the Content-Length header, if any.
+    let progress_bar = ProgressBar::new(download.content_length().unwrap_or(0));
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{bar:40.cyan/blue}] {bytes}/{total_bytes} - {msg}")
+            .progress_chars("#>-"),
+    );
+    progress_bar.set_message("ferris.png");
     
-    // Convert the stream into an futures::io::AsyncRead.
-    // We must first convert the reqwest::Error into an futures::io::Error.
-    let download = download
-        .map_err(|e| futures::io::Error::new(futures::io::ErrorKind::Other, e))
-        .into_async_read();
+    // Convert the body of the response into a tokio::io::AsyncRead.
+    // Under the hood this converts the response into a futures::io::Stream,
+    // then into a futures::io::AsyncRead, and finally uses the compatibility
+    // layer to turn it into a tokio::io::AsyncRead.
+    let download = download::into_async_read(download);
     
-    // Convert the futures::io::AsyncRead into a tokio::io::AsyncRead.
-    let mut download = download.compat();
+    // Wrap the AsyncRead so every byte read advances the progress bar.
+    let mut download = ProgressReader::new(download, progress_bar.clone());
     
     // Create an output file into which we will save ferris.
     let mut outfile = tokio::fs::File::create("ferris.png").await?;
@@ -36,5 +37,7 @@ async fn main() -> Result<(), util::BoxError> {
     // Invoke tokio::io::copy to actually perform the download.
     tokio::io::copy(&mut download, &mut outfile).await?;
     
+    progress_bar.finish();
+    
     Ok(())
 }
@@ -1,33 +1,47 @@
 // Downloads a picture of Ferris, the Rust mascot, from the Internet.
 // Demonstrates basic use of reqwest for async http(s) requests and downloading.
+//
+// Usage:
+//   reqwest-tokio [output.png | -]
+//
+// Give - as the output to write the picture to stdout and pipe it into
+// another program.
 
-// Needed to be able to call flush() on a tokio::io::AsyncWrite.
-use tokio::io::AsyncWriteExt;
+use download::{sink, Writer};
 
 // tokio::main macro automatically sets up the tokio runtime.
 #[tokio::main]
 async fn main() -> Result<(), util::BoxError> {
+    let output = std::env::args().nth(1).unwrap_or_else(|| "ferris.png".to_string());
+
     // Attempt to download ferris..
     let mut download = reqwest::get("https://rustacean.net/assets/rustacean-orig-noshadow.png")
         .await? // await server response
         .error_for_status()?; // generate an error if server didn't respond OK
     
-    // Create an output file into which we will save ferris.
-    let mut outfile = tokio::fs::File::create("ferris.png").await?;
+    // Pick the sink into which we will save ferris: stdout or an output file.
+    // Either way it sits behind a buffer that coalesces small chunks into
+    // larger writes, and a writer task that decouples the network from the
+    // output.
+    let mut writer = if output == "-" {
+        Writer::for_sink(sink::stdout())
+    } else {
+        Writer::for_sink(sink::file(output.as_ref()).await?)
+    };
     
-    // Do an asynchronous, buffered copy of the download to the output file.
+    // Do an asynchronous, buffered copy of the download to the output.
     // 
     // Note that in some sense this is a workaround for being unable to use
     // tokio::io::copy as in the reqwest-tokio-compat example, but on the other
     // hand this method has no performance penalty and can actually be
     // preferable in some cases because it gives us more control.
     while let Some(chunk) = download.chunk().await? {
-        outfile.write(&chunk).await?;
+        writer.write(chunk).await?;
     }
     
-    // Must flush tokio::io::BufWriter manually.
-    // It will *not* flush itself automatically when dropped.
-    outfile.flush().await?;
+    // Wait for the writer to flush everything.  Buffered output is *not*
+    // flushed automatically when dropped.
+    writer.finish().await?;
     
     Ok(())
 }
@@ -16,8 +16,8 @@ pub type BoxError = std::boxed::Box<dyn
 	+ std::marker::Sync // needed for threads
 >;
 
-/// Generic error type that stores a message `what` and optionally wraps another
-/// causative error `source`.  Example:
+/// Generic error type that stores a message `what`, the `kind` of error, and
+/// optionally wraps another causative error `source`.  Example:
 /// 
 /// ```ignore
 /// fn my_func() -> Result<(), BoxError> {
@@ -26,6 +26,7 @@ pub type BoxError = std::boxed::Box<dyn
 ///         Err(e) => return Err(Error{
 ///             what: "There was a problem doing something.".to_string(),
 ///             source: Some(e),
+///             kind: ErrorKind::Other,
 ///         }.into()),
 ///     };
 ///     
@@ -33,6 +34,7 @@ pub type BoxError = std::boxed::Box<dyn
 ///         return Err(Error{
 ///             what: "It didn't work.".to_string(),
 ///             source: None,
+///             kind: ErrorKind::Other,
 ///         }.into());
 ///     }
 ///     
@@ -44,7 +46,18 @@ pub struct Error {
     /// What went wrong?
 	pub what: String,
 	/// What was the source/cause of this error, if any?
-	pub source: Option<BoxError>
+	pub source: Option<BoxError>,
+	/// What kind of error is this?
+	pub kind: ErrorKind,
+}
+impl Error {
+	/// Is it worth trying the same operation again?
+	pub fn is_retryable(&self) -> bool {
+		match self.kind {
+			ErrorKind::Timeout | ErrorKind::Network | ErrorKind::Integrity => true,
+			ErrorKind::Other | ErrorKind::Auth | ErrorKind::Tls => false,
+		}
+	}
 }
 impl std::fmt::Display for Error {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -63,3 +76,26 @@ impl std::error::Error for Error {
 		}
 	}
 }
+
+/// Broad category of an `Error`, so callers can decide how to react without
+/// parsing the message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+	/// Anything not covered by a more specific kind.
+	Other,
+	/// The operation took too long or stalled.  Timeouts are retryable.
+	Timeout,
+	/// The connection couldn't be made or broke off, e.g. it was reset.
+	/// Network errors are retryable.
+	Network,
+	/// Data arrived but was not what we expected, e.g. a checksum or size
+	/// mismatch.  Integrity errors are retryable.
+	Integrity,
+	/// The server refused access (HTTP 401 or 403) because credentials were
+	/// missing or wrong.  Trying again with the same credentials won't help.
+	Auth,
+	/// No secure connection could be made, e.g. because the server's
+	/// certificate couldn't be verified or didn't match a pinned key.  Trying
+	/// again won't help.
+	Tls,
+}
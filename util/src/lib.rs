@@ -5,3 +5,5 @@ mod error;
 pub use error::BoxError;
 /// Generic error type that stores a message and can wrap other errors.
 pub use error::Error;
+/// Broad category of an `Error`.
+pub use error::ErrorKind;